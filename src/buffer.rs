@@ -0,0 +1,410 @@
+//! A write-back buffer for any [Vfs], to turn the many small writes SQLite issues per transaction
+//! into a few large ones.
+//!
+//! Writes are buffered per handle, with adjacent and overlapping writes coalesced into a single
+//! range. The buffered ranges are flushed as one [DatabaseHandle::write_batch] (which backends can
+//! implement to write them in parallel) on [DatabaseHandle::sync], when the lock gets downgraded
+//! (i.e. other connections might read the file afterwards), once the buffer exceeds its capacity,
+//! and when the handle is closed. Reads of buffered data are served from the buffer.
+//!
+//! WAL files are written through: other connections read new frames right after the wal-index got
+//! updated, without any lock change on the WAL file itself. Rollback journals are written through
+//! as well, so that the original content of a page always reaches the journal before the changed
+//! page can be flushed to the database (even with `PRAGMA synchronous = OFF`, where nothing gets
+//! synced in between).
+//!
+//! Databases in WAL mode are written through as well, once their wal-index got opened: a checkpoint
+//! neither syncs (with `PRAGMA synchronous = OFF`) nor releases the lock of the connection, but
+//! other connections read the checkpointed pages from the database as soon as the wal-index says
+//! so.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+/// A [Vfs] that buffers the writes to the files opened through the wrapped [Vfs].
+pub struct BufferedVfs<V> {
+    vfs: V,
+    capacity: usize,
+}
+
+/// A [DatabaseHandle] opened by [BufferedVfs].
+pub struct BufferedHandle<H: DatabaseHandle> {
+    handle: H,
+    /// `None` for files that are written through.
+    buffer: Option<Buffer>,
+    /// Set once the wal-index of the database got opened, i.e. the database is in WAL mode.
+    wal: AtomicBool,
+}
+
+#[derive(Default)]
+struct Buffer {
+    capacity: usize,
+    /// The buffered, non-overlapping and non-adjacent ranges by their offset.
+    ranges: BTreeMap<u64, Vec<u8>>,
+    /// The size of all buffered ranges in bytes.
+    size: usize,
+}
+
+impl<V> BufferedVfs<V> {
+    /// Wrap `vfs`, buffering up to `capacity` bytes of writes per open file.
+    pub fn new(vfs: V, capacity: usize) -> Self {
+        Self { vfs, capacity }
+    }
+}
+
+impl Buffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The end of the last buffered range.
+    fn end(&self) -> Option<u64> {
+        let (offset, data) = self.ranges.iter().next_back()?;
+        Some(offset + data.len() as u64)
+    }
+
+    /// The offsets of all ranges that overlap or touch `offset..end`, in descending order.
+    fn touching(&self, offset: u64, end: u64) -> Vec<u64> {
+        self.ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(start, data)| *start + data.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect()
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) {
+        let mut end = offset + buf.len() as u64;
+        let touching = self.touching(offset, end);
+        let start = touching.last().map_or(offset, |start| offset.min(*start));
+        if let Some(last) = touching.first() {
+            end = end.max(last + self.ranges[last].len() as u64);
+        }
+
+        let mut merged = vec![0; (end - start) as usize];
+        for range in touching {
+            let data = self.ranges.remove(&range).unwrap();
+            self.size -= data.len();
+            let at = (range - start) as usize;
+            merged[at..at + data.len()].copy_from_slice(&data);
+        }
+        let at = (offset - start) as usize;
+        merged[at..at + buf.len()].copy_from_slice(buf);
+        self.size += merged.len();
+        self.ranges.insert(start, merged);
+    }
+
+    /// Copy the buffered data within `offset..offset + buf.len()` into `buf`. Returns whether the
+    /// whole range is buffered.
+    fn read(&self, buf: &mut [u8], offset: u64) -> bool {
+        let end = offset + buf.len() as u64;
+        let mut covered = false;
+        for (start, data) in self.ranges.range(..end).rev() {
+            let range_end = start + data.len() as u64;
+            if range_end <= offset {
+                break;
+            }
+            let from = offset.max(*start);
+            let to = end.min(range_end);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+            covered = *start <= offset && range_end >= end;
+        }
+        covered
+    }
+
+    fn truncate(&mut self, size: u64) {
+        let truncated: Vec<_> = self
+            .ranges
+            .range(..)
+            .rev()
+            .take_while(|(start, data)| *start + data.len() as u64 > size)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in truncated {
+            let mut data = self.ranges.remove(&start).unwrap();
+            self.size -= data.len();
+            if start < size {
+                data.truncate((size - start) as usize);
+                self.size += data.len();
+                self.ranges.insert(start, data);
+            }
+        }
+    }
+}
+
+impl<H: DatabaseHandle> BufferedHandle<H> {
+    /// Write all buffered ranges to the wrapped handle.
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        let buffer = match &mut self.buffer {
+            Some(buffer) if !buffer.ranges.is_empty() => buffer,
+            _ => return Ok(()),
+        };
+        let writes: Vec<_> = buffer
+            .ranges
+            .iter()
+            .map(|(offset, data)| (*offset, data.as_slice()))
+            .collect();
+        self.handle.write_batch(&writes)?;
+        buffer.ranges.clear();
+        buffer.size = 0;
+        Ok(())
+    }
+}
+
+impl<V: Vfs> Vfs for BufferedVfs<V> {
+    type Handle = BufferedHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let write_through = matches!(
+            opts.kind,
+            OpenKind::MainJournal | OpenKind::SuperJournal | OpenKind::Wal
+        );
+        let buffer = (!write_through).then(|| Buffer::new(self.capacity));
+        Ok(BufferedHandle {
+            handle: self.vfs.open(db, opts)?,
+            buffer,
+            wal: AtomicBool::new(false),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for BufferedHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        let size = self.handle.size()?;
+        let end = self.buffer.as_ref().and_then(Buffer::end).unwrap_or(0);
+        Ok(size.max(end))
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let buffer = match &self.buffer {
+            Some(buffer) if !buffer.ranges.is_empty() => buffer,
+            _ => return self.handle.read_exact_at(buf, offset),
+        };
+        if buffer.read(buf, offset) {
+            return Ok(());
+        }
+
+        let end = offset + buf.len() as u64;
+        match self.handle.read_exact_at(buf, offset) {
+            Ok(()) => {}
+            // the end might only exist in the buffer
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                let size = self.handle.size()?;
+                let available = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+                buf.fill(0);
+                if available > 0 {
+                    self.handle.read_exact_at(&mut buf[..available], offset)?;
+                }
+                if buffer.end().unwrap_or(0).max(size) < end {
+                    buffer.read(buf, offset);
+                    return Err(err);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        buffer.read(buf, offset);
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        if self.buffer.is_some() && self.wal.load(Ordering::Relaxed) {
+            self.flush()?;
+            self.buffer = None;
+        }
+
+        let buffer = match &mut self.buffer {
+            Some(buffer) => buffer,
+            None => return self.handle.write_all_at(buf, offset),
+        };
+        buffer.write(buf, offset);
+        if buffer.size > buffer.capacity {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.flush()?;
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.truncate(size);
+        }
+        self.handle.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.flush()?;
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.wal.store(true, Ordering::Relaxed);
+        self.handle.wal_index(readonly)
+    }
+}
+
+impl<H: DatabaseHandle> Drop for BufferedHandle<H> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("failed to flush write buffer on close: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let mut buffer = Buffer::new(usize::MAX);
+        buffer.write(&[1; 10], 0);
+        buffer.write(&[2; 10], 20);
+        assert_eq!(buffer.ranges.len(), 2);
+
+        // fill the gap (touching both ranges)
+        buffer.write(&[3; 10], 10);
+        assert_eq!(buffer.ranges.len(), 1);
+        // overlap the end
+        buffer.write(&[4; 10], 25);
+        assert_eq!(buffer.size, 35);
+
+        let mut expected = vec![1; 10];
+        expected.extend([3; 10]);
+        expected.extend([2; 5]);
+        expected.extend([4; 10]);
+        assert_eq!(buffer.ranges[&0], expected);
+    }
+
+    #[test]
+    fn test_read_overlay() {
+        let mut buffer = Buffer::new(usize::MAX);
+        buffer.write(&[1; 10], 10);
+        buffer.write(&[2; 10], 30);
+
+        let mut buf = [0; 40];
+        assert!(!buffer.read(&mut buf, 0));
+        assert_eq!(&buf[..10], &[0; 10]);
+        assert_eq!(&buf[10..20], &[1; 10]);
+        assert_eq!(&buf[20..30], &[0; 10]);
+        assert_eq!(&buf[30..], &[2; 10]);
+
+        let mut buf = [0; 4];
+        assert!(buffer.read(&mut buf, 12));
+        assert_eq!(buf, [1; 4]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut buffer = Buffer::new(usize::MAX);
+        buffer.write(&[1; 10], 0);
+        buffer.write(&[2; 10], 20);
+        buffer.truncate(5);
+        assert_eq!(buffer.ranges.len(), 1);
+        assert_eq!(buffer.ranges[&0], vec![1; 5]);
+        assert_eq!(buffer.size, 5);
+    }
+}
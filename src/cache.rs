@@ -0,0 +1,451 @@
+//! An in-memory LRU page cache for any [Vfs], to save round-trips to high-latency backends.
+//!
+//! Only main database files are cached. Reads of whole pages (and reads within a cached page, like
+//! the header reads at the start of each transaction) are served from the cache, writes go through
+//! to the wrapped [Vfs] and update the cache.
+//!
+//! Other connections might change the database while this handle holds no lock. The cache is thus
+//! validated whenever the lock moves from [LockKind::None] to [LockKind::Shared], by comparing
+//! [DatabaseHandle::change_counter] (or, if the handle doesn't provide one, the file change counter
+//! in the database header) to the one the cache was filled at. Databases in WAL mode, in which
+//! SQLite holds on to its shared lock, are not cached.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+/// A [Vfs] that caches the pages of the main databases opened through the wrapped [Vfs].
+pub struct CachedVfs<V> {
+    vfs: V,
+    capacity: usize,
+}
+
+/// A [DatabaseHandle] opened by [CachedVfs].
+pub struct CachedHandle<H> {
+    handle: H,
+    lock: LockKind,
+    /// `None` for files that are not cached.
+    cache: Option<Cache>,
+}
+
+struct Cache {
+    /// The maximum size of all cached pages in bytes.
+    capacity: usize,
+    size: usize,
+    page_size: Option<usize>,
+    /// The cached pages by page index, with the tick they were last used at.
+    pages: HashMap<u64, (Vec<u8>, u64)>,
+    /// The page indices by the tick they were last used at.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    /// The change counter the cached pages are valid for.
+    counter: Option<u64>,
+    /// Whether the file got changed through this handle since the last validation.
+    dirty: bool,
+    wal: bool,
+}
+
+impl<V> CachedVfs<V> {
+    /// Wrap `vfs`, caching up to `capacity` bytes of pages per open main database.
+    pub fn new(vfs: V, capacity: usize) -> Self {
+        Self { vfs, capacity }
+    }
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            page_size: None,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            counter: None,
+            dirty: false,
+            wal: false,
+        }
+    }
+
+    fn get(&mut self, index: u64) -> Option<&[u8]> {
+        let (data, used) = self.pages.get_mut(&index)?;
+        self.lru.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.lru.insert(self.tick, index);
+        Some(data)
+    }
+
+    fn insert(&mut self, index: u64, data: Vec<u8>) {
+        self.remove(index);
+        if data.len() > self.capacity {
+            return;
+        }
+
+        while self.size + data.len() > self.capacity {
+            let (_, index) = self.lru.pop_first().expect("cache size out of sync");
+            if let Some((data, _)) = self.pages.remove(&index) {
+                self.size -= data.len();
+            }
+        }
+        self.tick += 1;
+        self.size += data.len();
+        self.lru.insert(self.tick, index);
+        self.pages.insert(index, (data, self.tick));
+    }
+
+    fn remove(&mut self, index: u64) {
+        if let Some((data, used)) = self.pages.remove(&index) {
+            self.lru.remove(&used);
+            self.size -= data.len();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+
+    /// The page index and the offset within the page of the range `offset..offset + len`, if it
+    /// lies within a single page.
+    fn locate(&self, offset: u64, len: usize) -> Option<(u64, usize)> {
+        let page_size = self.page_size? as u64;
+        let index = offset / page_size;
+        let start = (offset - index * page_size) as usize;
+        (start + len <= page_size as usize).then_some((index, start))
+    }
+
+    /// Learn the page size and the journal mode from the data read from or written to the file.
+    fn observe(&mut self, buf: &[u8], offset: u64) {
+        let len = buf.len() as u64;
+        let is_page = buf.len() >= 512 && len.is_power_of_two() && offset.is_multiple_of(len);
+        if is_page && self.page_size != Some(buf.len()) {
+            self.clear();
+            self.page_size = Some(buf.len());
+        }
+
+        if offset == 0 && buf.len() >= 20 {
+            // the read and write versions in the header are `2` for databases in WAL mode
+            self.wal = buf[18] == 2 || buf[19] == 2;
+            if self.wal {
+                self.clear();
+            }
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> bool {
+        let (index, start) = match self.locate(offset, buf.len()) {
+            Some(location) if !self.wal => location,
+            _ => return false,
+        };
+        match self.get(index) {
+            Some(data) if start + buf.len() <= data.len() => {
+                buf.copy_from_slice(&data[start..start + buf.len()]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) {
+        self.dirty = true;
+        self.observe(buf, offset);
+        if self.wal {
+            return;
+        }
+
+        match self.locate(offset, buf.len()) {
+            Some((index, 0)) if Some(buf.len()) == self.page_size => {
+                self.insert(index, buf.to_vec());
+            }
+            Some((index, start)) => {
+                if let Some((data, _)) = self.pages.get_mut(&index) {
+                    data[start..start + buf.len()].copy_from_slice(buf);
+                }
+            }
+            None => {
+                // writes across pages don't happen for main databases, but be safe
+                if let Some(page_size) = self.page_size {
+                    let page_size = page_size as u64;
+                    let end = offset + buf.len() as u64;
+                    for index in offset / page_size..end.div_ceil(page_size) {
+                        self.remove(index);
+                    }
+                }
+            }
+        }
+    }
+
+    fn truncate(&mut self, size: u64) {
+        self.dirty = true;
+        if let Some(page_size) = self.page_size {
+            let page_size = page_size as u64;
+            let truncated: Vec<_> = self
+                .pages
+                .keys()
+                .copied()
+                .filter(|index| (index + 1) * page_size > size)
+                .collect();
+            for index in truncated {
+                self.remove(index);
+            }
+        }
+    }
+}
+
+impl<H: DatabaseHandle> CachedHandle<H> {
+    /// The current change counter of the file.
+    fn change_counter(handle: &mut H) -> Result<Option<u64>, std::io::Error> {
+        if let Some(counter) = handle.change_counter()? {
+            return Ok(Some(counter));
+        }
+
+        // fall back to the file change counter of the database header
+        let mut counter = [0; 4];
+        match handle.read_exact_at(&mut counter, 24) {
+            Ok(()) => Ok(Some(u32::from_be_bytes(counter) as u64)),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<V: Vfs> Vfs for CachedVfs<V> {
+    type Handle = CachedHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let cache = (opts.kind == OpenKind::MainDb).then(|| Cache::new(self.capacity));
+        Ok(CachedHandle {
+            handle: self.vfs.open(db, opts)?,
+            lock: LockKind::None,
+            cache,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for CachedHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.handle.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let cache = match &mut self.cache {
+            Some(cache) => cache,
+            None => return self.handle.read_exact_at(buf, offset),
+        };
+        if cache.read(buf, offset) {
+            return Ok(());
+        }
+
+        self.handle.read_exact_at(buf, offset)?;
+        cache.observe(buf, offset);
+        if !cache.wal {
+            if let Some((index, 0)) = cache.locate(offset, buf.len()) {
+                if Some(buf.len()) == cache.page_size {
+                    cache.insert(index, buf.to_vec());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let result = self.handle.write_all_at(buf, offset);
+        if let Some(cache) = &mut self.cache {
+            match result {
+                Ok(()) => cache.write(buf, offset),
+                // the content of the page is unknown after a failed write
+                Err(_) => cache.clear(),
+            }
+        }
+        result
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let result = self.handle.set_len(size);
+        if let Some(cache) = &mut self.cache {
+            match result {
+                Ok(()) => cache.truncate(size),
+                Err(_) => cache.clear(),
+            }
+        }
+        result
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        if !self.handle.lock(lock)? {
+            return Ok(false);
+        }
+
+        if let Some(cache) = &mut self.cache {
+            if self.lock == LockKind::None && lock == LockKind::Shared {
+                // Retrieving the counter must not fail the lock; start over with an empty cache
+                // instead.
+                let counter = Self::change_counter(&mut self.handle).unwrap_or(None);
+                if counter.is_none() || counter != cache.counter {
+                    cache.clear();
+                }
+                cache.counter = counter;
+                cache.dirty = false;
+            }
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        if let Some(cache) = &mut self.cache {
+            // Remember the counter that includes the own changes while still holding the lock, so
+            // that the cache doesn't get dropped when locking the next time.
+            if lock == LockKind::None && cache.dirty {
+                cache.counter = Self::change_counter(&mut self.handle).unwrap_or(None);
+                cache.dirty = false;
+            }
+        }
+
+        let unlocked = self.handle.unlock(lock)?;
+        if unlocked {
+            self.lock = lock;
+        }
+        Ok(unlocked)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = Cache::new(3 * 512);
+        cache.observe(&[0; 512], 0);
+        for index in 0..3 {
+            cache.insert(index, vec![index as u8; 512]);
+        }
+        // use page 0, so that page 1 is the least recently used one
+        assert!(cache.get(0).is_some());
+        cache.insert(3, vec![3; 512]);
+
+        assert_eq!(cache.size, 3 * 512);
+        assert!(cache.get(1).is_none());
+        for index in [0, 2, 3] {
+            assert_eq!(cache.get(index).unwrap()[0], index as u8);
+        }
+    }
+
+    #[test]
+    fn test_partial_reads_and_writes() {
+        let mut cache = Cache::new(4096);
+        cache.write(&[1; 1024], 1024);
+        cache.write(&[2; 16], 1024 + 24);
+
+        let mut buf = [0; 32];
+        assert!(cache.read(&mut buf, 1024 + 16));
+        assert_eq!(&buf[..8], &[1; 8]);
+        assert_eq!(&buf[8..24], &[2; 16]);
+        // spans two pages
+        assert!(!cache.read(&mut buf, 2048 - 16));
+
+        cache.truncate(1500);
+        assert!(!cache.read(&mut buf, 1024));
+    }
+}
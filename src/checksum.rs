@@ -0,0 +1,280 @@
+//! Page checksums for any [Vfs], compatible with SQLite's `cksumvfs` extension.
+//!
+//! An 8 byte checksum is stored in the reserved bytes at the end of each page of the main database.
+//! Checksums are verified whenever a page is read, and a mismatch is reported as
+//! `SQLITE_IOERR_DATA`. WAL frames are already protected by SQLite's own frame checksums (which
+//! cover the whole page, and would thus break when the page is changed while being written), so
+//! they are passed through unchanged. Their pages get a checksum once checkpointed.
+//!
+//! Checksums are only maintained for databases with exactly [RESERVED_BYTES] reserved bytes per
+//! page (e.g. set by calling `sqlite3_file_control(db, "main", SQLITE_FCNTL_RESERVE_BYTES, &n)`
+//! before creating the first table). Other databases are passed through unchanged. Verification
+//! can be turned off (and on again) with `PRAGMA checksum_verification = OFF`.
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+/// The number of bytes that must be reserved at the end of each database page.
+pub const RESERVED_BYTES: usize = 8;
+
+/// A [Vfs] that maintains page checksums for all databases of the wrapped [Vfs].
+pub struct ChecksumVfs<V> {
+    vfs: V,
+}
+
+/// A [DatabaseHandle] opened by [ChecksumVfs].
+pub struct ChecksumHandle<H> {
+    handle: H,
+    /// `None` for files other than main databases.
+    flags: Option<Flags>,
+}
+
+#[derive(Default)]
+struct Flags {
+    /// Whether checksums are written, i.e. whether the database has the right amount of reserved
+    /// bytes.
+    compute: bool,
+    /// Whether checksums are verified on read.
+    verify: bool,
+}
+
+impl<V> ChecksumVfs<V> {
+    /// Wrap `vfs`.
+    pub fn new(vfs: V) -> Self {
+        Self { vfs }
+    }
+}
+
+impl<V: Vfs> Vfs for ChecksumVfs<V> {
+    type Handle = ChecksumHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let flags = (opts.kind == OpenKind::MainDb).then(Flags::default);
+        Ok(ChecksumHandle {
+            handle: self.vfs.open(db, opts)?,
+            flags,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl Flags {
+    /// Update the flags based on the database header in `buf` (if it is one).
+    fn update(&mut self, buf: &[u8], offset: u64) {
+        if offset != 0 || buf.len() < 100 || !buf.starts_with(b"SQLite format 3\0") {
+            return;
+        }
+
+        // Only reset verification if the reserved bytes changed, so that a disabled verification
+        // stays disabled.
+        let enabled = buf[20] as usize == RESERVED_BYTES;
+        if self.compute != enabled {
+            self.compute = enabled;
+            self.verify = enabled;
+        }
+    }
+}
+
+/// Whether a read or write of `len` bytes is a whole page.
+fn is_page(len: usize) -> bool {
+    len >= 512 && len.is_power_of_two()
+}
+
+/// Compute the checksum of `data` (which must be a multiple of 8 bytes long).
+fn checksum(data: &[u8]) -> [u8; RESERVED_BYTES] {
+    let (mut s1, mut s2) = (0u32, 0u32);
+    for chunk in data.chunks_exact(8) {
+        let a = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+        let b = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+        s1 = s1.wrapping_add(a).wrapping_add(s2);
+        s2 = s2.wrapping_add(b).wrapping_add(s1);
+    }
+
+    let mut out = [0; RESERVED_BYTES];
+    out[..4].copy_from_slice(&s1.to_le_bytes());
+    out[4..].copy_from_slice(&s2.to_le_bytes());
+    out
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for ChecksumHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.handle.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.handle.read_exact_at(buf, offset)?;
+
+        let flags = match &mut self.flags {
+            Some(flags) => flags,
+            None => return Ok(()),
+        };
+        flags.update(buf, offset);
+
+        if is_page(buf.len()) && flags.verify {
+            let (data, expected) = buf.split_at(buf.len() - RESERVED_BYTES);
+            if checksum(data) != expected {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("checksum fault at offset {}", offset),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let flags = match &mut self.flags {
+            Some(flags) => flags,
+            None => return self.handle.write_all_at(buf, offset),
+        };
+        flags.update(buf, offset);
+
+        if is_page(buf.len()) && flags.compute {
+            let mut page = buf.to_vec();
+            let (data, sum) = page.split_at_mut(buf.len() - RESERVED_BYTES);
+            sum.copy_from_slice(&checksum(data));
+            return self.handle.write_all_at(&page, offset);
+        }
+
+        self.handle.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.handle.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        // Changing the page size would also change the reserved bytes, so it is silently ignored
+        // for databases with checksums.
+        let compute = self.flags.as_ref().map(|f| f.compute).unwrap_or(false);
+        if compute && value.is_some() && name.eq_ignore_ascii_case("page_size") {
+            return Some(Ok(None));
+        }
+
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        let flags = match &mut self.flags {
+            Some(flags) => flags,
+            None => return self.handle.checksum_verification(enable),
+        };
+
+        if let Some(enable) = enable {
+            flags.verify = enable && flags.compute;
+        }
+        Some(Ok(flags.verify))
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_matches_cksumvfs() {
+        let page: Vec<u8> = (0..512).map(|i| (i * 7 + 3) as u8).collect();
+        assert_eq!(
+            checksum(&page[..504]),
+            [0xfc, 0xce, 0x0c, 0x20, 0x04, 0xc7, 0xd0, 0x10]
+        );
+    }
+}
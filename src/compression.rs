@@ -0,0 +1,615 @@
+//! Transparent compression for any [Vfs] using DEFLATE.
+//!
+//! A compressed file is split into blocks of a fixed size (the page size for databases), which are
+//! compressed individually and stored wherever they fit in the underlying file. An indirection map
+//! from block index to location is written to the underlying file on [DatabaseHandle::sync] and
+//! when the write lock is released. The blocks and the map are synced before the header that
+//! references them gets written, and blocks referenced by the previous map are only reused once
+//! that header got synced as well, so the underlying file stays consistent if a crash happens in
+//! between.
+//!
+//! Which files are compressed is configured per [OpenKind]. By default, only main databases are
+//! compressed, while journals and WALs stay uncompressed.
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+const MAGIC: &[u8; 8] = b"SQLVFSZ\0";
+const HEADER_LEN: u64 = 32;
+const ENTRY_LEN: usize = 16;
+
+/// How files of a certain [OpenKind] are compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    /// The size of the blocks the file is compressed in. Should match the page size of the
+    /// database. Only used when creating files; existing files keep their block size.
+    pub block_size: usize,
+    /// The DEFLATE compression level (`0` to `10`).
+    pub level: u8,
+}
+
+/// A [Vfs] that compresses the files of the wrapped [Vfs].
+pub struct CompressedVfs<V> {
+    vfs: V,
+    kinds: Vec<(OpenKind, Compression)>,
+}
+
+/// A [DatabaseHandle] opened by [CompressedVfs].
+pub struct CompressedHandle<H: DatabaseHandle> {
+    handle: H,
+    /// `None` for files that are not compressed.
+    store: Option<Store>,
+}
+
+/// The location of a compressed block in the underlying file.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: u32,
+    capacity: u32,
+    /// Whether the block was written after the last map got written (and can thus be overwritten
+    /// in place).
+    fresh: bool,
+}
+
+struct Store {
+    compression: Compression,
+    /// The logical size of the file.
+    size: u64,
+    map: Vec<Option<Entry>>,
+    /// The location of the last written map.
+    map_region: Option<(u64, u32)>,
+    /// Unused regions of the underlying file.
+    free: Vec<(u64, u32)>,
+    /// Regions that become unused once the header of the next map got synced.
+    pending_free: Vec<(u64, u32)>,
+    /// The end of the last used region.
+    end: u64,
+    dirty: bool,
+    /// The last accessed block (index, data, dirty).
+    cache: Option<(u64, Vec<u8>, bool)>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            level: 6,
+        }
+    }
+}
+
+impl<V> CompressedVfs<V> {
+    /// Wrap `vfs` and compress main databases with the default [Compression].
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs,
+            kinds: vec![(OpenKind::MainDb, Compression::default())],
+        }
+    }
+
+    /// Compress files of the given `kind` with `compression`.
+    pub fn with_compression(mut self, kind: OpenKind, compression: Compression) -> Self {
+        self.kinds.retain(|(k, _)| *k != kind);
+        self.kinds.push((kind, compression));
+        self
+    }
+
+    /// Don't compress files of the given `kind`.
+    pub fn without_compression(mut self, kind: OpenKind) -> Self {
+        self.kinds.retain(|(k, _)| *k != kind);
+        self
+    }
+}
+
+impl<V: Vfs> Vfs for CompressedVfs<V> {
+    type Handle = CompressedHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let compression = self
+            .kinds
+            .iter()
+            .find(|(kind, _)| *kind == opts.kind)
+            .map(|(_, compression)| *compression);
+
+        let mut handle = CompressedHandle {
+            handle: self.vfs.open(db, opts)?,
+            store: compression.map(Store::new),
+        };
+        handle.reload()?;
+        Ok(handle)
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl Store {
+    fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            size: 0,
+            map: Vec::new(),
+            map_region: None,
+            free: Vec::new(),
+            pending_free: Vec::new(),
+            end: HEADER_LEN,
+            dirty: false,
+            cache: None,
+        }
+    }
+
+    fn block_size(&self) -> u64 {
+        self.compression.block_size as u64
+    }
+
+    /// Find a place for `len` bytes in the underlying file.
+    fn allocate(&mut self, len: u32) -> (u64, u32) {
+        if let Some(i) = self.free.iter().position(|(_, capacity)| *capacity >= len) {
+            let (offset, capacity) = self.free.swap_remove(i);
+            if capacity > len {
+                self.free.push((offset + len as u64, capacity - len));
+            }
+            return (offset, len);
+        }
+
+        let offset = self.end;
+        self.end += len as u64;
+        (offset, len)
+    }
+
+    /// Release the region of `entry`.
+    fn release(&mut self, entry: Entry) {
+        if entry.fresh {
+            self.free.push((entry.offset, entry.capacity));
+        } else {
+            self.pending_free.push((entry.offset, entry.capacity));
+        }
+    }
+}
+
+impl<H: DatabaseHandle> CompressedHandle<H> {
+    /// (Re-)read the header and map from the underlying file.
+    fn reload(&mut self) -> Result<(), std::io::Error> {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let compression = store.compression;
+        *store = Store::new(compression);
+        if self.handle.size()? == 0 {
+            return Ok(());
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        self.handle.read_exact_at(&mut header, 0)?;
+        if header.iter().all(|b| *b == 0) {
+            // lost power before the first header got written
+            return Ok(());
+        }
+        if &header[..8] != MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a compressed file",
+            ));
+        }
+
+        let block_size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let entries = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        store.compression.block_size = block_size;
+        store.size = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let map_offset = u64::from_le_bytes(header[24..32].try_into().unwrap());
+
+        let mut used = Vec::with_capacity(entries + 1);
+        if entries > 0 {
+            let mut data = vec![0u8; entries * ENTRY_LEN];
+            self.handle.read_exact_at(&mut data, map_offset)?;
+            store.map = data
+                .chunks_exact(ENTRY_LEN)
+                .map(|entry| {
+                    let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+                    (offset > 0).then(|| Entry {
+                        offset,
+                        len: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                        capacity: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                        fresh: false,
+                    })
+                })
+                .collect();
+            store.map_region = Some((map_offset, data.len() as u32));
+            used.push((map_offset, data.len() as u32));
+        }
+
+        // Everything not used by the map or its blocks is free.
+        used.extend(store.map.iter().flatten().map(|e| (e.offset, e.capacity)));
+        used.sort_unstable();
+        let mut pos = HEADER_LEN;
+        for (offset, capacity) in used {
+            if offset > pos {
+                store.free.push((pos, (offset - pos) as u32));
+            }
+            pos = pos.max(offset + capacity as u64);
+        }
+        store.end = pos;
+
+        Ok(())
+    }
+
+    /// Make the block at `index` the cached block and return it.
+    fn block(&mut self, index: u64) -> Result<&mut (u64, Vec<u8>, bool), std::io::Error> {
+        let cached = matches!(
+            self.store.as_ref().and_then(|s| s.cache.as_ref()),
+            Some((i, _, _)) if *i == index
+        );
+        if !cached {
+            self.evict()?;
+            let data = self.load(index)?;
+            self.store.as_mut().unwrap().cache = Some((index, data, false));
+        }
+
+        Ok(self.store.as_mut().unwrap().cache.as_mut().unwrap())
+    }
+
+    /// Read and decompress the block at `index`.
+    fn load(&mut self, index: u64) -> Result<Vec<u8>, std::io::Error> {
+        let store = self.store.as_ref().unwrap();
+        let block_size = store.compression.block_size;
+        let entry = match store.map.get(index as usize).copied().flatten() {
+            Some(entry) => entry,
+            None => return Ok(vec![0u8; block_size]),
+        };
+
+        let mut data = vec![0u8; entry.len as usize];
+        self.handle.read_exact_at(&mut data, entry.offset)?;
+        if entry.len as usize == block_size {
+            return Ok(data);
+        }
+
+        miniz_oxide::inflate::decompress_to_vec(&data).map_err(|err| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("failed to decompress block {}: {:?}", index, err),
+            )
+        })
+    }
+
+    /// Compress and write the cached block if it got changed.
+    fn evict(&mut self) -> Result<(), std::io::Error> {
+        let store = self.store.as_mut().unwrap();
+        let (index, data) = match store.cache.take() {
+            Some((index, data, true)) => (index, data),
+            _ => return Ok(()),
+        };
+
+        let compressed = miniz_oxide::deflate::compress_to_vec(&data, store.compression.level);
+        // Store blocks that don't compress as is, which is detectable as its length equals the
+        // block size.
+        let data = if compressed.len() < data.len() {
+            compressed
+        } else {
+            data
+        };
+        let len = data.len() as u32;
+
+        let index = index as usize;
+        if store.map.len() <= index {
+            store.map.resize(index + 1, None);
+        }
+        let entry = match store.map[index] {
+            Some(entry) if entry.fresh && entry.capacity >= len => Entry { len, ..entry },
+            previous => {
+                if let Some(previous) = previous {
+                    store.release(previous);
+                }
+                let (offset, capacity) = store.allocate(len);
+                Entry {
+                    offset,
+                    len,
+                    capacity,
+                    fresh: true,
+                }
+            }
+        };
+        store.map[index] = Some(entry);
+        store.dirty = true;
+
+        self.handle.write_all_at(&data, entry.offset)
+    }
+
+    /// Write all pending changes, the map and the header to the underlying file. Returns whether
+    /// the underlying file got synced.
+    fn flush(&mut self) -> Result<bool, std::io::Error> {
+        if self.store.is_none() {
+            return Ok(false);
+        }
+
+        self.evict()?;
+        let store = self.store.as_mut().unwrap();
+        if !store.dirty {
+            return Ok(false);
+        }
+
+        let block_size = store.block_size();
+        let blocks = store.size.div_ceil(block_size) as usize;
+        store.map.truncate(blocks);
+        let mut map = Vec::with_capacity(store.map.len() * ENTRY_LEN);
+        for entry in &store.map {
+            let (offset, len, capacity) = entry
+                .map(|e| (e.offset, e.len, e.capacity))
+                .unwrap_or_default();
+            map.extend_from_slice(&offset.to_le_bytes());
+            map.extend_from_slice(&len.to_le_bytes());
+            map.extend_from_slice(&capacity.to_le_bytes());
+        }
+
+        let (map_offset, map_capacity) = store.allocate(map.len() as u32);
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&(block_size as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(store.map.len() as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&store.size.to_le_bytes());
+        header[24..32].copy_from_slice(&map_offset.to_le_bytes());
+
+        // The blocks and the map have to be durable before the header references them, and the
+        // header before the regions referenced by the previous map get reused.
+        self.handle.write_all_at(&map, map_offset)?;
+        self.handle.sync(true)?;
+        self.handle.write_all_at(&header, 0)?;
+        self.handle.sync(true)?;
+
+        // Everything referenced by the previous map can be reused now.
+        let store = self.store.as_mut().unwrap();
+        if let Some(region) = store.map_region.replace((map_offset, map_capacity)) {
+            store.pending_free.push(region);
+        }
+        let pending = std::mem::take(&mut store.pending_free);
+        store.free.extend(pending);
+        for entry in store.map.iter_mut().flatten() {
+            entry.fresh = false;
+        }
+        store.dirty = false;
+
+        Ok(true)
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for CompressedHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.store {
+            Some(store) => Ok(store.size),
+            None => self.handle.size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let (size, block_size) = match &self.store {
+            Some(store) => (store.size, store.block_size()),
+            None => return self.handle.read_exact_at(buf, offset),
+        };
+
+        let end = (offset + buf.len() as u64).min(size);
+        let mut pos = offset;
+        while pos < end {
+            let start = (pos % block_size) as usize;
+            let n = ((end - pos) as usize).min(block_size as usize - start);
+            let (_, data, _) = self.block(pos / block_size)?;
+            let at = (pos - offset) as usize;
+            buf[at..at + n].copy_from_slice(&data[start..start + n]);
+            pos += n as u64;
+        }
+
+        if end < offset + buf.len() as u64 {
+            buf[end.saturating_sub(offset) as usize..].fill(0);
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let block_size = match &self.store {
+            Some(store) => store.block_size(),
+            None => return self.handle.write_all_at(buf, offset),
+        };
+
+        let end = offset + buf.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let start = (pos % block_size) as usize;
+            let n = ((end - pos) as usize).min(block_size as usize - start);
+            let at = (pos - offset) as usize;
+            let (_, data, dirty) = self.block(pos / block_size)?;
+            data[start..start + n].copy_from_slice(&buf[at..at + n]);
+            *dirty = true;
+            pos += n as u64;
+        }
+
+        let store = self.store.as_mut().unwrap();
+        store.size = store.size.max(end);
+        store.dirty = true;
+
+        Ok(())
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        if self.flush()? && data_only {
+            return Ok(());
+        }
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return self.handle.set_len(size),
+        };
+
+        if size < store.size {
+            let block_size = store.block_size();
+            let (index, rem) = (size / block_size, size % block_size);
+
+            // Drop all blocks past the new end.
+            let blocks = (index + u64::from(rem > 0)) as usize;
+            if matches!(store.cache, Some((i, _, _)) if i >= blocks as u64) {
+                store.cache = None;
+            }
+            if store.map.len() > blocks {
+                for entry in store.map.split_off(blocks).into_iter().flatten() {
+                    store.release(entry);
+                }
+            }
+
+            // Zero the remainder of the new last block, so that it reads as zeros when the file is
+            // extended again.
+            if rem > 0 {
+                let (_, data, dirty) = self.block(index)?;
+                data[rem as usize..].fill(0);
+                *dirty = true;
+            }
+        }
+
+        let store = self.store.as_mut().unwrap();
+        store.size = size;
+        store.dirty = true;
+
+        Ok(())
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let previous = self.handle.current_lock()?;
+        if lock <= LockKind::Shared && previous > LockKind::Shared {
+            self.flush()?;
+        }
+
+        let ok = self.handle.lock(lock)?;
+        if ok && previous == LockKind::None && lock == LockKind::Shared {
+            // Other connections might have changed the file in the meantime.
+            self.reload()?;
+        }
+        Ok(ok)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        if lock <= LockKind::Shared && self.handle.current_lock()? > LockKind::Shared {
+            self.flush()?;
+        }
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+impl<H: DatabaseHandle> Drop for CompressedHandle<H> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("failed to write compressed blocks on close: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_reuses_free_regions() {
+        let mut store = Store::new(Compression::default());
+        assert_eq!(store.allocate(100), (HEADER_LEN, 100));
+        assert_eq!(store.allocate(50), (HEADER_LEN + 100, 50));
+
+        store.free.push((HEADER_LEN, 100));
+        assert_eq!(store.allocate(60), (HEADER_LEN, 60));
+        assert_eq!(store.allocate(40), (HEADER_LEN + 60, 40));
+        assert_eq!(store.allocate(10), (HEADER_LEN + 150, 10));
+    }
+}
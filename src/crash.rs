@@ -0,0 +1,642 @@
+//! Power-loss simulation for any [Vfs], to test that a database survives a crash at any point.
+//!
+//! [CrashVfs] passes all calls through to the wrapped [Vfs], but remembers each write and
+//! truncation since the last [DatabaseHandle::sync] of a file. A simulated power loss (see
+//! [Crash::power_loss]) then decides which of these unsynced changes made it to the disk: writes
+//! can be dropped, torn (only some of their sectors made it, others got garbled) or reordered with
+//! the other unsynced writes to the same file. Writes are split into sectors of the size the
+//! wrapped file reports ([DatabaseHandle::sector_size]). Unless the file is "powersafe-overwrite"
+//! (the `psow` URI parameter, enabled by default, and [DatabaseHandle::powersafe_overwrite] of the
+//! wrapped file), a garbled sector is garbled entirely and not just the bytes that were written to
+//! it.
+//!
+//! The surviving state is written to the wrapped [Vfs], while all files of the crashed [CrashVfs]
+//! fail from then on. Reopen the database with a fresh VFS:
+//!
+//! ```ignore
+//! let vfs = CrashVfs::new(mem.clone());
+//! let crash = vfs.crash();
+//! sqlite_vfs::register("crash", vfs, false)?;
+//! // ... run a workload ...
+//! crash.power_loss(PowerLoss::Random(seed))?;
+//! sqlite_vfs::register("recovered", CrashVfs::new(mem), false)?;
+//! ```
+//!
+//! Files that are deleted on close (temporary files) don't survive a crash anyway and are not
+//! tracked. Deleting a file is considered durable.
+//!
+//! [CrashVfs::crash_at] loses power at a specific write or sync instead. [CrashTest] uses it to
+//! check a workload against a power loss at each of its writes and syncs.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+mod harness;
+
+pub use harness::{CrashFailure, CrashTest};
+
+/// A [Vfs] that can simulate a power loss, see the [module documentation](self).
+pub struct CrashVfs<V> {
+    vfs: Arc<V>,
+    state: Arc<Mutex<State>>,
+}
+
+/// A [DatabaseHandle] opened by [CrashVfs].
+pub struct CrashHandle<V: Vfs> {
+    handle: V::Handle,
+    db: String,
+    kind: OpenKind,
+    /// Whether unsynced changes to the file are tracked.
+    tracked: bool,
+    powersafe_overwrite: bool,
+    crash: Crash<V>,
+}
+
+/// Triggers the power loss of a [CrashVfs]. Cheap to clone.
+pub struct Crash<V> {
+    vfs: Arc<V>,
+    state: Arc<Mutex<State>>,
+}
+
+/// What happens to the unsynced changes on a power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerLoss {
+    /// All unsynced changes are lost.
+    DropAll,
+    /// All unsynced changes made it to the disk.
+    KeepAll,
+    /// Each sector of an unsynced write is randomly kept, dropped or garbled, and the writes to a
+    /// file land in random order (truncations stay in place). Deterministic for the given seed.
+    Random(u64),
+}
+
+#[derive(Default)]
+struct State {
+    crashed: bool,
+    /// The unsynced changes per file, in the order they happened.
+    files: HashMap<String, File>,
+    /// The files that are deleted on close.
+    temporary: HashSet<String>,
+    /// The number of writes and syncs of tracked files so far.
+    boundaries: usize,
+    /// Lose power right before the given boundary.
+    crash_at: Option<(usize, PowerLoss)>,
+    /// A description of the boundary the power got lost at.
+    crash_point: Option<String>,
+}
+
+struct File {
+    kind: OpenKind,
+    changes: Vec<Change>,
+}
+
+/// An unsynced change to a file, with everything needed to undo it.
+struct Change {
+    op: ChangeOp,
+    /// The size of the file before the change.
+    old_size: u64,
+    /// The offset the change starts at (the new size for truncations).
+    offset: u64,
+    /// The content at `offset` (up to `old_size`) before the change.
+    old: Vec<u8>,
+    /// The sector size of the file at the time of the change.
+    sector_size: usize,
+    /// Whether the file was "powersafe-overwrite" at the time of the change.
+    powersafe_overwrite: bool,
+}
+
+enum ChangeOp {
+    Write(Vec<u8>),
+    SetLen(u64),
+}
+
+/// A sector (or part of it) of an unsynced write, as it is replayed after a power loss.
+enum Piece<'a> {
+    Write(u64, &'a [u8]),
+    Garble(u64, usize),
+    SetLen(u64),
+}
+
+impl<V> CrashVfs<V> {
+    /// Wrap `vfs`.
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs: Arc::new(vfs),
+            state: Default::default(),
+        }
+    }
+
+    /// Lose power (as described by `loss`) right before the `boundary`th (counting from `0`) write
+    /// or sync of a file that would survive a crash.
+    pub fn crash_at(self, boundary: usize, loss: PowerLoss) -> Self {
+        self.state.lock().unwrap().crash_at = Some((boundary, loss));
+        self
+    }
+
+    /// The [Crash] to trigger the power loss with. Retrieve it before registering the VFS.
+    pub fn crash(&self) -> Crash<V> {
+        Crash {
+            vfs: self.vfs.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<V> Clone for Crash<V> {
+    fn clone(&self) -> Self {
+        Self {
+            vfs: self.vfs.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<V: Vfs> Crash<V> {
+    /// Simulate a power loss: write the changes that survived (according to `loss`) to the wrapped
+    /// [Vfs] and fail all further calls to the [CrashVfs] and its files.
+    pub fn power_loss(&self, loss: PowerLoss) -> Result<(), std::io::Error> {
+        let mut state = self::state(&self.state)?;
+        self.lose_power(&mut state, loss)
+    }
+
+    fn lose_power(&self, state: &mut State, loss: PowerLoss) -> Result<(), std::io::Error> {
+        state.crashed = true;
+
+        let seed = match loss {
+            PowerLoss::KeepAll => return Ok(()),
+            PowerLoss::DropAll => None,
+            PowerLoss::Random(seed) => Some(seed),
+        };
+        let mut rng = Rng(seed.unwrap_or_default());
+
+        // sorted to be deterministic for a given seed
+        let mut files: Vec<_> = state.files.drain().collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        for (db, file) in files {
+            let mut handle = self
+                .vfs
+                .open(&db, OpenOptions::new(file.kind, OpenAccess::Write))?;
+            let mut data = vec![0; handle.size()? as usize];
+            handle.read_exact_at(&mut data, 0)?;
+
+            for change in file.changes.iter().rev() {
+                change.undo(&mut data);
+            }
+            if seed.is_some() {
+                replay(&mut data, &file.changes, &mut rng);
+            }
+
+            handle.write_all_at(&data, 0)?;
+            handle.set_len(data.len() as u64)?;
+            handle.sync(false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the power loss already happened.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// The number of writes and syncs (of files that would survive a crash) so far.
+    pub fn boundaries(&self) -> usize {
+        self.state.lock().unwrap().boundaries
+    }
+
+    /// A description of the write or sync the power got lost at (when lost via
+    /// [CrashVfs::crash_at]).
+    pub fn crash_point(&self) -> Option<String> {
+        self.state.lock().unwrap().crash_point.clone()
+    }
+
+    /// The number of changes (writes and truncations) that haven't been synced yet.
+    pub fn unsynced(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.files.values().map(|file| file.changes.len()).sum()
+    }
+}
+
+impl Change {
+    fn undo(&self, data: &mut Vec<u8>) {
+        data.resize(self.old_size as usize, 0);
+        if !self.old.is_empty() {
+            let offset = self.offset as usize;
+            data[offset..offset + self.old.len()].copy_from_slice(&self.old);
+        }
+    }
+}
+
+/// Replay the `changes` on `data` (the content at the last sync) as they could have survived a
+/// power loss.
+fn replay(data: &mut Vec<u8>, changes: &[Change], rng: &mut Rng) {
+    let mut pieces = Vec::new();
+    let mut unordered = 0;
+    for change in changes {
+        let buf = match &change.op {
+            ChangeOp::Write(buf) => buf,
+            ChangeOp::SetLen(size) => {
+                // the writes before the truncation land in random order, but not after it
+                shuffle(&mut pieces[unordered..], rng);
+                if rng.below(2) == 0 {
+                    pieces.push(Piece::SetLen(*size));
+                }
+                unordered = pieces.len();
+                continue;
+            }
+        };
+
+        let sector_size = change.sector_size as u64;
+        let end = change.offset + buf.len() as u64;
+        let mut offset = change.offset;
+        while offset < end {
+            let sector = offset / sector_size * sector_size;
+            let len = ((sector + sector_size).min(end) - offset) as usize;
+            let start = (offset - change.offset) as usize;
+            match rng.below(4) {
+                0 | 1 => pieces.push(Piece::Write(offset, &buf[start..start + len])),
+                2 => {}
+                _ if change.powersafe_overwrite => pieces.push(Piece::Garble(offset, len)),
+                _ => pieces.push(Piece::Garble(sector, sector_size as usize)),
+            }
+            offset += len as u64;
+        }
+    }
+    shuffle(&mut pieces[unordered..], rng);
+
+    for piece in pieces {
+        match piece {
+            Piece::Write(offset, buf) => {
+                let offset = offset as usize;
+                if data.len() < offset + buf.len() {
+                    data.resize(offset + buf.len(), 0);
+                }
+                data[offset..offset + buf.len()].copy_from_slice(buf);
+            }
+            Piece::Garble(offset, len) => {
+                // a sector beyond the end of the file doesn't exist (yet)
+                let offset = (offset as usize).min(data.len());
+                let len = len.min(data.len() - offset);
+                for b in &mut data[offset..offset + len] {
+                    *b = rng.next() as u8;
+                }
+            }
+            Piece::SetLen(size) => data.resize(size as usize, 0),
+        }
+    }
+}
+
+fn shuffle<T>(items: &mut [T], rng: &mut Rng) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.below(i + 1));
+    }
+}
+
+/// A small deterministic random number generator (SplitMix64).
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn crashed() -> std::io::Error {
+    std::io::Error::other("simulated power loss")
+}
+
+fn state(state: &Mutex<State>) -> Result<MutexGuard<'_, State>, std::io::Error> {
+    let state = state.lock().unwrap();
+    if state.crashed {
+        return Err(crashed());
+    }
+    Ok(state)
+}
+
+fn check(state: &Mutex<State>) -> Result<(), std::io::Error> {
+    self::state(state).map(|_| ())
+}
+
+impl<V: Vfs + Send> Vfs for CrashVfs<V> {
+    type Handle = CrashHandle<V>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let mut state = state(&self.state)?;
+        let kind = opts.kind;
+        let tracked = !opts.delete_on_close;
+        if !tracked {
+            state.temporary.insert(db.to_string());
+        }
+        Ok(CrashHandle {
+            handle: self.vfs.open(db, opts)?,
+            db: db.to_string(),
+            kind,
+            tracked,
+            powersafe_overwrite: true,
+            crash: self.crash(),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        // temporary files can still be deleted (when closed) after the power loss
+        if state.crashed && !state.temporary.contains(db) {
+            return Err(crashed());
+        }
+        state.files.remove(db);
+        state.temporary.remove(db);
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        check(&self.state)?;
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        check(&self.state)?;
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<V: Vfs> CrashHandle<V> {
+    /// Count the write or sync (described by `describe`) as a boundary, and lose power if it is
+    /// the one to crash at.
+    fn boundary(
+        &self,
+        state: &mut State,
+        describe: impl FnOnce() -> String,
+    ) -> Result<(), std::io::Error> {
+        let boundary = state.boundaries;
+        state.boundaries += 1;
+        match state.crash_at {
+            Some((at, loss)) if at == boundary => {
+                state.crash_point = Some(describe());
+                self.crash.lose_power(state, loss)?;
+                Err(crashed())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply a change to the file via `f`, and remember how to undo it.
+    fn change(
+        &mut self,
+        op: ChangeOp,
+        offset: u64,
+        f: impl FnOnce(&mut V::Handle) -> Result<(), std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let mut state = state(&self.crash.state)?;
+        if !self.tracked {
+            return f(&mut self.handle);
+        }
+        if let ChangeOp::Write(buf) = &op {
+            self.boundary(&mut state, || {
+                format!(
+                    "write of {} bytes at offset {} to {}",
+                    buf.len(),
+                    offset,
+                    self.db
+                )
+            })?;
+        }
+
+        let old_size = self.handle.size()?;
+        let end = match &op {
+            ChangeOp::Write(buf) => offset + buf.len() as u64,
+            ChangeOp::SetLen(_) => old_size,
+        };
+        let mut old = vec![0; end.min(old_size).saturating_sub(offset) as usize];
+        if !old.is_empty() {
+            self.handle.read_exact_at(&mut old, offset)?;
+        }
+        f(&mut self.handle)?;
+
+        let file = state.files.entry(self.db.clone()).or_insert_with(|| File {
+            kind: self.kind,
+            changes: Vec::new(),
+        });
+        file.changes.push(Change {
+            op,
+            old_size,
+            offset,
+            old,
+            sector_size: self.handle.sector_size(),
+            powersafe_overwrite: self.powersafe_overwrite && self.handle.powersafe_overwrite(),
+        });
+        Ok(())
+    }
+}
+
+impl<V: Vfs + Send> DatabaseHandle for CrashHandle<V> {
+    type WalIndex = <V::Handle as DatabaseHandle>::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        check(&self.crash.state)?;
+        self.handle.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        check(&self.crash.state)?;
+        self.handle.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.change(ChangeOp::Write(buf.to_vec()), offset, |handle| {
+            handle.write_all_at(buf, offset)
+        })
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        let mut state = state(&self.crash.state)?;
+        if self.tracked {
+            self.boundary(&mut state, || format!("sync of {}", self.db))?;
+        }
+        self.handle.sync(data_only)?;
+        state.files.remove(&self.db);
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.change(ChangeOp::SetLen(size), size, |handle| handle.set_len(size))
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        check(&self.crash.state)?;
+        self.handle.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        // locks are released on a power loss anyway
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        check(&self.crash.state)?;
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.powersafe_overwrite = enabled;
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(offset: u64, len: usize, sector_size: usize, powersafe_overwrite: bool) -> Change {
+        Change {
+            op: ChangeOp::Write(vec![0xff; len]),
+            old_size: 4096,
+            offset,
+            old: vec![0; len],
+            sector_size,
+            powersafe_overwrite,
+        }
+    }
+
+    #[test]
+    fn test_replay_respects_psow() {
+        let mut garbled_neighbours = false;
+        for seed in 0..64 {
+            for psow in [true, false] {
+                let mut data = vec![0; 4096];
+                replay(&mut data, &[write(100, 10, 1024, psow)], &mut Rng(seed));
+                assert_eq!(data.len(), 4096);
+                let outside = data[..100].iter().chain(&data[110..]).any(|b| *b != 0);
+                if psow {
+                    assert!(!outside, "psow write changed adjacent bytes");
+                } else {
+                    garbled_neighbours |= outside;
+                    assert!(data[1024..].iter().all(|b| *b == 0));
+                }
+            }
+        }
+        assert!(garbled_neighbours);
+    }
+
+    #[test]
+    fn test_replay_garbles_whole_sectors() {
+        let mut garbled_sector = false;
+        for seed in 0..64 {
+            let mut data = vec![0; 8192];
+            replay(&mut data, &[write(100, 10, 4096, false)], &mut Rng(seed));
+            garbled_sector |= data[1024..4096].iter().any(|b| *b != 0);
+            assert!(data[4096..].iter().all(|b| *b == 0));
+        }
+        assert!(garbled_sector);
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut data = vec![1; 1000];
+        data.resize(1300, 0xff);
+        data[800..].fill(0xff);
+        data.truncate(900);
+        let changes = [
+            Change {
+                op: ChangeOp::Write(vec![0xff; 500]),
+                old_size: 1000,
+                offset: 800,
+                old: vec![1; 200],
+                sector_size: 512,
+                powersafe_overwrite: true,
+            },
+            Change {
+                op: ChangeOp::SetLen(900),
+                old_size: 1300,
+                offset: 900,
+                old: vec![0xff; 400],
+                sector_size: 512,
+                powersafe_overwrite: true,
+            },
+        ];
+        for change in changes.iter().rev() {
+            change.undo(&mut data);
+        }
+        assert_eq!(data, vec![1; 1000]);
+    }
+}
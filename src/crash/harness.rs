@@ -0,0 +1,245 @@
+use std::ffi::{c_void, CStr, CString};
+use std::fmt::Display;
+use std::os::raw::{c_char, c_int};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{CrashVfs, PowerLoss};
+use crate::{ffi, register, Vfs};
+
+/// Checks that a database survives a power loss at any point of a workload.
+///
+/// The workload is run once per crash point: the first run loses power right before the first
+/// write or sync, the second run right before the second one, and so on, until the workload
+/// completes without reaching the crash point (the power is then lost after the workload). After
+/// each crash, the database is reopened with a fresh VFS over the surviving state and checked with
+/// `PRAGMA integrity_check` and the user's invariants.
+///
+/// ```ignore
+/// CrashTest::new(MemVfs::default)
+///     .run(
+///         |vfs| {
+///             let conn = Connection::open_with_flags_and_vfs("main.db", flags, vfs)?;
+///             conn.execute_batch("...")
+///         },
+///         |vfs| {
+///             let conn = Connection::open_with_flags_and_vfs("main.db", flags, vfs)?;
+///             // check the invariants
+///         },
+///     )
+///     .unwrap();
+/// ```
+///
+/// The VFSs are registered with SQLite under unique names and are never unregistered, so keep the
+/// workloads small.
+pub struct CrashTest<F> {
+    new_vfs: F,
+    db: String,
+    loss: PowerLoss,
+    point: Option<usize>,
+}
+
+/// The first crash point a [CrashTest] failed at.
+#[derive(Debug, Clone)]
+pub struct CrashFailure {
+    /// The crash point (the number of writes and syncs before the power got lost).
+    pub point: usize,
+    /// A description of the write or sync the power got lost at (`None` if the power got lost
+    /// after the workload completed).
+    pub boundary: Option<String>,
+    /// The [PowerLoss] the [CrashTest] was configured with.
+    pub loss: PowerLoss,
+    /// What failed.
+    pub error: String,
+}
+
+impl<F, V> CrashTest<F>
+where
+    F: FnMut() -> V,
+    V: Vfs + Clone + Send,
+{
+    /// Create a test that wraps a fresh VFS returned by `new_vfs` for each crash point. Clones of
+    /// the returned VFS must share their files, as the database is reopened with a clone after the
+    /// crash.
+    pub fn new(new_vfs: F) -> Self {
+        Self {
+            new_vfs,
+            db: "main.db".to_string(),
+            loss: PowerLoss::Random(0),
+            point: None,
+        }
+    }
+
+    /// The database (a name or `file:` URI) to check after each crash. Defaults to `main.db`.
+    pub fn database(mut self, db: &str) -> Self {
+        self.db = db.to_string();
+        self
+    }
+
+    /// What happens to the unsynced changes on each power loss. A [PowerLoss::Random] seed is
+    /// offset by the crash point, so that each crash point sees different losses. Defaults to
+    /// `PowerLoss::Random(0)`.
+    pub fn loss(mut self, loss: PowerLoss) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    /// Only run the given crash point, e.g. to reproduce a [CrashFailure].
+    pub fn point(mut self, point: usize) -> Self {
+        self.point = Some(point);
+        self
+    }
+
+    /// Run the `workload` and check the `invariants` after each crash. Both receive the name of
+    /// the VFS to open the database with. Errors of the workload are expected once the power got
+    /// lost. Returns the number of crash points checked.
+    pub fn run<W, I, E1, E2>(
+        mut self,
+        mut workload: W,
+        mut invariants: I,
+    ) -> Result<usize, CrashFailure>
+    where
+        W: FnMut(&str) -> Result<(), E1>,
+        I: FnMut(&str) -> Result<(), E2>,
+        E1: Display,
+        E2: Display,
+    {
+        let start = self.point.unwrap_or(0);
+        for point in start.. {
+            let fail = |boundary, error| CrashFailure {
+                point,
+                boundary,
+                loss: self.loss,
+                error,
+            };
+            let loss = match self.loss {
+                PowerLoss::Random(seed) => PowerLoss::Random(seed.wrapping_add(point as u64)),
+                loss => loss,
+            };
+
+            let inner = (self.new_vfs)();
+            let vfs = CrashVfs::new(inner.clone()).crash_at(point, loss);
+            let crash = vfs.crash();
+            let result = workload(&register_unique(vfs));
+
+            let completed = !crash.crashed();
+            if completed {
+                if let Err(err) = result {
+                    return Err(fail(None, format!("workload failed: {}", err)));
+                }
+                crash
+                    .power_loss(loss)
+                    .map_err(|err| fail(None, format!("power loss failed: {}", err)))?;
+            }
+
+            let boundary = crash.crash_point();
+            let vfs = register_unique(CrashVfs::new(inner));
+            if let Err(err) = integrity_check(&vfs, &self.db) {
+                return Err(fail(boundary, err));
+            }
+            if let Err(err) = invariants(&vfs) {
+                return Err(fail(boundary, format!("invariant violated: {}", err)));
+            }
+
+            if completed || self.point.is_some() {
+                return Ok(point - start + 1);
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Display for CrashFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.boundary {
+            Some(boundary) => write!(f, "crash at point {} ({})", self.point, boundary)?,
+            None => write!(f, "crash at point {} (after the workload)", self.point)?,
+        }
+        write!(
+            f,
+            " failed: {}; reproduce with `.loss(PowerLoss::{:?}).point({})`",
+            self.error, self.loss, self.point
+        )
+    }
+}
+
+impl std::error::Error for CrashFailure {}
+
+fn register_unique<V: Vfs + Send>(vfs: CrashVfs<V>) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("crash-test-{}", COUNTER.fetch_add(1, Ordering::Relaxed));
+    register(&name, vfs, false).expect("register crash test vfs");
+    name
+}
+
+/// Open `db` with the VFS `vfs` and run `PRAGMA integrity_check` on it.
+fn integrity_check(vfs: &str, db: &str) -> Result<(), String> {
+    struct Connection(*mut ffi::sqlite3);
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            unsafe { ffi::sqlite3_close(self.0) };
+        }
+    }
+
+    unsafe extern "C" fn collect(
+        rows: *mut c_void,
+        n: c_int,
+        values: *mut *mut c_char,
+        _names: *mut *mut c_char,
+    ) -> c_int {
+        let rows = &mut *(rows as *mut Vec<String>);
+        if n > 0 && !(*values).is_null() {
+            rows.push(CStr::from_ptr(*values).to_string_lossy().into_owned());
+        }
+        ffi::SQLITE_OK
+    }
+
+    let vfs = CString::new(vfs).map_err(|err| err.to_string())?;
+    let db = CString::new(db).map_err(|err| err.to_string())?;
+    let sql = CString::new("PRAGMA integrity_check").unwrap();
+    unsafe {
+        let mut conn = Connection(null_mut());
+        let rc = ffi::sqlite3_open_v2(
+            db.as_ptr(),
+            &mut conn.0,
+            ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_URI,
+            vfs.as_ptr(),
+        );
+        if rc != ffi::SQLITE_OK {
+            return Err(format!("reopening failed: {}", errmsg(conn.0)));
+        }
+
+        let mut rows: Vec<String> = Vec::new();
+        let mut err = null_mut();
+        let rc = ffi::sqlite3_exec(
+            conn.0,
+            sql.as_ptr(),
+            Some(collect),
+            &mut rows as *mut Vec<String> as *mut c_void,
+            &mut err,
+        );
+        if rc != ffi::SQLITE_OK {
+            let msg = if err.is_null() {
+                errmsg(conn.0)
+            } else {
+                let msg = CStr::from_ptr(err).to_string_lossy().into_owned();
+                ffi::sqlite3_free(err as *mut c_void);
+                msg
+            };
+            return Err(format!("integrity_check failed: {}", msg));
+        }
+        if rows != ["ok"] {
+            return Err(format!("integrity_check: {}", rows.join("; ")));
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn errmsg(conn: *mut ffi::sqlite3) -> String {
+    match ffi::sqlite3_errmsg(conn).as_ref() {
+        Some(msg) => CStr::from_ptr(msg).to_string_lossy().into_owned(),
+        None => "out of memory".to_string(),
+    }
+}
@@ -0,0 +1,222 @@
+//! A read-only [Vfs] over databases embedded in the binary (e.g. with `include_bytes!`) or held
+//! in memory, to query them without any file system.
+//!
+//! ```ignore
+//! static LOOKUP: &[u8] = include_bytes!("lookup.db");
+//!
+//! register("embedded", StaticVfs::new().with_database("lookup.db", LOOKUP), false)?;
+//! ```
+//!
+//! The databases are opened read-only (even if a read-write connection was requested), so writes
+//! fail with `SQLITE_READONLY`. They are reported as immutable (`SQLITE_IOCAP_IMMUTABLE`), so
+//! SQLite doesn't lock them nor look for a hot journal. Temporary files and journals are kept in
+//! memory. The databases must not be in WAL mode.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::memory::{MemoryHandle, MemoryVfs};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs, WalDisabled};
+
+/// A read-only [Vfs] over named byte slices.
+#[derive(Default)]
+pub struct StaticVfs {
+    databases: HashMap<String, Bytes>,
+    /// Temporary files and journals.
+    memory: MemoryVfs,
+}
+
+/// The content of a database registered with [StaticVfs::with_database].
+#[derive(Clone)]
+pub enum Bytes {
+    Static(&'static [u8]),
+    Shared(Arc<[u8]>),
+}
+
+/// A [DatabaseHandle] opened by [StaticVfs].
+pub struct StaticHandle {
+    file: File,
+}
+
+enum File {
+    Static { data: Bytes, lock: LockKind },
+    Memory(MemoryHandle),
+}
+
+impl StaticVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `data` as the database `name`.
+    pub fn with_database(mut self, name: &str, data: impl Into<Bytes>) -> Self {
+        self.databases.insert(name.to_string(), data.into());
+        self
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Bytes::Static(data) => data,
+            Bytes::Shared(data) => data,
+        }
+    }
+}
+
+impl From<&'static [u8]> for Bytes {
+    fn from(data: &'static [u8]) -> Self {
+        Bytes::Static(data)
+    }
+}
+
+impl<const N: usize> From<&'static [u8; N]> for Bytes {
+    fn from(data: &'static [u8; N]) -> Self {
+        Bytes::Static(data)
+    }
+}
+
+impl From<Arc<[u8]>> for Bytes {
+    fn from(data: Arc<[u8]>) -> Self {
+        Bytes::Shared(data)
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Self {
+        Bytes::Shared(data.into())
+    }
+}
+
+impl Vfs for StaticVfs {
+    type Handle = StaticHandle;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(StaticHandle {
+                file: File::Memory(self.memory.open(db, opts)?),
+            });
+        }
+
+        let data = self.databases.get(db).ok_or(ErrorKind::NotFound)?;
+        if opts.access != OpenAccess::Read {
+            // SQLite retries to open it read-only
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        Ok(StaticHandle {
+            file: File::Static {
+                data: data.clone(),
+                lock: LockKind::None,
+            },
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        if self.databases.contains_key(db) {
+            Err(ErrorKind::PermissionDenied.into())
+        } else {
+            self.memory.delete(db)
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.databases.contains_key(db) || self.memory.exists(db)?)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.memory.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.memory.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.memory.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        if self.databases.contains_key(db) {
+            return Ok(!write);
+        }
+        self.memory.access(db, write)
+    }
+}
+
+impl DatabaseHandle for StaticHandle {
+    type WalIndex = WalDisabled;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.file {
+            File::Static { data, .. } => Ok(data.len() as u64),
+            File::Memory(file) => file.size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let data = match &mut self.file {
+            File::Static { data, .. } => data,
+            File::Memory(file) => return file.read_exact_at(buf, offset),
+        };
+        let offset = (offset as usize).min(data.len());
+        let n = (data.len() - offset).min(buf.len());
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        buf[n..].fill(0);
+        if n < buf.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Static { .. } => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Static { .. } => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.set_len(size),
+        }
+    }
+
+    fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
+        match &mut self.file {
+            File::Static { lock, .. } => {
+                *lock = to;
+                Ok(true)
+            }
+            File::Memory(file) => file.lock(to),
+        }
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        match &self.file {
+            File::Static { lock, .. } => Ok(*lock),
+            File::Memory(file) => file.current_lock(),
+        }
+    }
+
+    fn immutable(&self) -> bool {
+        matches!(self.file, File::Static { .. })
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(WalDisabled)
+    }
+}
@@ -0,0 +1,710 @@
+//! Encryption at rest for any [Vfs] using ChaCha20-Poly1305 (an AEAD cipher).
+//!
+//! Main database pages are encrypted in place. The nonce and authentication tag of each page are
+//! stored in the reserved bytes at the end of the page, which is why the database must be created
+//! with at least [RESERVED_BYTES] reserved bytes per page (e.g. by calling
+//! `sqlite3_file_control(db, "main", SQLITE_FCNTL_RESERVE_BYTES, &n)` before creating the first
+//! table). Journals, WALs and temporary files don't have reserved bytes. They are split into blocks
+//! of the page size instead, each followed by its nonce and tag. As writes to them re-encrypt whole
+//! blocks, encrypted files report blocks as their sectors and don't claim "powersafe-overwrite"
+//! (which SQLite takes from the main database for its journal and WAL), so that SQLite doesn't
+//! append to a block that holds already synced data (e.g. WAL frames).
+//!
+//! All blocks within a file are authenticated, including those the file got extended with, so
+//! that zeroing a block cannot be used to bypass the authentication.
+//!
+//! The 256 bit key is provided as 64 hex characters either through the `key` URI parameter
+//! (`file:app.db?key=...`) or via `PRAGMA key = '...'`. The key only applies to the connection it
+//! got provided to: it is kept by the connection's main database handle and shared with the
+//! connection's own journal and WAL, but not with other connections to the same database. Temporary
+//! files are encrypted with a random key that only lives as long as the file is open.
+//! Super-journals (which only contain file names) are not encrypted.
+//!
+//! Each block is authenticated together with its index and the kind of file it belongs to, so that
+//! blocks can neither be moved within a file nor between e.g. a WAL and its database.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The number of bytes that must be reserved at the end of each database page.
+pub const RESERVED_BYTES: usize = NONCE_LEN + TAG_LEN;
+
+type KeySlot = Mutex<Option<ChaCha20Poly1305>>;
+
+/// A [Vfs] that encrypts all files of the wrapped [Vfs].
+pub struct EncryptedVfs<V> {
+    vfs: V,
+    page_size: usize,
+    /// The keys of all open main database handles (by [OpenOptions::database_id]), shared with the
+    /// journals and WALs of their connection.
+    keys: Mutex<HashMap<usize, Weak<KeySlot>>>,
+}
+
+/// A [DatabaseHandle] opened by [EncryptedVfs].
+pub struct EncryptedHandle<H> {
+    handle: H,
+    /// `None` for files that are not encrypted.
+    layout: Option<Layout>,
+    key: Arc<KeySlot>,
+}
+
+/// Describes how the logical (plaintext) file is split into blocks and stored.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// The logical size of each block.
+    block_size: usize,
+    /// Whether nonce and tag are stored in the reserved bytes at the end of each block (database
+    /// pages), or appended to each block.
+    reserved: bool,
+    /// The kind of the file, authenticated alongside each block.
+    kind: OpenKind,
+}
+
+impl<V> EncryptedVfs<V> {
+    /// Wrap `vfs` and encrypt all databases with a page size of 4096 bytes.
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs,
+            page_size: 4096,
+            keys: Default::default(),
+        }
+    }
+
+    /// Set the page size of the databases. The page size cannot be derived from the encrypted
+    /// database header, so it must match the `page_size` of the database.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// The key slot of the main database handle `database_id`. Main databases always get a new
+    /// slot; journals and WALs share the one of their main database handle.
+    fn key_slot(&self, kind: OpenKind, database_id: Option<usize>) -> Arc<KeySlot> {
+        let database_id = match database_id {
+            Some(id) => id,
+            None => return Arc::new(Mutex::new(None)),
+        };
+
+        let mut keys = self.keys.lock().unwrap();
+        if kind != OpenKind::MainDb {
+            if let Some(slot) = keys.get(&database_id).and_then(Weak::upgrade) {
+                return slot;
+            }
+        }
+
+        keys.retain(|_, slot| slot.strong_count() > 0);
+        let slot = Arc::new(Mutex::new(None));
+        keys.insert(database_id, Arc::downgrade(&slot));
+        slot
+    }
+}
+
+impl<V: Vfs> Vfs for EncryptedVfs<V> {
+    type Handle = EncryptedHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let (key, layout) = match opts.kind {
+            OpenKind::MainDb | OpenKind::MainJournal | OpenKind::Wal => {
+                let key = self.key_slot(opts.kind, opts.database_id);
+                if let Some(hex) = opts.uri_parameter("key") {
+                    let mut key = key.lock().unwrap();
+                    // A key set via `PRAGMA key` takes precedence over the one of the URI.
+                    if opts.kind == OpenKind::MainDb || key.is_none() {
+                        *key = Some(parse_key(hex)?);
+                    }
+                }
+
+                let layout = Layout {
+                    block_size: self.page_size,
+                    reserved: opts.kind == OpenKind::MainDb,
+                    kind: opts.kind,
+                };
+                (key, Some(layout))
+            }
+            OpenKind::TempDb
+            | OpenKind::TempJournal
+            | OpenKind::TransientDb
+            | OpenKind::SubJournal => {
+                let key = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+                let layout = Layout {
+                    block_size: self.page_size,
+                    reserved: false,
+                    kind: opts.kind,
+                };
+                (Arc::new(Mutex::new(Some(key))), Some(layout))
+            }
+            OpenKind::SuperJournal => (Arc::new(Mutex::new(None)), None),
+        };
+
+        Ok(EncryptedHandle {
+            handle: self.vfs.open(db, opts)?,
+            layout,
+            key,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl Layout {
+    /// The size of each block in the underlying file.
+    fn physical_block_size(&self) -> u64 {
+        if self.reserved {
+            self.block_size as u64
+        } else {
+            (self.block_size + RESERVED_BYTES) as u64
+        }
+    }
+
+    /// The associated data authenticated with the block at `index`.
+    fn associated_data(&self, index: u64) -> [u8; 9] {
+        let mut data = [0; 9];
+        data[..8].copy_from_slice(&index.to_le_bytes());
+        data[8] = match self.kind {
+            OpenKind::MainDb => 0,
+            OpenKind::MainJournal => 1,
+            OpenKind::TempDb => 2,
+            OpenKind::TempJournal => 3,
+            OpenKind::TransientDb => 4,
+            OpenKind::SubJournal => 5,
+            OpenKind::SuperJournal => 6,
+            OpenKind::Wal => 7,
+        };
+        data
+    }
+
+    /// Translate the size of the underlying file to the size of the plaintext file.
+    fn logical_size(&self, physical_size: u64) -> u64 {
+        if self.reserved {
+            return physical_size;
+        }
+
+        let physical_block_size = self.physical_block_size();
+        let rem = physical_size % physical_block_size;
+        (physical_size / physical_block_size) * self.block_size as u64
+            + rem.saturating_sub(RESERVED_BYTES as u64)
+    }
+
+    /// Translate the size of the plaintext file to the size of the underlying file.
+    fn physical_size(&self, logical_size: u64) -> u64 {
+        if self.reserved {
+            return logical_size;
+        }
+
+        let rem = logical_size % self.block_size as u64;
+        (logical_size / self.block_size as u64) * self.physical_block_size()
+            + if rem > 0 {
+                rem + RESERVED_BYTES as u64
+            } else {
+                0
+            }
+    }
+}
+
+impl<H: DatabaseHandle> EncryptedHandle<H> {
+    fn cipher(&self) -> Result<ChaCha20Poly1305, std::io::Error> {
+        self.key.lock().unwrap().clone().ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "no encryption key provided (use the `key` URI parameter or `PRAGMA key`)",
+            )
+        })
+    }
+
+    /// Read and decrypt the block at `index`. Returns less than a full block for the last block of
+    /// the file, and nothing for blocks past the end of the file.
+    fn load(
+        &mut self,
+        layout: Layout,
+        cipher: &ChaCha20Poly1305,
+        index: u64,
+        physical_size: u64,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let start = index * layout.physical_block_size();
+        if start >= physical_size {
+            return Ok(Vec::new());
+        }
+
+        let len = (physical_size - start).min(layout.physical_block_size()) as usize;
+        if len < RESERVED_BYTES {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("encrypted block {} is truncated", index),
+            ));
+        }
+
+        let mut block = vec![0; len];
+        self.handle.read_exact_at(&mut block, start)?;
+
+        let (payload, tail) = block.split_at_mut(len - RESERVED_BYTES);
+        let (nonce, tag) = tail.split_at(NONCE_LEN);
+        let tag = *Tag::from_slice(tag);
+        cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &layout.associated_data(index),
+                payload,
+                &tag,
+            )
+            .map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("failed to decrypt block {} (wrong key or corrupted)", index),
+                )
+            })?;
+
+        if layout.reserved {
+            // The reserved bytes are not part of the plaintext.
+            tail.fill(0);
+        } else {
+            block.truncate(len - RESERVED_BYTES);
+        }
+
+        Ok(block)
+    }
+
+    /// Encrypt and write the logical block `plaintext` at `index`.
+    fn store(
+        &mut self,
+        layout: Layout,
+        cipher: &ChaCha20Poly1305,
+        index: u64,
+        plaintext: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let payload_len = if layout.reserved {
+            plaintext.len() - RESERVED_BYTES
+        } else {
+            plaintext.len()
+        };
+
+        let mut block = Vec::with_capacity(payload_len + RESERVED_BYTES);
+        block.extend_from_slice(&plaintext[..payload_len]);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, &layout.associated_data(index), &mut block)
+            .map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("failed to encrypt block {}", index),
+                )
+            })?;
+        block.extend_from_slice(&nonce);
+        block.extend_from_slice(&tag);
+
+        self.handle
+            .write_all_at(&block, index * layout.physical_block_size())
+    }
+
+    /// Grow the plaintext file from `from` to `to` bytes, filling it with encrypted zeros (database
+    /// files are grown by whole pages).
+    fn extend(
+        &mut self,
+        layout: Layout,
+        cipher: &ChaCha20Poly1305,
+        from: u64,
+        to: u64,
+    ) -> Result<(), std::io::Error> {
+        let block_size = layout.block_size as u64;
+        let mut pos = from;
+        while pos < to {
+            let index = pos / block_size;
+            let mut block = if !pos.is_multiple_of(block_size) {
+                // The last block is not complete. Its tag is stored right after it, so it has to
+                // be re-encrypted at its new size.
+                self.load(layout, cipher, index, layout.physical_size(from))?
+            } else {
+                Vec::new()
+            };
+            let len = if layout.reserved {
+                block_size
+            } else {
+                (to - index * block_size).min(block_size)
+            };
+            block.resize(len as usize, 0);
+            self.store(layout, cipher, index, &block)?;
+            pos = (index + 1) * block_size;
+        }
+        Ok(())
+    }
+
+    /// Ensure that page 1 of a database is compatible with the encryption.
+    fn check_header(&self, page: &[u8]) -> Result<(), std::io::Error> {
+        if !page.starts_with(b"SQLite format 3\0") {
+            return Ok(());
+        }
+
+        let page_size = match u16::from_be_bytes([page[16], page[17]]) {
+            1 => 65536,
+            n => n as usize,
+        };
+        if page_size != page.len() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "database page size {} does not match configured page size {}",
+                    page_size,
+                    page.len()
+                ),
+            ));
+        }
+
+        if (page[20] as usize) < RESERVED_BYTES {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "database must reserve at least {} bytes per page for encryption (got {})",
+                    RESERVED_BYTES, page[20]
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for EncryptedHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        let size = self.handle.size()?;
+        Ok(match self.layout {
+            Some(layout) => layout.logical_size(size),
+            None => size,
+        })
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let layout = match self.layout {
+            Some(layout) => layout,
+            None => return self.handle.read_exact_at(buf, offset),
+        };
+
+        let cipher = match self.cipher() {
+            Ok(cipher) => cipher,
+            // SQLite reads the database header while opening the database, which happens before a
+            // key could be provided via `PRAGMA key`. Pretend the database to be empty until then.
+            Err(_) if layout.reserved => {
+                buf.fill(0);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let physical_size = self.handle.size()?;
+        let end = (offset + buf.len() as u64).min(layout.logical_size(physical_size));
+        let block_size = layout.block_size as u64;
+        let mut pos = offset;
+        while pos < end {
+            let block = self.load(layout, &cipher, pos / block_size, physical_size)?;
+            let start = (pos % block_size) as usize;
+            let n = ((end - pos) as usize).min(block.len().saturating_sub(start));
+            if n == 0 {
+                break;
+            }
+            let at = (pos - offset) as usize;
+            buf[at..at + n].copy_from_slice(&block[start..start + n]);
+            pos += n as u64;
+        }
+
+        if pos < offset + buf.len() as u64 {
+            buf[(pos - offset) as usize..].fill(0);
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let layout = match self.layout {
+            Some(layout) => layout,
+            None => return self.handle.write_all_at(buf, offset),
+        };
+        let cipher = self.cipher()?;
+
+        let size = self.size()?;
+        if offset > size {
+            self.extend(layout, &cipher, size, offset)?;
+        }
+
+        let block_size = layout.block_size as u64;
+        let mut physical_size = self.handle.size()?;
+        let mut pos = offset;
+        let end = offset + buf.len() as u64;
+        while pos < end {
+            let index = pos / block_size;
+            let start = (pos % block_size) as usize;
+            let n = ((end - pos) as usize).min(layout.block_size - start);
+            let data = &buf[(pos - offset) as usize..(pos - offset) as usize + n];
+
+            let mut block = if start == 0 && n == layout.block_size {
+                data.to_vec()
+            } else {
+                let mut block = self.load(layout, &cipher, index, physical_size)?;
+                let len = if layout.reserved {
+                    layout.block_size
+                } else {
+                    block.len().max(start + n)
+                };
+                block.resize(len, 0);
+                block[start..start + n].copy_from_slice(data);
+                block
+            };
+
+            if layout.reserved {
+                if index == 0 {
+                    self.check_header(&block)?;
+                }
+                // Don't let any plaintext end up in the reserved bytes.
+                let len = block.len();
+                block[len - RESERVED_BYTES..].fill(0);
+            }
+
+            self.store(layout, &cipher, index, &block)?;
+            physical_size = physical_size.max(
+                index * layout.physical_block_size() + layout.physical_size(block.len() as u64),
+            );
+            pos += n as u64;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let layout = match self.layout {
+            Some(layout) => layout,
+            None => return self.handle.set_len(size),
+        };
+
+        let current = self.size()?;
+        if size > current {
+            let cipher = self.cipher()?;
+            return self.extend(layout, &cipher, current, size);
+        }
+        if layout.reserved {
+            return self.handle.set_len(size);
+        }
+
+        let block_size = layout.block_size as u64;
+        let (index, rem) = (size / block_size, size % block_size);
+        if rem > 0 && size < current {
+            // Re-encrypt the new last block, as its tag is stored right after it.
+            let cipher = self.cipher()?;
+            let physical_size = self.handle.size()?;
+            let mut block = self.load(layout, &cipher, index, physical_size)?;
+            block.truncate(rem as usize);
+            self.store(layout, &cipher, index, &block)?;
+        }
+
+        self.handle.set_len(layout.physical_size(size))
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        if !name.eq_ignore_ascii_case("key") {
+            return self.handle.pragma(name, value);
+        }
+
+        // Never reveal the key; querying it is a no-op.
+        let value = value?;
+        Some(parse_key(value).map(|key| {
+            *self.key.lock().unwrap() = Some(key);
+            None
+        }))
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        // SQLite decides how to write journals and WALs by the main database file
+        self.layout.is_none() && self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        match self.layout {
+            Some(layout) => layout.block_size.max(self.handle.sector_size()),
+            None => self.handle.sector_size(),
+        }
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+/// Parse a 256 bit key from 64 hex characters.
+fn parse_key(hex: &str) -> Result<ChaCha20Poly1305, std::io::Error> {
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "encryption key must consist of 64 hex characters",
+        )
+    };
+
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        let pair = std::str::from_utf8(&hex[i * 2..i * 2 + 2]).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryVfs;
+    use crate::OpenAccess;
+
+    #[test]
+    fn test_layout_sizes() {
+        let layout = Layout {
+            block_size: 4096,
+            reserved: false,
+            kind: OpenKind::Wal,
+        };
+        for size in [0, 1, 4095, 4096, 4097, 3 * 4096 + 12] {
+            assert_eq!(layout.logical_size(layout.physical_size(size)), size);
+        }
+        assert_eq!(layout.physical_size(4096 + 1), 4096 + 28 + 1 + 28);
+
+        let layout = Layout {
+            block_size: 4096,
+            reserved: true,
+            kind: OpenKind::MainDb,
+        };
+        assert_eq!(layout.physical_size(8192), 8192);
+        assert_eq!(layout.logical_size(8192), 8192);
+    }
+
+    #[test]
+    fn test_blocks_are_bound_to_their_file_kind() {
+        let mem = MemoryVfs::default();
+        let vfs = EncryptedVfs::new(mem.clone());
+        let key = "ab".repeat(32);
+        let opts =
+            |kind| OpenOptions::new(kind, OpenAccess::Create).with_uri_parameter("key", &key);
+
+        let mut wal = vfs.open("main.db-wal", opts(OpenKind::Wal)).unwrap();
+        wal.write_all_at(&[1; 4096], 0).unwrap();
+
+        // copy the encrypted block into a journal
+        let mut src = mem.open("main.db-wal", opts(OpenKind::Wal)).unwrap();
+        let mut raw = vec![0; src.size().unwrap() as usize];
+        src.read_exact_at(&mut raw, 0).unwrap();
+        let mut dst = mem
+            .open("main.db-journal", opts(OpenKind::MainJournal))
+            .unwrap();
+        dst.write_all_at(&raw, 0).unwrap();
+
+        let mut buf = [0; 4096];
+        wal.read_exact_at(&mut buf, 0).unwrap();
+        let mut journal = vfs
+            .open("main.db-journal", opts(OpenKind::MainJournal))
+            .unwrap();
+        let err = journal.read_exact_at(&mut buf, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key(&"ab".repeat(32)).is_ok());
+        assert!(parse_key(&"ab".repeat(31)).is_err());
+        assert!(parse_key(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Fault injection for any [Vfs], to test how an application handles I/O errors.
+//!
+//! Faults are described by scripted [Rule]s, e.g.:
+//!
+//! ```ignore
+//! let vfs = FaultVfs::new(vfs)
+//!     // fail the 3rd write to the main journal with an I/O error
+//!     .with_rule(Rule::on(Op::Write).kind(OpenKind::MainJournal).nth(3).fail(Fault::Io))
+//!     // the disk of main databases is full after 1MiB
+//!     .with_rule(Rule::disk_full_after(1024 * 1024).kind(OpenKind::MainDb))
+//!     // the first two locks return `SQLITE_BUSY`
+//!     .with_rule(Rule::on(Op::Lock).times(2).fail(Fault::Busy));
+//! ```
+//!
+//! Rules can also be added after the VFS got registered through the [Faults] returned by
+//! [FaultVfs::faults].
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+/// A [Vfs] that injects faults into the calls to the wrapped [Vfs].
+pub struct FaultVfs<V> {
+    vfs: V,
+    faults: Faults,
+}
+
+/// A [DatabaseHandle] opened by [FaultVfs].
+pub struct FaultHandle<H> {
+    handle: H,
+    kind: OpenKind,
+    faults: Faults,
+}
+
+/// The rules of a [FaultVfs]. Cheap to clone, all clones share the same rules.
+#[derive(Clone, Default)]
+pub struct Faults {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    rules: Vec<Rule>,
+    injected: usize,
+}
+
+/// An operation faults can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Open,
+    Delete,
+    Exists,
+    Access,
+    Size,
+    Read,
+    Write,
+    Sync,
+    SetLen,
+    Lock,
+    Unlock,
+    Reserved,
+}
+
+/// The fault to inject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with a generic I/O error (`SQLITE_IOERR_*`, the equivalent of `EIO`).
+    Io,
+    /// Fail with an error of the given kind.
+    Error(ErrorKind),
+    /// Fail because the disk is full (`SQLITE_FULL`).
+    DiskFull,
+    /// Report the lock as held by someone else (`SQLITE_BUSY` for [Op::Lock], reserved for
+    /// [Op::Reserved]). Fails with [ErrorKind::WouldBlock] for other operations.
+    Busy,
+}
+
+/// A scripted fault.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    op: Op,
+    kind: Option<OpenKind>,
+    /// The number of matching calls to let through before injecting the fault.
+    skip: usize,
+    /// How often to inject the fault (`None` for forever).
+    times: Option<usize>,
+    fault: Fault,
+    /// Only inject once more than this amount of bytes got written.
+    budget: Option<u64>,
+    /// The number of matching calls so far.
+    seen: usize,
+    /// The number of times the fault got injected.
+    injected: usize,
+    written: u64,
+}
+
+impl<V> FaultVfs<V> {
+    /// Wrap `vfs` (without any rules).
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs,
+            faults: Faults::default(),
+        }
+    }
+
+    /// Add the `rule`.
+    pub fn with_rule(self, rule: Rule) -> Self {
+        self.faults.add(rule);
+        self
+    }
+
+    /// The rules of this VFS. Retrieve them before registering the VFS to change the rules later.
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+}
+
+impl Faults {
+    /// Add the `rule`.
+    pub fn add(&self, rule: Rule) {
+        self.state.lock().unwrap().rules.push(rule);
+    }
+
+    /// Remove all rules.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// The number of faults injected so far.
+    pub fn injected(&self) -> usize {
+        self.state.lock().unwrap().injected
+    }
+
+    /// Check whether a fault should be injected for a call to `op` (transferring `len` bytes) on a
+    /// file of the given `kind`.
+    fn check(&self, op: Op, kind: Option<OpenKind>, len: usize) -> Option<Fault> {
+        let mut state = self.state.lock().unwrap();
+        // Every matching rule sees the call (even if an earlier one already fired), so that their
+        // counters stay accurate.
+        let mut fault = None;
+        for rule in &mut state.rules {
+            if rule.op == op && (rule.kind.is_none() || rule.kind == kind) {
+                if let Some(f) = rule.check(len) {
+                    fault.get_or_insert(f);
+                }
+            }
+        }
+        let fault = fault?;
+        state.injected += 1;
+        Some(fault)
+    }
+
+    fn inject(&self, op: Op, kind: Option<OpenKind>, len: usize) -> Result<(), std::io::Error> {
+        match self.check(op, kind, len) {
+            Some(fault) => Err(fault.into_error()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Rule {
+    /// Inject a [Fault::Io] into the first call to `op` (customize with the other methods).
+    pub fn on(op: Op) -> Self {
+        Self {
+            op,
+            kind: None,
+            skip: 0,
+            times: Some(1),
+            fault: Fault::Io,
+            budget: None,
+            seen: 0,
+            injected: 0,
+            written: 0,
+        }
+    }
+
+    /// Fail all writes with [Fault::DiskFull] once more than `bytes` got written.
+    pub fn disk_full_after(bytes: u64) -> Self {
+        Self {
+            budget: Some(bytes),
+            times: None,
+            fault: Fault::DiskFull,
+            ..Self::on(Op::Write)
+        }
+    }
+
+    /// Only apply to files of the given `kind`. Operations that aren't bound to an open file (like
+    /// [Op::Delete]) never match rules with a kind.
+    pub fn kind(mut self, kind: OpenKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Inject the fault into the `n`th matching call (starting at `1`).
+    pub fn nth(mut self, n: usize) -> Self {
+        self.skip = n.saturating_sub(1);
+        self
+    }
+
+    /// Inject the fault `n` times in a row.
+    pub fn times(mut self, n: usize) -> Self {
+        self.times = Some(n);
+        self
+    }
+
+    /// Inject the fault into all matching calls.
+    pub fn always(mut self) -> Self {
+        self.times = None;
+        self
+    }
+
+    /// The fault to inject.
+    pub fn fail(mut self, fault: Fault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    fn check(&mut self, len: usize) -> Option<Fault> {
+        self.seen += 1;
+        if self.seen <= self.skip || self.times.is_some_and(|times| self.injected >= times) {
+            return None;
+        }
+
+        if let Some(budget) = self.budget {
+            if self.written + len as u64 <= budget {
+                self.written += len as u64;
+                return None;
+            }
+        }
+
+        self.injected += 1;
+        Some(self.fault)
+    }
+}
+
+impl Fault {
+    fn into_error(self) -> std::io::Error {
+        match self {
+            Fault::Io => std::io::Error::other("injected I/O error"),
+            Fault::Error(kind) => std::io::Error::new(kind, "injected error"),
+            Fault::DiskFull => std::io::Error::new(ErrorKind::WriteZero, "injected disk full"),
+            Fault::Busy => std::io::Error::new(ErrorKind::WouldBlock, "injected busy"),
+        }
+    }
+}
+
+impl<V: Vfs> Vfs for FaultVfs<V> {
+    type Handle = FaultHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let kind = opts.kind;
+        self.faults.inject(Op::Open, Some(kind), 0)?;
+        Ok(FaultHandle {
+            handle: self.vfs.open(db, opts)?,
+            kind,
+            faults: self.faults.clone(),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.faults.inject(Op::Delete, None, 0)?;
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.faults.inject(Op::Exists, None, 0)?;
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.faults.inject(Op::Access, None, 0)?;
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<H> FaultHandle<H> {
+    fn inject(&self, op: Op, len: usize) -> Result<(), std::io::Error> {
+        self.faults.inject(op, Some(self.kind), len)
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for FaultHandle<H> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.inject(Op::Size, 0)?;
+        self.handle.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.inject(Op::Read, buf.len())?;
+        self.handle.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.inject(Op::Write, buf.len())?;
+        self.handle.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.inject(Op::Sync, 0)?;
+        self.handle.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.inject(Op::SetLen, 0)?;
+        self.handle.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        match self.faults.check(Op::Lock, Some(self.kind), 0) {
+            Some(Fault::Busy) => Ok(false),
+            Some(fault) => Err(fault.into_error()),
+            None => self.handle.lock(lock),
+        }
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.inject(Op::Unlock, 0)?;
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        match self.faults.check(Op::Reserved, Some(self.kind), 0) {
+            Some(Fault::Busy) => Ok(true),
+            Some(fault) => Err(fault.into_error()),
+            None => self.handle.reserved(),
+        }
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_triggers() {
+        let mut rule = Rule::on(Op::Write).nth(2).times(2);
+        let fired: Vec<_> = (0..5).map(|_| rule.check(1).is_some()).collect();
+        assert_eq!(fired, [false, true, true, false, false]);
+
+        let mut rule = Rule::disk_full_after(10);
+        assert_eq!(rule.check(6), None);
+        assert_eq!(rule.check(6), Some(Fault::DiskFull));
+        assert_eq!(rule.check(4), None);
+        assert_eq!(rule.check(1), Some(Fault::DiskFull));
+    }
+
+    #[test]
+    fn test_all_matching_rules_see_each_call() {
+        let faults = Faults::default();
+        faults.add(Rule::on(Op::Write).fail(Fault::Io));
+        faults.add(Rule::on(Op::Write).nth(2).fail(Fault::DiskFull));
+        faults.add(Rule::disk_full_after(10));
+
+        assert_eq!(faults.check(Op::Write, None, 8), Some(Fault::Io));
+        assert_eq!(faults.check(Op::Write, None, 1), Some(Fault::DiskFull));
+        assert_eq!(faults.check(Op::Write, None, 2), Some(Fault::DiskFull));
+        assert_eq!(faults.injected(), 3);
+    }
+}
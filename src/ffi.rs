@@ -585,6 +585,21 @@ pub struct sqlite3_io_methods {
     >,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sqlite3 {
+    _unused: [u8; 0],
+}
+
+pub type sqlite3_callback = ::std::option::Option<
+    unsafe extern "C" fn(
+        arg1: *mut ::std::os::raw::c_void,
+        arg2: ::std::os::raw::c_int,
+        arg3: *mut *mut ::std::os::raw::c_char,
+        arg4: *mut *mut ::std::os::raw::c_char,
+    ) -> ::std::os::raw::c_int,
+>;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct sqlite3_file {
@@ -731,4 +746,46 @@ extern "C" {
         z_param: *const ::std::os::raw::c_char,
         b_dflt: i32,
     ) -> i32;
+
+    pub fn sqlite3_uri_parameter(
+        z_filename: *const ::std::os::raw::c_char,
+        z_param: *const ::std::os::raw::c_char,
+    ) -> *const ::std::os::raw::c_char;
+
+    pub fn sqlite3_uri_key(
+        z_filename: *const ::std::os::raw::c_char,
+        n: ::std::os::raw::c_int,
+    ) -> *const ::std::os::raw::c_char;
+
+    pub fn sqlite3_filename_database(
+        z_filename: *const ::std::os::raw::c_char,
+    ) -> *const ::std::os::raw::c_char;
+
+    pub fn sqlite3_database_file_object(
+        z_filename: *const ::std::os::raw::c_char,
+    ) -> *mut sqlite3_file;
+
+    pub fn sqlite3_mprintf(arg1: *const ::std::os::raw::c_char, ...)
+        -> *mut ::std::os::raw::c_char;
+
+    pub fn sqlite3_free(arg1: *mut ::std::os::raw::c_void);
+
+    pub fn sqlite3_open_v2(
+        filename: *const ::std::os::raw::c_char,
+        pp_db: *mut *mut sqlite3,
+        flags: ::std::os::raw::c_int,
+        z_vfs: *const ::std::os::raw::c_char,
+    ) -> ::std::os::raw::c_int;
+
+    pub fn sqlite3_close(arg1: *mut sqlite3) -> ::std::os::raw::c_int;
+
+    pub fn sqlite3_exec(
+        arg1: *mut sqlite3,
+        sql: *const ::std::os::raw::c_char,
+        callback: sqlite3_callback,
+        arg2: *mut ::std::os::raw::c_void,
+        errmsg: *mut *mut ::std::os::raw::c_char,
+    ) -> ::std::os::raw::c_int;
+
+    pub fn sqlite3_errmsg(arg1: *mut sqlite3) -> *const ::std::os::raw::c_char;
 }
@@ -0,0 +1,936 @@
+//! A [Vfs] adapter that keeps the history of the pages of each database, so that a database can be
+//! opened as it was at a past point in time (e.g. to audit what it looked like at 14:00 yesterday).
+//!
+//! ```ignore
+//! register("history", HistoryVfs::new(vfs).with_retention(Duration::from_secs(7 * 86400)), false)?;
+//! let conn = Connection::open_with_flags_and_vfs(
+//!     "file:main.db?asof=2026-10-17T14:00:00Z",
+//!     OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+//!     "history",
+//! )?;
+//! ```
+//!
+//! Before a range of the database gets overwritten (or truncated) for the first time in a
+//! transaction, its previous content is appended to the history. A transaction is recorded as
+//! committed at the current time once the write lock got released (down to [LockKind::Shared] or
+//! [LockKind::None]). A transaction that got rolled back is recorded as well; as the database ends
+//! up with its previous content, this doesn't change any snapshot.
+//!
+//! The URI parameter `asof` (an RFC 3339 timestamp, or possibly fractional seconds since the Unix
+//! epoch) opens a read-only snapshot of the database: each range is read from the history of the
+//! first transaction committed after that time, and from the database if it didn't change since.
+//!
+//! The history of `{db}` is kept in the inner [Vfs], next to the database:
+//! - `{db}-history`: when the history got started, up to when it got garbage collected, and the
+//!   range of its segments,
+//! - `{db}-history-{n}`: segments of previous page contents and commit records; a new segment is
+//!   started once the current one exceeds the segment size.
+//!
+//! With a retention ([HistoryVfs::with_retention]), segments that only contain transactions older
+//! than the retention are deleted after commits. Snapshots from before the history got started or
+//! from its deleted part cannot be opened.
+//!
+//! Only rollback journal modes are supported, as the database is only written when checkpointing in
+//! WAL mode (and a snapshot would read pages of the WAL that are newer than the snapshot). Databases
+//! in WAL mode (according to their header, or with a `-wal` file) cannot be opened, and switching
+//! to WAL mode has no effect (the update of the header is refused). Previous page contents are synced before the database (or, with `PRAGMA synchronous =
+//! OFF`, where the database never gets synced, once the transaction ends), commit records aren't:
+//! if a commit record gets lost, its pages are attributed to the next transaction.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+/// The size of the `-history` file.
+const INDEX_SIZE: usize = 32;
+
+/// The size of the header of each record in a segment.
+const RECORD_HEADER_SIZE: usize = 17;
+
+const PAGE_RECORD: u8 = 1;
+const COMMIT_RECORD: u8 = 2;
+
+/// A [Vfs] adapter that keeps the previous contents of the pages of each committed transaction.
+pub struct HistoryVfs<V> {
+    vfs: Arc<V>,
+    retention: Option<Duration>,
+    segment_size: u64,
+}
+
+/// A [DatabaseHandle] opened by [HistoryVfs].
+pub struct HistoryHandle<V: Vfs> {
+    file: V::Handle,
+    /// Only main databases have a history.
+    history: Option<History<V>>,
+    /// The transaction in progress of a writable database.
+    txn: Option<Txn>,
+    /// Set if the database was opened as of a past time.
+    snapshot: Option<Snapshot>,
+}
+
+/// The content of the `-history` file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Index {
+    /// When the history got started (in milliseconds since the Unix epoch).
+    created: i64,
+    /// The time of the latest transaction that got garbage collected.
+    collected: i64,
+    /// The first and last segment.
+    first: u64,
+    last: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    /// The previous content of `len` bytes at `offset`, followed by the data.
+    Page { offset: u64, len: u64 },
+    /// The commit of a transaction at `time`, which started with a database of `size` bytes.
+    Commit { time: i64, size: u64 },
+}
+
+struct History<V: Vfs> {
+    vfs: Arc<V>,
+    db: String,
+    access: OpenAccess,
+    retention: Option<Duration>,
+    segment_size: u64,
+    index_file: V::Handle,
+    index: Index,
+    segments: BTreeMap<u64, Segment<V::Handle>>,
+    /// The next segment to read records from.
+    next: u64,
+    /// The time of the latest commit read or written.
+    latest: i64,
+}
+
+struct Segment<H> {
+    file: H,
+    /// The end of the last complete record read or written.
+    len: u64,
+    /// The time of the latest commit in this segment.
+    newest: Option<i64>,
+}
+
+struct Txn {
+    /// The size of the database when the transaction started.
+    size: u64,
+    /// The ranges whose previous content got added to the history already.
+    captured: Ranges<()>,
+    /// Whether the previous contents got synced already.
+    synced: bool,
+}
+
+struct Snapshot {
+    asof: i64,
+    /// The ranges that changed since, and where their content is found in the history.
+    pages: Ranges<Location>,
+    /// The size of the database, if it changed since.
+    size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Location {
+    segment: u64,
+    /// The position of the data of the record in the segment.
+    at: u64,
+    /// The database offset of the record.
+    offset: u64,
+}
+
+/// Non-overlapping byte ranges, each with a value.
+#[derive(Debug)]
+struct Ranges<T> {
+    ranges: BTreeMap<u64, (u64, T)>,
+}
+
+impl<V> HistoryVfs<V> {
+    /// Keep the history of the databases of `vfs`, forever and in segments of 64 MiB.
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs: Arc::new(vfs),
+            retention: None,
+            segment_size: 64 * 1024 * 1024,
+        }
+    }
+
+    /// Delete the history of transactions older than `retention`.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Start a new segment of the history once the current one exceeds `segment_size` bytes. As
+    /// only whole segments are deleted, smaller segments follow the retention more closely.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        assert!(segment_size > 0, "segment size must not be zero");
+        self.segment_size = segment_size;
+        self
+    }
+}
+
+impl Index {
+    fn decode(data: &[u8]) -> Self {
+        let i64_at = |i: usize| i64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+        Index {
+            created: i64_at(0),
+            collected: i64_at(8),
+            first: i64_at(16) as u64,
+            last: i64_at(24) as u64,
+        }
+    }
+
+    fn encode(&self) -> [u8; INDEX_SIZE] {
+        let mut data = [0; INDEX_SIZE];
+        data[..8].copy_from_slice(&self.created.to_be_bytes());
+        data[8..16].copy_from_slice(&self.collected.to_be_bytes());
+        data[16..24].copy_from_slice(&self.first.to_be_bytes());
+        data[24..].copy_from_slice(&self.last.to_be_bytes());
+        data
+    }
+}
+
+impl Record {
+    fn decode(data: &[u8]) -> Result<Self, std::io::Error> {
+        let a = u64::from_be_bytes(data[1..9].try_into().unwrap());
+        let b = u64::from_be_bytes(data[9..17].try_into().unwrap());
+        match data[0] {
+            PAGE_RECORD => Ok(Record::Page { offset: a, len: b }),
+            COMMIT_RECORD => Ok(Record::Commit {
+                time: a as i64,
+                size: b,
+            }),
+            tag => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid history record {}", tag),
+            )),
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_HEADER_SIZE] {
+        let (tag, a, b) = match *self {
+            Record::Page { offset, len } => (PAGE_RECORD, offset, len),
+            Record::Commit { time, size } => (COMMIT_RECORD, time as u64, size),
+        };
+        let mut data = [0; RECORD_HEADER_SIZE];
+        data[0] = tag;
+        data[1..9].copy_from_slice(&a.to_be_bytes());
+        data[9..].copy_from_slice(&b.to_be_bytes());
+        data
+    }
+
+    /// The size of the data following the header.
+    fn data_len(&self) -> u64 {
+        match self {
+            Record::Page { len, .. } => *len,
+            Record::Commit { .. } => 0,
+        }
+    }
+}
+
+impl<T: Clone> Ranges<T> {
+    fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    /// The ranges overlapping `start..end`, clipped to it.
+    fn overlapping(&self, start: u64, end: u64) -> Vec<(u64, u64, T)> {
+        // a range starting before `start` might reach into it
+        let from = match self.ranges.range(..=start).next_back() {
+            Some((s, _)) => *s,
+            None => start,
+        };
+        self.ranges
+            .range(from..end)
+            .filter(|(_, (e, _))| *e > start)
+            .map(|(s, (e, value))| ((*s).max(start), (*e).min(end), value.clone()))
+            .collect()
+    }
+
+    /// The parts of `start..end` not covered by any range.
+    fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for (s, e, _) in self.overlapping(start, end) {
+            if s > pos {
+                gaps.push((pos, s));
+            }
+            pos = e;
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+        gaps
+    }
+
+    /// Add `value` for the parts of `start..end` that aren't covered yet.
+    fn insert(&mut self, start: u64, end: u64, value: T) {
+        for (s, e) in self.gaps(start, end) {
+            self.ranges.insert(s, (e, value.clone()));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.ranges.clear();
+    }
+}
+
+impl<V: Vfs> History<V> {
+    /// Open the history of `db`. It is started if it doesn't exist yet and `access` allows it.
+    fn open(vfs: &HistoryVfs<V>, db: &str, access: OpenAccess) -> Result<Self, std::io::Error> {
+        let name = format!("{}-history", db);
+        let mut index_file = vfs.vfs.open(&name, open_options(access))?;
+        let index = if index_file.size()? >= INDEX_SIZE as u64 {
+            let mut data = [0; INDEX_SIZE];
+            index_file.read_exact_at(&mut data, 0)?;
+            Index::decode(&data)
+        } else if access == OpenAccess::Read {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} has no history", db),
+            ));
+        } else {
+            let index = Index {
+                created: now(),
+                collected: i64::MIN,
+                first: 0,
+                last: 0,
+            };
+            index_file.write_all_at(&index.encode(), 0)?;
+            index_file.sync(false)?;
+            index
+        };
+
+        Ok(History {
+            vfs: vfs.vfs.clone(),
+            db: db.to_string(),
+            access,
+            retention: vfs.retention,
+            segment_size: vfs.segment_size,
+            index_file,
+            // writers only need to read the last segment
+            next: if access == OpenAccess::Read {
+                index.first
+            } else {
+                index.last
+            },
+            index,
+            segments: BTreeMap::new(),
+            latest: i64::MIN,
+        })
+    }
+
+    fn segment(&mut self, n: u64) -> Result<&mut Segment<V::Handle>, std::io::Error> {
+        if !self.segments.contains_key(&n) {
+            let name = format!("{}-history-{}", self.db, n);
+            let file = self.vfs.open(&name, open_options(self.access))?;
+            self.segments.insert(
+                n,
+                Segment {
+                    file,
+                    len: 0,
+                    newest: None,
+                },
+            );
+        }
+        Ok(self.segments.get_mut(&n).unwrap())
+    }
+
+    fn write_index(&mut self) -> Result<(), std::io::Error> {
+        self.index_file.write_all_at(&self.index.encode(), 0)?;
+        self.index_file.sync(false)
+    }
+
+    /// Read the records of segment `n` that weren't read yet, passing each to `f` together with the
+    /// position of its data.
+    fn read_records(
+        &mut self,
+        n: u64,
+        mut f: impl FnMut(&Record, u64),
+    ) -> Result<(), std::io::Error> {
+        let mut latest = self.latest;
+        let segment = self.segment(n)?;
+        let size = segment.file.size()?;
+        let mut header = [0; RECORD_HEADER_SIZE];
+        while segment.len + RECORD_HEADER_SIZE as u64 <= size {
+            segment.file.read_exact_at(&mut header, segment.len)?;
+            let record = Record::decode(&header)?;
+            let at = segment.len + RECORD_HEADER_SIZE as u64;
+            if at + record.data_len() > size {
+                // incompletely written
+                break;
+            }
+            if let Record::Commit { time, .. } = record {
+                segment.newest = Some(time);
+                latest = latest.max(time);
+            }
+            f(&record, at);
+            segment.len = at + record.data_len();
+        }
+        self.latest = latest;
+        Ok(())
+    }
+
+    /// Read the records added since the last refresh, passing each to `f` together with the
+    /// segment and the position of its data.
+    fn refresh(&mut self, mut f: impl FnMut(&Record, u64, u64)) -> Result<(), std::io::Error> {
+        let mut data = [0; INDEX_SIZE];
+        self.index_file.read_exact_at(&mut data, 0)?;
+        self.index = Index::decode(&data);
+        let first = self.index.first;
+        self.segments.retain(|n, _| *n >= first);
+
+        let mut n = self.next.max(first);
+        while n <= self.index.last {
+            self.read_records(n, |record, at| f(record, n, at))?;
+            self.next = n;
+            n += 1;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: Record, data: &[u8]) -> Result<(), std::io::Error> {
+        let segment = self.segment(self.index.last)?;
+        segment.file.write_all_at(&record.encode(), segment.len)?;
+        segment
+            .file
+            .write_all_at(data, segment.len + RECORD_HEADER_SIZE as u64)?;
+        segment.len += (RECORD_HEADER_SIZE + data.len()) as u64;
+        if let Record::Commit { time, .. } = record {
+            segment.newest = Some(time);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.segment(self.index.last)?.file.sync(true)
+    }
+
+    fn read_exact_at(
+        &mut self,
+        segment: u64,
+        buf: &mut [u8],
+        at: u64,
+    ) -> Result<(), std::io::Error> {
+        if segment < self.index.first {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                "the history of the snapshot got garbage collected",
+            ));
+        }
+        self.segment(segment)?.file.read_exact_at(buf, at)
+    }
+
+    /// Start a new segment if the last one is full, and delete the segments that only contain
+    /// transactions committed before `cutoff`.
+    fn maintain(&mut self, cutoff: Option<i64>) -> Result<(), std::io::Error> {
+        let mut index = self.index;
+        if self.segment(index.last)?.len >= self.segment_size {
+            index.last += 1;
+            // readers expect the segments of the index to exist
+            self.segment(index.last)?;
+        }
+        let mut collected = Vec::new();
+        if let Some(cutoff) = cutoff {
+            while index.first < index.last {
+                let n = index.first;
+                self.read_records(n, |_, _| {})?;
+                match self.segment(n)?.newest {
+                    Some(newest) if newest < cutoff => {
+                        index.collected = index.collected.max(newest);
+                        index.first += 1;
+                        collected.push(n);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        if index == self.index {
+            return Ok(());
+        }
+
+        self.index = index;
+        self.write_index()?;
+        for n in collected {
+            self.segments.remove(&n);
+            self.vfs.delete(&format!("{}-history-{}", self.db, n))?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs + Send> Vfs for HistoryVfs<V> {
+    type Handle = HistoryHandle<V>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(HistoryHandle {
+                file: self.vfs.open(db, opts)?,
+                history: None,
+                txn: None,
+                snapshot: None,
+            });
+        }
+
+        let asof = match opts.uri_parameter("asof") {
+            // SQLite retries to open it read-only
+            Some(_) if opts.access != OpenAccess::Read => {
+                return Err(ErrorKind::PermissionDenied.into())
+            }
+            // later commits must not be part of the snapshot
+            Some(asof) => Some(parse_time(asof)?.min(now() - 1)),
+            None => None,
+        };
+
+        let mut file = self.vfs.open(db, opts.clone())?;
+        if (asof.is_some() || opts.access != OpenAccess::Read)
+            && (self.vfs.exists(&format!("{}-wal", db))? || wal_mode(&mut file)?)
+        {
+            return Err(wal_unsupported());
+        }
+        let history = match asof {
+            Some(_) => Some(History::open(self, db, OpenAccess::Read)?),
+            None if opts.access == OpenAccess::Read => None,
+            None => Some(History::open(self, db, OpenAccess::Create)?),
+        };
+        let snapshot = match (asof, &history) {
+            (Some(asof), Some(history)) => {
+                let start = history.index.created.max(history.index.collected);
+                if asof < start {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "the history of {} only goes back to {}",
+                            db,
+                            format_time(start)
+                        ),
+                    ));
+                }
+                Some(Snapshot {
+                    asof,
+                    pages: Ranges::new(),
+                    size: None,
+                })
+            }
+            _ => None,
+        };
+
+        Ok(HistoryHandle {
+            file,
+            history,
+            txn: None,
+            snapshot,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<V: Vfs> HistoryHandle<V> {
+    /// Add the previous content of `start..end` to the history, unless it got added in this
+    /// transaction already.
+    fn capture(&mut self, start: u64, end: u64) -> Result<(), std::io::Error> {
+        let history = match &mut self.history {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        let txn = match &mut self.txn {
+            Some(txn) => txn,
+            None => {
+                history.refresh(|_, _, _| {})?;
+                // drop an incompletely written record (e.g. of a crashed writer)
+                let segment = history.segment(history.index.last)?;
+                if segment.file.size()? > segment.len {
+                    segment.file.set_len(segment.len)?;
+                }
+                self.txn.insert(Txn {
+                    size: self.file.size()?,
+                    captured: Ranges::new(),
+                    synced: false,
+                })
+            }
+        };
+
+        // ranges beyond the initial size didn't exist before the transaction
+        let end = end.min(txn.size);
+        if start >= end {
+            return Ok(());
+        }
+        for (start, end) in txn.captured.gaps(start, end) {
+            let mut data = vec![0; (end - start) as usize];
+            self.file.read_exact_at(&mut data, start)?;
+            history.append(
+                Record::Page {
+                    offset: start,
+                    len: end - start,
+                },
+                &data,
+            )?;
+            txn.captured.insert(start, end, ());
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, txn: Txn) -> Result<(), std::io::Error> {
+        let history = match &mut self.history {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        // keep the commit times in order, even if the clock goes backwards
+        let time = now().max(history.latest + 1);
+        history.append(
+            Record::Commit {
+                time,
+                size: txn.size,
+            },
+            &[],
+        )?;
+        history.latest = time;
+
+        let cutoff = history
+            .retention
+            .map(|retention| time - retention.as_millis() as i64);
+        history.maintain(cutoff)
+    }
+
+    fn refresh_snapshot(&mut self) -> Result<(), std::io::Error> {
+        let (history, snapshot) = match (&mut self.history, &mut self.snapshot) {
+            (Some(history), Some(snapshot)) => (history, snapshot),
+            _ => return Ok(()),
+        };
+        history.refresh(|record, segment, at| match *record {
+            Record::Page { offset, len } => {
+                let location = Location {
+                    segment,
+                    at,
+                    offset,
+                };
+                snapshot.pages.insert(offset, offset + len, location);
+            }
+            Record::Commit { time, .. } if time <= snapshot.asof => {
+                // the snapshot contains this transaction
+                snapshot.pages.clear();
+                snapshot.size = None;
+            }
+            Record::Commit { size, .. } => {
+                snapshot.size.get_or_insert(size);
+            }
+        })?;
+        if history.index.collected > snapshot.asof {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                "the history of the snapshot got garbage collected",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs + Send> DatabaseHandle for HistoryHandle<V> {
+    type WalIndex = <V::Handle as DatabaseHandle>::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.snapshot {
+            Some(Snapshot {
+                size: Some(size), ..
+            }) => Ok(*size),
+            _ => self.file.size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let (history, snapshot) = match (&mut self.history, &self.snapshot) {
+            (Some(history), Some(snapshot)) => (history, snapshot),
+            _ => return self.file.read_exact_at(buf, offset),
+        };
+
+        let size = match snapshot.size {
+            Some(size) => size,
+            None => self.file.size()?,
+        };
+        let end = offset + buf.len() as u64;
+        let n = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        match self.file.read_exact_at(&mut buf[..n], offset) {
+            Err(err) if err.kind() != ErrorKind::UnexpectedEof => return Err(err),
+            _ => {}
+        }
+
+        // replace the ranges that changed since
+        for (start, stop, location) in snapshot.pages.overlapping(offset, end) {
+            let range = (start - offset) as usize..(stop - offset) as usize;
+            let at = location.at + (start - location.offset);
+            history.read_exact_at(location.segment, &mut buf[range], at)?;
+        }
+        buf[n..].fill(0);
+
+        if n < buf.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        if self.snapshot.is_some() {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        // the file format versions of the header are set to 2 when switching to WAL mode
+        if self.history.is_some() && offset <= 18 && offset + buf.len() as u64 >= 20 {
+            let at = (18 - offset) as usize;
+            if buf[at..at + 2].contains(&2) {
+                return Err(wal_unsupported());
+            }
+        }
+        self.capture(offset, offset + buf.len() as u64)?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        if let (Some(history), Some(txn)) = (&mut self.history, &mut self.txn) {
+            // the previous contents must be durable before the new ones
+            history.sync()?;
+            txn.synced = true;
+        }
+        self.file.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        if self.snapshot.is_some() {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        self.capture(size, u64::MAX)?;
+        self.file.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let from = self.file.current_lock()?;
+        if !self.file.lock(lock)? {
+            return Ok(false);
+        }
+        if from == LockKind::None && lock == LockKind::Shared {
+            // other connections might have committed in the meantime
+            if let Err(err) = self.refresh_snapshot() {
+                self.file.unlock(LockKind::None)?;
+                return Err(err);
+            }
+        }
+        Ok(true)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        if lock < LockKind::Reserved {
+            if let Some(txn) = self.txn.take() {
+                if let (Some(history), false) = (&mut self.history, txn.synced) {
+                    history.sync()?;
+                }
+                self.commit(txn)?;
+            }
+        }
+        self.file.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.file.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.file.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.file.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.file.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.file.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.file.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.file.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.file.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.file.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.file.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.file.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.file.wal_index(readonly)
+    }
+}
+
+/// Whether the database `file` is in WAL mode, according to the file format versions in its header.
+fn wal_mode<H: DatabaseHandle>(file: &mut H) -> Result<bool, std::io::Error> {
+    if file.size()? < 20 {
+        return Ok(false);
+    }
+    let mut versions = [0; 2];
+    file.read_exact_at(&mut versions, 18)?;
+    Ok(versions.contains(&2))
+}
+
+fn wal_unsupported() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::Unsupported,
+        "the history of databases in WAL mode is not supported",
+    )
+}
+
+/// Parse the `asof` URI parameter into milliseconds since the Unix epoch.
+fn parse_time(value: &str) -> Result<i64, std::io::Error> {
+    if let Ok(secs) = value.parse::<i64>() {
+        return Ok(secs.saturating_mul(1000));
+    }
+    if let Ok(secs) = value.parse::<f64>() {
+        return Ok((secs * 1000.0).round() as i64);
+    }
+    let time = OffsetDateTime::parse(value, &Rfc3339).map_err(|err| {
+        std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid asof timestamp {}: {}", value, err),
+        )
+    })?;
+    Ok((time.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+fn format_time(millis: i64) -> String {
+    match OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000) {
+        Ok(time) => time.to_string(),
+        Err(_) => millis.to_string(),
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn open_options(access: OpenAccess) -> OpenOptions {
+    OpenOptions::new(OpenKind::MainDb, access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let mut ranges = Ranges::new();
+        ranges.insert(10, 20, 1);
+        ranges.insert(30, 40, 2);
+        // only the gaps get added
+        ranges.insert(0, 50, 3);
+        assert_eq!(
+            ranges.overlapping(15, 45),
+            [(15, 20, 1), (20, 30, 3), (30, 40, 2), (40, 45, 3)]
+        );
+        assert_eq!(Ranges::<()>::new().gaps(5, 10), [(5, 10)]);
+
+        let mut ranges = Ranges::new();
+        ranges.insert(10, 20, ());
+        assert_eq!(ranges.gaps(0, 30), [(0, 10), (20, 30)]);
+        assert_eq!(ranges.gaps(12, 18), []);
+    }
+
+    #[test]
+    fn test_records() {
+        for record in [
+            Record::Page {
+                offset: 4096,
+                len: 4096,
+            },
+            Record::Commit {
+                time: 1_700_000_000_000,
+                size: 8192,
+            },
+        ] {
+            assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        }
+        let index = Index {
+            created: 1,
+            collected: i64::MIN,
+            first: 2,
+            last: 3,
+        };
+        assert_eq!(Index::decode(&index.encode()), index);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1700000000").unwrap(), 1_700_000_000_000);
+        assert_eq!(parse_time("1700000000.25").unwrap(), 1_700_000_000_250);
+        assert_eq!(
+            parse_time("2023-11-14T22:13:20.5Z").unwrap(),
+            1_700_000_000_500
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,547 @@
+//! A read-only [Vfs] for databases served over HTTP, e.g. static reference databases on a CDN,
+//! queried in place with HTTP `Range` requests.
+//!
+//! The database names are resolved relative to the base URL of the [HttpVfs] (percent-encoded,
+//! except for `/`, which separates path segments). The size of a
+//! database is retrieved with a `HEAD` request when it is opened, and each read fetches (at least)
+//! the readahead size, so that scans don't issue a request per page. The files are reported as
+//! immutable (`SQLITE_IOCAP_IMMUTABLE`), so SQLite doesn't lock them nor check for a hot journal;
+//! locking is a no-op. Temporary files (e.g. for sorting) are kept in memory. If the server
+//! ignores the `Range` header, the whole database is kept in memory after the first read.
+//!
+//! Reads are conditional on the (strong) `ETag` returned by the `HEAD` request (`If-Match` and
+//! `If-Range`), so that a database that gets replaced while it is open (e.g. on a CDN) fails to be
+//! read instead of returning pages of two different versions.
+//!
+//! The database must not be in WAL mode. Only plain `http://` URLs are supported; put a
+//! TLS-terminating proxy in front of `https://` origins.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::memory::{MemoryHandle, MemoryVfs};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs, WalDisabled};
+
+/// A read-only [Vfs] that reads databases with HTTP `Range` requests.
+pub struct HttpVfs {
+    base: String,
+    readahead: usize,
+    timeout: Duration,
+    /// Temporary files and journals.
+    memory: MemoryVfs,
+}
+
+/// A [DatabaseHandle] opened by [HttpVfs].
+pub struct HttpHandle {
+    file: File,
+}
+
+enum File {
+    Remote(Remote),
+    Memory(MemoryHandle),
+}
+
+struct Remote {
+    url: Url,
+    timeout: Duration,
+    size: u64,
+    /// The entity tag of the database when it got opened, if the server returned a strong one.
+    etag: Option<String>,
+    readahead: usize,
+    lock: LockKind,
+    /// The offset and data of the last response.
+    buffer: (u64, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+struct Response {
+    status: u16,
+    content_length: Option<u64>,
+    content_range: Option<String>,
+    etag: Option<String>,
+    body: Vec<u8>,
+}
+
+impl HttpVfs {
+    /// Serve the databases found under `base` (e.g. `http://cdn.example.com/databases`), with a
+    /// readahead of 64 KiB and a timeout of 30 seconds.
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            base: base.into().trim_end_matches('/').to_string(),
+            readahead: 64 * 1024,
+            timeout: Duration::from_secs(30),
+            memory: MemoryVfs::default(),
+        }
+    }
+
+    /// Fetch at least `readahead` bytes per request.
+    pub fn with_readahead(mut self, readahead: usize) -> Self {
+        self.readahead = readahead;
+        self
+    }
+
+    /// The timeout for connecting as well as for each read and write of a request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn url(&self, db: &str) -> Result<Url, std::io::Error> {
+        Url::parse(&format!(
+            "{}/{}",
+            self.base,
+            percent_encode(db.trim_start_matches('/'))
+        ))
+    }
+
+    /// Retrieve the size and entity tag of `db`, or `None` if it does not exist.
+    fn head(&self, db: &str) -> Result<Option<(u64, Option<String>)>, std::io::Error> {
+        let url = self.url(db)?;
+        let res = request(&url, "HEAD", None, None, self.timeout)?;
+        match res.status {
+            200 => match res.content_length {
+                Some(size) => Ok(Some((size, res.etag))),
+                None => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("missing content-length for {}", url),
+                )),
+            },
+            404 | 410 => Ok(None),
+            status => Err(status_error(&url, status)),
+        }
+    }
+}
+
+impl Remote {
+    fn fetch(&mut self, range: Range<u64>) -> Result<(), std::io::Error> {
+        let range = range.start..range.end.min(self.size);
+        let res = request(
+            &self.url,
+            "GET",
+            Some(range.clone()),
+            self.etag.as_deref(),
+            self.timeout,
+        )?;
+        if self.etag.is_some() && res.etag.is_some() && res.etag != self.etag {
+            return Err(changed_error(&self.url));
+        }
+        self.buffer = match res.status {
+            206 => {
+                let content_range = res.content_range.as_deref().unwrap_or_default();
+                let (start, end, size) = parse_content_range(content_range).ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "invalid content-range from {}: {:?}",
+                            self.url, content_range
+                        ),
+                    )
+                })?;
+                if size.is_some_and(|size| size != self.size) {
+                    return Err(changed_error(&self.url));
+                }
+                if start != range.start || end + 1 - start != res.body.len() as u64 {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{} responded with bytes {}-{} (and a body of {} bytes) for {}-{}",
+                            self.url,
+                            start,
+                            end,
+                            res.body.len(),
+                            range.start,
+                            range.end - 1
+                        ),
+                    ));
+                }
+                (start, res.body)
+            }
+            // the server ignored the range, keep the whole database
+            200 => {
+                if res.body.len() as u64 != self.size {
+                    return Err(changed_error(&self.url));
+                }
+                (0, res.body)
+            }
+            412 => return Err(changed_error(&self.url)),
+            416 => (range.start, Vec::new()),
+            status => return Err(status_error(&self.url, status)),
+        };
+        Ok(())
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let end = offset + buf.len() as u64;
+        let (start, data) = &self.buffer;
+        if offset < *start || end > start + data.len() as u64 {
+            let len = buf.len().max(self.readahead) as u64;
+            self.fetch(offset..offset + len)?;
+        }
+
+        let (start, data) = &self.buffer;
+        let from = (offset - start) as usize;
+        let n = data.len().saturating_sub(from).min(buf.len());
+        buf[..n].copy_from_slice(&data[from..from + n]);
+        buf[n..].fill(0);
+        if n < buf.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+impl Vfs for HttpVfs {
+    type Handle = HttpHandle;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(HttpHandle {
+                file: File::Memory(self.memory.open(db, opts)?),
+            });
+        }
+        if opts.access != OpenAccess::Read {
+            // SQLite retries to open it read-only
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let (size, etag) = self.head(db)?.ok_or(ErrorKind::NotFound)?;
+        Ok(HttpHandle {
+            file: File::Remote(Remote {
+                url: self.url(db)?,
+                timeout: self.timeout,
+                size,
+                // weak tags can't be used for range requests
+                etag: etag.filter(|etag| !etag.starts_with("W/")),
+                readahead: self.readahead,
+                lock: LockKind::None,
+                buffer: (0, Vec::new()),
+            }),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        if self.memory.exists(db)? {
+            self.memory.delete(db)
+        } else {
+            Err(ErrorKind::PermissionDenied.into())
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.memory.exists(db)? || self.head(db)?.is_some())
+    }
+
+    fn temporary_name(&self) -> String {
+        self.memory.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.memory.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.memory.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        if self.memory.exists(db)? {
+            return Ok(true);
+        }
+        Ok(!write && self.head(db)?.is_some())
+    }
+}
+
+impl DatabaseHandle for HttpHandle {
+    type WalIndex = WalDisabled;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.file {
+            File::Remote(remote) => Ok(remote.size),
+            File::Memory(file) => file.size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Remote(remote) => remote.read_exact_at(buf, offset),
+            File::Memory(file) => file.read_exact_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Remote(_) => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Remote(_) => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.set_len(size),
+        }
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        match &mut self.file {
+            File::Remote(remote) => {
+                remote.lock = lock;
+                Ok(true)
+            }
+            File::Memory(file) => file.lock(lock),
+        }
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        match &self.file {
+            File::Remote(remote) => Ok(remote.lock),
+            File::Memory(file) => file.current_lock(),
+        }
+    }
+
+    fn immutable(&self) -> bool {
+        matches!(self.file, File::Remote(_))
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(WalDisabled)
+    }
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, std::io::Error> {
+        let invalid = || {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unsupported url (expected http://host[:port]/path): {}",
+                    url
+                ),
+            )
+        };
+        // would end up in the request line or headers as is
+        if url.contains(|c: char| c.is_ascii_whitespace() || c.is_control()) {
+            return Err(invalid());
+        }
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Percent-encode everything but unreserved characters and `/` of the path `path`.
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded += &format!("%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+fn status_error(url: &Url, status: u16) -> std::io::Error {
+    let kind = match status {
+        404 | 410 => ErrorKind::NotFound,
+        401 | 403 => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, format!("{} responded with status {}", url, status))
+}
+
+fn changed_error(url: &Url) -> std::io::Error {
+    std::io::Error::other(format!("{} changed since it got opened", url))
+}
+
+/// Parse a `Content-Range` header (`bytes {start}-{end}/{size}`, with `*` for an unknown size).
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, size) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    let size = match size {
+        "*" => None,
+        size => Some(size.parse().ok()?),
+    };
+    (start <= end).then_some((start, end, size))
+}
+
+/// Send a single request (on a new connection) and read the response.
+fn request(
+    url: &Url,
+    method: &str,
+    range: Option<Range<u64>>,
+    etag: Option<&str>,
+    timeout: Duration,
+) -> Result<Response, std::io::Error> {
+    let addr = std::net::ToSocketAddrs::to_socket_addrs(&(url.host.as_str(), url.port))?
+        .next()
+        .ok_or(ErrorKind::NotFound)?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nAccept-Encoding: identity\r\n",
+        method, url.path, url.host, url.port
+    );
+    if let Some(range) = range {
+        req += &format!("Range: bytes={}-{}\r\n", range.start, range.end.max(1) - 1);
+    }
+    if let Some(etag) = etag {
+        req += &format!("If-Match: {}\r\nIf-Range: {}\r\n", etag, etag);
+    }
+    req += "\r\n";
+    stream.write_all(req.as_bytes())?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid response from {}: {:?}", url, line),
+            )
+        })?;
+
+    let mut content_length = None;
+    let mut content_range = None;
+    let mut etag = None;
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("content-range") {
+                content_range = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("etag") {
+                etag = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    if method == "HEAD" {
+        // no body
+    } else if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(len) = content_length {
+        body.resize(len as usize, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    Ok(Response {
+        status,
+        content_length,
+        content_range,
+        etag,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = Url::parse("http://localhost:8080/db/main.db").unwrap();
+        assert_eq!(url.host, "localhost");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/db/main.db");
+        assert_eq!(Url::parse("http://example.com").unwrap().port, 80);
+        assert!(Url::parse("https://example.com/main.db").is_err());
+        assert!(Url::parse("http://:80/main.db").is_err());
+        assert!(Url::parse("http://example.com/a b.db").is_err());
+        assert!(Url::parse("http://example.com/a.db\r\nX-Injected: 1").is_err());
+    }
+
+    #[test]
+    fn test_database_names_are_encoded() {
+        let vfs = HttpVfs::new("http://example.com/dbs/");
+        assert_eq!(vfs.url("a b.db").unwrap().path, "/dbs/a%20b.db");
+        assert_eq!(
+            vfs.url("sub/x\r\nHost: evil.db").unwrap().path,
+            "/dbs/sub/x%0D%0AHost%3A%20evil.db"
+        );
+        assert_eq!(vfs.url("/ref-1_2.db").unwrap().path, "/dbs/ref-1_2.db");
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-99/1000"),
+            Some((0, 99, Some(1000)))
+        );
+        assert_eq!(
+            parse_content_range("bytes 100-199/*"),
+            Some((100, 199, None))
+        );
+        assert_eq!(parse_content_range("bytes */1000"), None);
+        assert_eq!(parse_content_range("bytes 9-0/1000"), None);
+    }
+}
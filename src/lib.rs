@@ -10,12 +10,51 @@ use std::mem::{size_of, ManuallyDrop, MaybeUninit};
 use std::ops::Range;
 use std::os::raw::{c_char, c_int};
 use std::pin::Pin;
-use std::ptr::null_mut;
+use std::ptr::{null, null_mut};
 use std::slice;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+#[cfg(feature = "buffer")]
+pub mod buffer;
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "crash")]
+pub mod crash;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "fault")]
+pub mod fault;
 mod ffi;
+#[cfg(feature = "history")]
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod lock;
+#[cfg(any(
+    feature = "embedded",
+    feature = "http",
+    feature = "overlay",
+    feature = "replication"
+))]
+mod memory;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "object_store")]
+pub mod object_store;
+#[cfg(feature = "overlay")]
+pub mod overlay;
+#[cfg(feature = "replication")]
+pub mod replication;
+#[cfg(feature = "tracing")]
+mod trace;
+pub mod wal_index;
 
 /// A file opened by [Vfs].
 pub trait DatabaseHandle: Sync {
@@ -31,6 +70,16 @@ pub trait DatabaseHandle: Sync {
     /// Attempts to write an entire `buf` starting from the given `offset`.
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error>;
 
+    /// Write each `(offset, buf)` of `writes`. Backends that can issue writes in parallel (or in a
+    /// single request) should override this, as it is used to flush coalesced writes at once. The
+    /// default implementation writes them one after another.
+    fn write_batch(&mut self, writes: &[(u64, &[u8])]) -> Result<(), std::io::Error> {
+        for (offset, buf) in writes {
+            self.write_all_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+
     /// Make sure all writes are committed to the underlying storage. If `data_only` is set to
     /// `true`, only the data and not the metadata (like size, access time, etc) should be synced.
     fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error>;
@@ -68,6 +117,75 @@ pub trait DatabaseHandle: Sync {
         Ok(false)
     }
 
+    /// Return a counter that changes whenever the file changed, no matter through which handle
+    /// (e.g. the generation of a remote object). It should be cheap to retrieve, as it is used by
+    /// caches to detect changes made by other connections. Return `None` if there is no such
+    /// counter.
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        Ok(None)
+    }
+
+    /// Intercept a `PRAGMA name` or `PRAGMA name = value` statement on the database. Return `None`
+    /// to fall back to SQLite's normal pragma processing. A returned `Some(Ok(Some(value)))` is
+    /// used as the single result row of the pragma.
+    fn pragma(
+        &mut self,
+        _name: &str,
+        _value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        None
+    }
+
+    /// Query (`enable` is `None`) or change whether page checksums are verified on read. Return
+    /// `None` if the handle doesn't maintain page checksums. Backs `PRAGMA checksum_verification`
+    /// and `SQLITE_FCNTL_CKSM_FILE`.
+    fn checksum_verification(
+        &mut self,
+        _enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        None
+    }
+
+    /// Query (`path` is `None`) or change the proxy file the handle takes its locks on instead of
+    /// the database file itself, e.g. to keep the locks of a database on a network share on a
+    /// local path. An empty `path` locks the database file again. Returns the proxy file in use
+    /// afterwards, or `None` if the handle doesn't support proxy locking. Changing the proxy file
+    /// while holding a lock should fail with [std::io::ErrorKind::WouldBlock]. Backs
+    /// `PRAGMA lock_proxy_file`, `SQLITE_FCNTL_GET_LOCKPROXYFILE` and
+    /// `SQLITE_FCNTL_SET_LOCKPROXYFILE`.
+    fn lock_proxy_file(
+        &mut self,
+        _path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        None
+    }
+
+    /// Called with the "powersafe-overwrite" (PSOW) setting of the file: once after it got opened
+    /// (from the `psow` URI parameter) and whenever it is changed via
+    /// `SQLITE_FCNTL_POWERSAFE_OVERWRITE`.
+    fn set_powersafe_overwrite(&mut self, _enabled: bool) {}
+
+    /// Whether the file can't change while it is open (e.g. a database served read-only). Reported
+    /// as `SQLITE_IOCAP_IMMUTABLE`, with which SQLite neither locks the file nor checks for a hot
+    /// journal.
+    fn immutable(&self) -> bool {
+        false
+    }
+
+    /// Whether a write to the file leaves all other bytes (even those of the same sector) intact on
+    /// power loss. Reported as `SQLITE_IOCAP_POWERSAFE_OVERWRITE` if the "powersafe-overwrite"
+    /// setting of the file is on as well. Handles that rewrite more than the written bytes (e.g.
+    /// whole blocks) must return `false`, and cover those blocks with [DatabaseHandle::sector_size].
+    fn powersafe_overwrite(&self) -> bool {
+        true
+    }
+
+    /// The sector size of the file, i.e. the unit a write could destroy on power loss unless
+    /// [DatabaseHandle::powersafe_overwrite]. SQLite doesn't rewrite synced data within a sector.
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
     fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error>;
 }
 
@@ -136,6 +254,10 @@ pub mod wip {
     }
 }
 
+/// The options a file is opened with, i.e. the typed `SQLITE_OPEN_*` flags passed to `xOpen`.
+///
+/// `SQLITE_OPEN_READONLY`, `SQLITE_OPEN_READWRITE`, `SQLITE_OPEN_CREATE` and
+/// `SQLITE_OPEN_EXCLUSIVE` are combined into [OpenOptions::access].
 #[derive(Debug, Clone, PartialEq)]
 pub struct OpenOptions {
     /// The object type that is being opened.
@@ -144,12 +266,35 @@ pub struct OpenOptions {
     /// The access an object is opened with.
     pub access: OpenAccess,
 
-    /// The file should be deleted when it is closed.
-    delete_on_close: bool,
+    /// The file should be deleted when it is closed (`SQLITE_OPEN_DELETEONCLOSE`).
+    pub delete_on_close: bool,
+
+    /// The file must not be opened if its name is a symbolic link (`SQLITE_OPEN_NOFOLLOW`).
+    pub nofollow: bool,
+
+    /// The database is an in-memory database (`SQLITE_OPEN_MEMORY`).
+    pub memory: bool,
+
+    /// The name of the database got interpreted as URI (`SQLITE_OPEN_URI`).
+    pub uri: bool,
+
+    /// The name of the main database the file belongs to, as passed to [Vfs::open] when the
+    /// database got opened. Set for main databases (to their own name), their journals and their
+    /// WALs, so that those don't have to derive the name of their database from their own.
+    pub database: Option<String>,
+
+    /// Identifies the handle of the main database the file belongs to (unique among the open
+    /// handles of a registered [Vfs]). Set for main databases (to the id of their own handle), their
+    /// journals and their WALs, so that state can be shared between the files of a single
+    /// connection instead of all connections to the same database.
+    pub database_id: Option<usize>,
+
+    /// The URI parameters of the database (`file:db?key=value`), in order of appearance.
+    parameters: Vec<(String, String)>,
 }
 
 /// The object type that is being opened.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpenKind {
     MainDb,
     MainJournal,
@@ -230,6 +375,9 @@ pub fn register<F: DatabaseHandle, V: Vfs<Handle = F>>(
     vfs: V,
     as_default: bool,
 ) -> Result<(), RegisterError> {
+    #[cfg(feature = "tracing")]
+    use trace::{io, vfs};
+
     let io_methods = ffi::sqlite3_io_methods {
         iVersion: 2,
         xClose: Some(io::close::<V, F>),
@@ -242,7 +390,7 @@ pub fn register<F: DatabaseHandle, V: Vfs<Handle = F>>(
         xUnlock: Some(io::unlock::<V, F>),
         xCheckReservedLock: Some(io::check_reserved_lock::<V, F>),
         xFileControl: Some(io::file_control::<V, F>),
-        xSectorSize: Some(io::sector_size::<F>),
+        xSectorSize: Some(io::sector_size::<V, F>),
         xDeviceCharacteristics: Some(io::device_characteristics::<V, F>),
         xShmMap: Some(io::shm_map::<V, F>),
         xShmLock: Some(io::shm_lock::<V, F>),
@@ -314,6 +462,9 @@ pub fn register<F: DatabaseHandle, V: Vfs<Handle = F>>(
 // TODO: add to [Vfs]?
 const MAX_PATH_LENGTH: usize = 512;
 
+/// The sector size reported to SQLite by default (see [DatabaseHandle::sector_size]).
+const SECTOR_SIZE: usize = 1024;
+
 #[repr(C)]
 struct FileState<V, F: DatabaseHandle> {
     base: ffi::sqlite3_file,
@@ -339,6 +490,8 @@ struct FileExt<V, F: DatabaseHandle> {
     chunk_size: Option<usize>,
     persist_wal: bool,
     powersafe_overwrite: bool,
+    /// The proxy file last returned by `SQLITE_FCNTL_GET_LOCKPROXYFILE`, kept alive for SQLite.
+    lock_proxy_file: Option<CString>,
 }
 
 // Example mem-fs implementation:
@@ -410,13 +563,27 @@ mod vfs {
             }
         };
 
-        let mut powersafe_overwrite = true;
-        if flags & ffi::SQLITE_OPEN_URI > 0 && name.is_some() {
-            let param = b"psow\0";
-            if ffi::sqlite3_uri_boolean(z_name, param.as_ptr() as *const c_char, 1) == 0 {
-                powersafe_overwrite = false;
+        // Only the names of main databases, journals and WALs point into the filename structure
+        // SQLite stores the database name and URI parameters in (journals and WALs report the ones
+        // of their database).
+        if name.is_some()
+            && matches!(
+                opts.kind,
+                OpenKind::MainDb | OpenKind::MainJournal | OpenKind::Wal
+            )
+        {
+            let database = ffi::sqlite3_filename_database(z_name);
+            if !database.is_null() {
+                opts.database = CStr::from_ptr(database).to_str().ok().map(String::from);
             }
+            opts.parameters = uri_parameters(z_name);
+            opts.database_id = if opts.kind == OpenKind::MainDb {
+                Some(state.next_id)
+            } else {
+                database_id::<V, F>(state, z_name)
+            };
         }
+        let powersafe_overwrite = opts.uri_boolean("psow", true);
 
         let name = name.map_or_else(|| state.vfs.temporary_name(), String::from);
         let result = state.vfs.open(&name, opts.clone());
@@ -450,12 +617,13 @@ mod vfs {
 
             Err(err) => Err(err),
         };
-        let file = match result {
+        let mut file = match result {
             Ok(f) => f,
             Err(err) => {
                 return state.set_last_error(ffi::SQLITE_CANTOPEN, err);
             }
         };
+        file.set_powersafe_overwrite(powersafe_overwrite);
 
         if let Some(p_out_flags) = p_out_flags.as_mut() {
             *p_out_flags = opts.to_flags();
@@ -478,6 +646,7 @@ mod vfs {
             chunk_size: None,
             persist_wal: false,
             powersafe_overwrite,
+            lock_proxy_file: None,
         });
         state.next_id = state.next_id.overflowing_add(1).0;
 
@@ -944,6 +1113,8 @@ mod io {
             let kind = err.kind();
             if kind == ErrorKind::UnexpectedEof {
                 return ffi::SQLITE_IOERR_SHORT_READ;
+            } else if kind == ErrorKind::InvalidData {
+                return state.set_last_error(ffi::SQLITE_IOERR_DATA, err);
             } else {
                 return state.set_last_error(ffi::SQLITE_IOERR_READ, err);
             }
@@ -1240,9 +1411,49 @@ mod io {
                 Err(err) => state.set_last_error(ffi::SQLITE_ERROR, err),
             },
 
-            // Relevant for proxy-type locking. Not implemented.
-            ffi::SQLITE_FCNTL_GET_LOCKPROXYFILE | ffi::SQLITE_FCNTL_SET_LOCKPROXYFILE => {
-                ffi::SQLITE_NOTFOUND
+            // Relevant for proxy-type locking. Write the path of the proxy file into (char**)pArg
+            // (NULL if the database file itself is locked). Handled by
+            // [DatabaseHandle::lock_proxy_file].
+            ffi::SQLITE_FCNTL_GET_LOCKPROXYFILE => match state.file.lock_proxy_file(None) {
+                None => ffi::SQLITE_NOTFOUND,
+                Some(Ok(path)) => {
+                    state.lock_proxy_file = path.and_then(|path| CString::new(path).ok());
+                    if let Some(p_arg) = (p_arg as *mut *const c_char).as_mut() {
+                        *p_arg = state
+                            .lock_proxy_file
+                            .as_ref()
+                            .map_or(null(), |path| path.as_ptr());
+                    }
+                    ffi::SQLITE_OK
+                }
+                Some(Err(err)) => state.set_last_error(ffi::SQLITE_ERROR, err),
+            },
+
+            // Take the locks on the proxy file at (const char*)pArg from now on (the database file
+            // itself for NULL or an empty path). Handled by [DatabaseHandle::lock_proxy_file].
+            ffi::SQLITE_FCNTL_SET_LOCKPROXYFILE => {
+                let path = match (p_arg as *const c_char).as_ref() {
+                    Some(path) => match CStr::from_ptr(path).to_str() {
+                        Ok(path) => path,
+                        Err(_) => return ffi::SQLITE_ERROR,
+                    },
+                    None => "",
+                };
+                log::trace!(
+                    "[{}] lock proxy file {} ({})",
+                    state.id,
+                    path,
+                    state.db_name
+                );
+
+                match state.file.lock_proxy_file(Some(path)) {
+                    None => ffi::SQLITE_NOTFOUND,
+                    Some(Ok(_)) => ffi::SQLITE_OK,
+                    Some(Err(err)) if err.kind() == ErrorKind::WouldBlock => {
+                        state.set_last_error(ffi::SQLITE_BUSY, err)
+                    }
+                    Some(Err(err)) => state.set_last_error(ffi::SQLITE_ERROR, err),
+                }
             }
 
             // Write last error number into (int)pArg.
@@ -1368,14 +1579,71 @@ mod io {
                         *p_arg = state.powersafe_overwrite as i32;
                     } else {
                         state.powersafe_overwrite = *p_arg == 1;
+                        state
+                            .file
+                            .set_powersafe_overwrite(state.powersafe_overwrite);
                     }
                 };
 
                 ffi::SQLITE_OK
             }
 
-            // Optionally intercept PRAGMA statements. Always fall back to normal pragma processing.
-            ffi::SQLITE_FCNTL_PRAGMA => ffi::SQLITE_NOTFOUND,
+            // Optionally intercept PRAGMA statements. Falls back to normal pragma processing unless
+            // handled by [DatabaseHandle::pragma].
+            ffi::SQLITE_FCNTL_PRAGMA => {
+                let args = match (p_arg as *mut [*mut c_char; 3]).as_mut() {
+                    Some(args) => args,
+                    None => return ffi::SQLITE_NOTFOUND,
+                };
+                let name = match args[1].as_ref().map(|name| CStr::from_ptr(name).to_str()) {
+                    Some(Ok(name)) => name,
+                    _ => return ffi::SQLITE_NOTFOUND,
+                };
+                let value = match args[2].as_ref().map(|value| CStr::from_ptr(value).to_str()) {
+                    Some(Ok(value)) => Some(value),
+                    Some(Err(_)) => return ffi::SQLITE_NOTFOUND,
+                    None => None,
+                };
+                log::trace!(
+                    "[{}] pragma {}={:?} ({})",
+                    state.id,
+                    name,
+                    value,
+                    state.db_name
+                );
+
+                let result = if name.eq_ignore_ascii_case("checksum_verification") {
+                    state
+                        .file
+                        .checksum_verification(
+                            value.map(|value| parse_boolean(value).unwrap_or(false)),
+                        )
+                        .map(|result| result.map(|enabled| Some((enabled as u8).to_string())))
+                } else if name.eq_ignore_ascii_case("lock_proxy_file") {
+                    // no result row if the database file itself is locked, like SQLite does
+                    state.file.lock_proxy_file(value)
+                } else {
+                    None
+                };
+
+                let (rc, msg) = match result.or_else(|| state.file.pragma(name, value)) {
+                    None => return ffi::SQLITE_NOTFOUND,
+                    Some(Ok(result)) => (ffi::SQLITE_OK, result),
+                    Some(Err(err)) => {
+                        let msg = err.to_string();
+                        (state.set_last_error(ffi::SQLITE_ERROR, err), Some(msg))
+                    }
+                };
+
+                // The result (or error message) must be allocated by SQLite, as it is freed by
+                // SQLite.
+                if let Some(msg) = msg.and_then(|msg| CString::new(msg).ok()) {
+                    let fmt = b"%s\0";
+                    args[0] = ffi::sqlite3_mprintf(fmt.as_ptr() as *const c_char, msg.as_ptr());
+                }
+
+                rc
+            }
 
             // May be invoked by SQLite on the database file handle shortly after it is opened in
             // order to provide a custom VFS with access to the connection's busy-handler callback.
@@ -1404,6 +1672,8 @@ mod io {
             ffi::SQLITE_FCNTL_TRACE => {
                 let trace = CStr::from_ptr(p_arg as *const c_char);
                 log::trace!("{}", trace.to_string_lossy());
+                #[cfg(feature = "tracing")]
+                tracing::trace!(trace = %trace.to_string_lossy(), "sqlite trace");
                 ffi::SQLITE_OK
             }
 
@@ -1477,18 +1747,33 @@ mod io {
             // unix-specific feature.
             ffi::SQLITE_FCNTL_EXTERNAL_READER => ffi::SQLITE_NOTFOUND,
 
-            // Unknown use-case. Ignored.
-            ffi::SQLITE_FCNTL_CKSM_FILE => ffi::SQLITE_NOTFOUND,
+            // Used by checksum VFSs to find out whether a file maintains page checksums. Answered
+            // with the file itself for handles that support [DatabaseHandle::checksum_verification].
+            ffi::SQLITE_FCNTL_CKSM_FILE => match state.file.checksum_verification(None) {
+                Some(_) => {
+                    if let Some(p_arg) = (p_arg as *mut *mut ffi::sqlite3_file).as_mut() {
+                        *p_arg = p_file;
+                    }
+                    ffi::SQLITE_OK
+                }
+                None => ffi::SQLITE_NOTFOUND,
+            },
 
             _ => ffi::SQLITE_NOTFOUND,
         }
     }
 
     /// Return the sector-size in bytes for a file.
-    pub unsafe extern "C" fn sector_size<F>(_p_file: *mut ffi::sqlite3_file) -> c_int {
-        log::trace!("sector_size");
+    pub unsafe extern "C" fn sector_size<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+    ) -> c_int {
+        let state = match file_state::<V, F>(p_file) {
+            Ok(f) => f,
+            Err(_) => return SECTOR_SIZE as c_int,
+        };
+        log::trace!("[{}] sector_size", state.id);
 
-        1024
+        state.file.sector_size() as c_int
     }
 
     /// Return the device characteristic flags supported by a file.
@@ -1507,11 +1792,14 @@ mod io {
         // after reboot following a crash or power loss, the only bytes in a file that were written
         // at the application level might have changed and that adjacent bytes, even bytes within
         // the same sector are guaranteed to be unchanged
-        if state.powersafe_overwrite {
-            ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE
-        } else {
-            0
+        let mut characteristics = 0;
+        if state.powersafe_overwrite && state.file.powersafe_overwrite() {
+            characteristics |= ffi::SQLITE_IOCAP_POWERSAFE_OVERWRITE;
         }
+        if state.file.immutable() {
+            characteristics |= ffi::SQLITE_IOCAP_IMMUTABLE;
+        }
+        characteristics
     }
 
     /// Create a shared memory file mapping.
@@ -1849,6 +2137,68 @@ impl<V, F: DatabaseHandle> FileExt<V, F> {
     }
 }
 
+/// Interpret `value` as boolean like `sqlite3GetBoolean` does for pragma and URI parameter values:
+/// integers (decimal, or hexadecimal with a `0x` prefix) are `true` unless zero, `on`, `yes`,
+/// `true`, `full` and `extra` are `true`, and `off`, `no` and `false` are `false` (ignoring case).
+/// Returns `None` for anything else.
+fn parse_boolean(value: &str) -> Option<bool> {
+    if value.starts_with(|c: char| c.is_ascii_digit()) {
+        // only the leading digits count, like for `sqlite3Atoi`
+        let (digits, radix) = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) if hex.starts_with(|c: char| c.is_ascii_hexdigit()) => (hex, 16),
+            _ => (value, 10),
+        };
+        return Some(
+            digits
+                .chars()
+                .take_while(|c| c.is_digit(radix))
+                .any(|c| c != '0'),
+        );
+    }
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" | "full" | "extra" => Some(true),
+        "off" | "no" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Collect all URI parameters of the database filename `z_name`.
+/// The id of the main database handle the journal or WAL `z_name` belongs to, if the main database
+/// got opened by this [Vfs].
+unsafe fn database_id<V, F: DatabaseHandle>(
+    state: &State<V>,
+    z_name: *const c_char,
+) -> Option<usize> {
+    let main_db = ffi::sqlite3_database_file_object(z_name);
+    if main_db.is_null() || !std::ptr::eq((*main_db).pMethods, &state.io_methods) {
+        return None;
+    }
+    let main_db = &*(main_db as *const FileState<V, F>);
+    Some(main_db.ext.assume_init_ref().id)
+}
+
+unsafe fn uri_parameters(z_name: *const c_char) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    for i in 0.. {
+        let key = ffi::sqlite3_uri_key(z_name, i);
+        if key.is_null() {
+            break;
+        }
+        let value = ffi::sqlite3_uri_parameter(z_name, key);
+        if value.is_null() {
+            continue;
+        }
+        parameters.push((
+            CStr::from_ptr(key).to_string_lossy().into_owned(),
+            CStr::from_ptr(value).to_string_lossy().into_owned(),
+        ));
+    }
+    parameters
+}
+
 fn null_ptr_error() -> std::io::Error {
     std::io::Error::new(ErrorKind::Other, "received null pointer")
 }
@@ -1872,22 +2222,117 @@ unsafe fn file_state<'a, V, F: DatabaseHandle>(
 }
 
 impl OpenOptions {
-    fn from_flags(flags: i32) -> Option<Self> {
+    /// Options to open a file of the given `kind` with `access`, without any other flags.
+    pub fn new(kind: OpenKind, access: OpenAccess) -> Self {
+        OpenOptions {
+            kind,
+            access,
+            delete_on_close: false,
+            nofollow: false,
+            memory: false,
+            uri: false,
+            database: None,
+            database_id: None,
+            parameters: Vec::new(),
+        }
+    }
+
+    pub fn with_delete_on_close(mut self, delete_on_close: bool) -> Self {
+        self.delete_on_close = delete_on_close;
+        self
+    }
+
+    pub fn with_nofollow(mut self, nofollow: bool) -> Self {
+        self.nofollow = nofollow;
+        self
+    }
+
+    pub fn with_memory(mut self, memory: bool) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn with_uri(mut self, uri: bool) -> Self {
+        self.uri = uri;
+        self
+    }
+
+    pub fn with_database(mut self, database: &str) -> Self {
+        self.database = Some(database.to_string());
+        self
+    }
+
+    pub fn with_database_id(mut self, database_id: usize) -> Self {
+        self.database_id = Some(database_id);
+        self
+    }
+
+    /// Add the URI parameter `name` (as if the database got opened as `file:db?name=value`).
+    pub fn with_uri_parameter(mut self, name: &str, value: &str) -> Self {
+        self.parameters.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Parse the `SQLITE_OPEN_*` flags passed to `xOpen`. Returns `None` if they contain neither
+    /// a known object type nor a known access mode.
+    pub fn from_flags(flags: i32) -> Option<Self> {
         Some(OpenOptions {
             kind: OpenKind::from_flags(flags)?,
             access: OpenAccess::from_flags(flags)?,
             delete_on_close: flags & ffi::SQLITE_OPEN_DELETEONCLOSE > 0,
+            nofollow: flags & ffi::SQLITE_OPEN_NOFOLLOW > 0,
+            memory: flags & ffi::SQLITE_OPEN_MEMORY > 0,
+            uri: flags & ffi::SQLITE_OPEN_URI > 0,
+            database: None,
+            database_id: None,
+            parameters: Vec::new(),
         })
     }
 
-    fn to_flags(&self) -> i32 {
+    /// The value of the URI parameter `name` (`file:db?name=value`), if present. Journals and WALs
+    /// report the parameters of their database; other files have none.
+    pub fn uri_parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The URI parameter `name` as boolean, interpreted like `sqlite3_uri_boolean` does (integers
+    /// other than zero, `yes`, `true`, `on`, `full` and `extra` are `true`; `0`, `no`, `false` and
+    /// `off` are `false`). Returns `default` if the parameter is missing or not a boolean.
+    pub fn uri_boolean(&self, name: &str, default: bool) -> bool {
+        self.uri_parameter(name)
+            .and_then(parse_boolean)
+            .unwrap_or(default)
+    }
+
+    /// The URI parameter `name` as integer (decimal, or hexadecimal with a `0x` prefix), like
+    /// `sqlite3_uri_int64`. Returns `default` if the parameter is missing or not an integer.
+    pub fn uri_int64(&self, name: &str, default: i64) -> i64 {
+        let value = match self.uri_parameter(name) {
+            Some(value) => value,
+            None => return default,
+        };
+        let parsed = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16).map(|n| n as i64).ok(),
+            None => value.parse::<i64>().ok(),
+        };
+        parsed.unwrap_or(default)
+    }
+
+    /// The `SQLITE_OPEN_*` flags of the options.
+    pub fn to_flags(&self) -> i32 {
+        let flag = |set: bool, flag: i32| if set { flag } else { 0 };
         self.kind.to_flags()
             | self.access.to_flags()
-            | if self.delete_on_close {
-                ffi::SQLITE_OPEN_DELETEONCLOSE
-            } else {
-                0
-            }
+            | flag(self.delete_on_close, ffi::SQLITE_OPEN_DELETEONCLOSE)
+            | flag(self.nofollow, ffi::SQLITE_OPEN_NOFOLLOW)
+            | flag(self.memory, ffi::SQLITE_OPEN_MEMORY)
+            | flag(self.uri, ffi::SQLITE_OPEN_URI)
     }
 }
 
@@ -2044,6 +2489,67 @@ impl From<std::ffi::NulError> for RegisterError {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_uri_parameters() {
+        let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Read)
+            .with_uri(true)
+            .with_uri_parameter("key", "value")
+            .with_uri_parameter("on", "ON")
+            .with_uri_parameter("off", "no")
+            .with_uri_parameter("one", "01")
+            .with_uri_parameter("zero", "000")
+            .with_uri_parameter("other", "maybe")
+            .with_uri_parameter("full", "Full")
+            .with_uri_parameter("enabled", "enabled")
+            .with_uri_parameter("hex_flag", "0x10")
+            .with_uri_parameter("suffix", "0abc")
+            .with_uri_parameter("n", "-42")
+            .with_uri_parameter("hex", "0x1F");
+
+        assert_eq!(opts.uri_parameter("key"), Some("value"));
+        assert_eq!(opts.uri_parameter("missing"), None);
+        assert!(opts.uri_boolean("on", false));
+        assert!(!opts.uri_boolean("off", true));
+        assert!(opts.uri_boolean("one", false));
+        assert!(!opts.uri_boolean("zero", true));
+        assert!(opts.uri_boolean("other", true));
+        assert!(opts.uri_boolean("full", false));
+        assert!(!opts.uri_boolean("enabled", false));
+        assert!(opts.uri_boolean("hex_flag", false));
+        assert!(!opts.uri_boolean("suffix", true));
+        assert!(opts.uri_boolean("n", true));
+        assert!(!opts.uri_boolean("n", false));
+        assert!(!opts.uri_boolean("missing", false));
+        assert_eq!(opts.uri_int64("n", 0), -42);
+        assert_eq!(opts.uri_int64("hex", 0), 31);
+        assert_eq!(opts.uri_int64("key", 7), 7);
+        assert_eq!(opts.uri_int64("missing", 7), 7);
+    }
+
+    #[test]
+    fn test_open_flags() {
+        let flags = ffi::SQLITE_OPEN_MAIN_JOURNAL
+            | ffi::SQLITE_OPEN_READWRITE
+            | ffi::SQLITE_OPEN_CREATE
+            | ffi::SQLITE_OPEN_EXCLUSIVE
+            | ffi::SQLITE_OPEN_DELETEONCLOSE
+            | ffi::SQLITE_OPEN_NOFOLLOW
+            | ffi::SQLITE_OPEN_URI;
+        let opts = OpenOptions::from_flags(flags).unwrap();
+        assert_eq!(
+            opts,
+            OpenOptions::new(OpenKind::MainJournal, OpenAccess::CreateNew)
+                .with_delete_on_close(true)
+                .with_nofollow(true)
+                .with_uri(true)
+        );
+        assert_eq!(opts.to_flags(), flags);
+
+        let opts = OpenOptions::new(OpenKind::TempDb, OpenAccess::Read).with_memory(true);
+        assert_eq!(OpenOptions::from_flags(opts.to_flags()), Some(opts));
+        assert_eq!(OpenOptions::from_flags(ffi::SQLITE_OPEN_READONLY), None);
+    }
+
     #[test]
     fn test_lock_order() {
         assert!(LockKind::None < LockKind::Shared);
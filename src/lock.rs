@@ -0,0 +1,240 @@
+//! Locking of databases in process memory, for [Vfs](crate::Vfs) implementations that don't have
+//! a locking mechanism of their own.
+//!
+//! A [LockManager] keeps the lock state of each database (identified by a key, usually its name),
+//! and hands out a [DatabaseLock] per handle. Handles delegate their `lock`, `unlock`, `reserved`
+//! and `current_lock` to it, which follow the rules of
+//! [DatabaseHandle::lock](crate::DatabaseHandle::lock):
+//!
+//! - any number of handles can hold a [LockKind::Shared] lock,
+//! - only one handle at a time can hold a [LockKind::Reserved] lock, while new
+//!   [LockKind::Shared] locks can still be acquired,
+//! - a handle that fails to acquire a [LockKind::Exclusive] lock because of other readers keeps a
+//!   [LockKind::Pending] lock, which keeps new readers out until the existing ones are done, so
+//!   that writers are not starved by a steady stream of readers,
+//! - a [LockKind::Exclusive] lock is only granted once its handle is the only reader left.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::LockKind;
+
+/// Manages the locks of databases in process memory. Clones share their state.
+#[derive(Default, Clone)]
+pub struct LockManager {
+    databases: Arc<Mutex<HashMap<String, LockState>>>,
+}
+
+/// The lock of a single handle on a database of a [LockManager]. Released when dropped.
+pub struct DatabaseLock {
+    databases: Arc<Mutex<HashMap<String, LockState>>>,
+    db: String,
+    current: LockKind,
+}
+
+#[derive(Default, PartialEq)]
+struct LockState {
+    shared: usize,
+    reserved: bool,
+    pending: bool,
+    exclusive: bool,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new lock on the database `db`, not holding any lock yet.
+    pub fn lock(&self, db: &str) -> DatabaseLock {
+        DatabaseLock {
+            databases: self.databases.clone(),
+            db: db.to_string(),
+            current: LockKind::None,
+        }
+    }
+}
+
+impl DatabaseLock {
+    /// Move the lock to `to`. Returns whether the lock could be acquired; releasing a lock always
+    /// succeeds. When a [LockKind::Exclusive] lock cannot be acquired because of other readers,
+    /// the handle is left with a [LockKind::Pending] lock.
+    ///
+    /// A [LockKind::Pending] lock cannot be requested explicitly (SQLite never does): unless the
+    /// handle already holds it, moving to it returns `false` and leaves the lock unchanged.
+    pub fn lock(&mut self, to: LockKind) -> bool {
+        let from = self.current;
+        if from == to {
+            return true;
+        }
+        if to == LockKind::Pending {
+            return false;
+        }
+        let mut databases = self.databases.lock().unwrap();
+        let state = databases.entry(self.db.clone()).or_default();
+
+        // release everything above the target lock first
+        if to < from {
+            if from >= LockKind::Reserved && to < LockKind::Reserved {
+                state.reserved = false;
+            }
+            if from >= LockKind::Pending {
+                state.pending = false;
+                state.exclusive = false;
+            }
+            if to == LockKind::None {
+                state.shared -= 1;
+            }
+            if *state == LockState::default() {
+                databases.remove(&self.db);
+            }
+            self.current = to;
+            return true;
+        }
+
+        match to {
+            LockKind::Shared => {
+                if state.pending || state.exclusive {
+                    return false;
+                }
+                state.shared += 1;
+            }
+            LockKind::Reserved => {
+                if state.reserved || state.pending || state.exclusive {
+                    return false;
+                }
+                state.reserved = true;
+            }
+            LockKind::Exclusive => {
+                if from < LockKind::Pending {
+                    if (from < LockKind::Reserved && state.reserved) || state.pending {
+                        return false;
+                    }
+                    state.pending = true;
+                    state.reserved = true;
+                    self.current = LockKind::Pending;
+                }
+                if state.shared > 1 {
+                    return false;
+                }
+                state.exclusive = true;
+            }
+            LockKind::None | LockKind::Pending => unreachable!(),
+        }
+        self.current = to;
+        true
+    }
+
+    /// Release the lock down to `to`.
+    pub fn unlock(&mut self, to: LockKind) -> bool {
+        self.lock(to)
+    }
+
+    /// Check if any handle holds a [LockKind::Reserved], [LockKind::Pending] or
+    /// [LockKind::Exclusive] lock on the database.
+    pub fn reserved(&self) -> bool {
+        let databases = self.databases.lock().unwrap();
+        databases
+            .get(&self.db)
+            .is_some_and(|state| state.reserved || state.pending || state.exclusive)
+    }
+
+    /// The lock currently held.
+    pub fn current_lock(&self) -> LockKind {
+        self.current
+    }
+}
+
+impl Drop for DatabaseLock {
+    fn drop(&mut self) {
+        self.lock(LockKind::None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_and_reserved() {
+        let manager = LockManager::new();
+        let mut a = manager.lock("main.db");
+        let mut b = manager.lock("main.db");
+        let mut other = manager.lock("other.db");
+
+        assert!(a.lock(LockKind::Shared));
+        assert!(b.lock(LockKind::Shared));
+        assert!(!a.reserved());
+        assert!(a.lock(LockKind::Reserved));
+        assert!(b.reserved());
+        assert!(!b.lock(LockKind::Reserved));
+
+        // databases are locked independently
+        assert!(other.lock(LockKind::Shared));
+        assert!(other.lock(LockKind::Exclusive));
+
+        assert!(a.unlock(LockKind::Shared));
+        assert!(!b.reserved());
+        assert!(b.lock(LockKind::Reserved));
+        assert_eq!(b.current_lock(), LockKind::Reserved);
+    }
+
+    #[test]
+    fn test_pending_keeps_new_readers_out() {
+        let manager = LockManager::new();
+        let mut writer = manager.lock("main.db");
+        let mut reader = manager.lock("main.db");
+        assert!(writer.lock(LockKind::Shared));
+        assert!(reader.lock(LockKind::Shared));
+
+        // the writer waits for the reader, but keeps a pending lock
+        assert!(writer.lock(LockKind::Reserved));
+        assert!(!writer.lock(LockKind::Exclusive));
+        assert_eq!(writer.current_lock(), LockKind::Pending);
+        let mut late = manager.lock("main.db");
+        assert!(!late.lock(LockKind::Shared));
+
+        // until the reader is done
+        assert!(reader.unlock(LockKind::None));
+        assert!(writer.lock(LockKind::Exclusive));
+        assert!(!late.lock(LockKind::Shared));
+
+        assert!(writer.unlock(LockKind::Shared));
+        assert!(late.lock(LockKind::Shared));
+    }
+
+    #[test]
+    fn test_pending_cannot_be_requested() {
+        let manager = LockManager::new();
+        let mut a = manager.lock("main.db");
+        assert!(!a.lock(LockKind::Pending));
+        assert_eq!(a.current_lock(), LockKind::None);
+        assert!(a.lock(LockKind::Shared));
+        assert!(a.lock(LockKind::Exclusive));
+        assert!(!a.unlock(LockKind::Pending));
+        assert_eq!(a.current_lock(), LockKind::Exclusive);
+
+        // but is kept while waiting for an exclusive lock
+        let mut b = manager.lock("main.db");
+        assert!(a.unlock(LockKind::Shared));
+        assert!(b.lock(LockKind::Shared));
+        assert!(!a.lock(LockKind::Exclusive));
+        assert!(a.lock(LockKind::Pending));
+        assert!(b.reserved());
+    }
+
+    #[test]
+    fn test_drop_releases_lock() {
+        let manager = LockManager::new();
+        let mut a = manager.lock("main.db");
+        assert!(a.lock(LockKind::Shared));
+        assert!(a.lock(LockKind::Exclusive));
+        drop(a);
+
+        let mut b = manager.lock("main.db");
+        assert!(b.lock(LockKind::Shared));
+        assert!(b.lock(LockKind::Exclusive));
+        drop(b);
+        assert!(manager.databases.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::lock::{DatabaseLock, LockManager};
+use crate::wal_index::{MemWalIndex, WalIndexManager};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenOptions, Vfs};
+
+/// The prefix of the names of temporary files.
+pub(crate) const TEMPORARY_PREFIX: &str = "etilqs_";
+
+/// An in-memory [Vfs]. Files are shared by name between all handles (and clones of the
+/// [MemoryVfs]), locks and wal indexes are managed in process memory.
+#[derive(Default, Clone)]
+pub struct MemoryVfs {
+    files: Arc<Mutex<HashMap<String, Arc<SharedFile>>>>,
+    locks: LockManager,
+    wal_indexes: WalIndexManager,
+    temp_counter: Arc<AtomicUsize>,
+}
+
+/// A [DatabaseHandle] opened by [MemoryVfs].
+pub struct MemoryHandle {
+    db: String,
+    file: Arc<SharedFile>,
+    lock: DatabaseLock,
+    wal_indexes: WalIndexManager,
+}
+
+#[derive(Default)]
+struct SharedFile {
+    data: Mutex<Vec<u8>>,
+}
+
+impl Vfs for MemoryVfs {
+    type Handle = MemoryHandle;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let file = match (files.get(db), opts.access) {
+            (Some(_), OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (Some(file), _) => file.clone(),
+            (None, OpenAccess::Create | OpenAccess::CreateNew) => {
+                files.entry(db.to_string()).or_default().clone()
+            }
+            (None, _) => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(MemoryHandle {
+            db: db.to_string(),
+            file,
+            lock: self.locks.lock(db),
+            wal_indexes: self.wal_indexes.clone(),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        match self.files.lock().unwrap().remove(db) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.files.lock().unwrap().contains_key(db))
+    }
+
+    fn temporary_name(&self) -> String {
+        format!(
+            "{}{:x}",
+            TEMPORARY_PREFIX,
+            self.temp_counter.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let random = RandomState::new().build_hasher().finish().to_ne_bytes();
+            for (b, r) in chunk.iter_mut().zip(random) {
+                *b = r as i8;
+            }
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        std::thread::sleep(duration);
+        duration
+    }
+}
+
+impl DatabaseHandle for MemoryHandle {
+    type WalIndex = MemWalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.data.lock().unwrap().len() as u64)
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        read_at(&self.file.data.lock().unwrap(), buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        write_at(&mut self.file.data.lock().unwrap(), buf, offset);
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.data.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
+        Ok(self.lock.lock(to))
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(self.lock.reserved())
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock.current_lock())
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(self.wal_indexes.wal_index(&self.db))
+    }
+}
+
+/// Read `buf` from `data` at `offset`, filling the part beyond the end of `data` with zeros.
+fn read_at(data: &[u8], buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    let offset = (offset as usize).min(data.len());
+    let n = (data.len() - offset).min(buf.len());
+    buf[..n].copy_from_slice(&data[offset..offset + n]);
+    buf[n..].fill(0);
+    if n < buf.len() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn write_at(data: &mut Vec<u8>, buf: &[u8], offset: u64) {
+    let offset = offset as usize;
+    if data.len() < offset + buf.len() {
+        data.resize(offset + buf.len(), 0);
+    }
+    data[offset..offset + buf.len()].copy_from_slice(buf);
+}
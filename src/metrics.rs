@@ -0,0 +1,680 @@
+//! Metrics for any [Vfs]: call counts, transferred bytes and latency histograms.
+//!
+//! All metrics are recorded per database, [OpenKind] and operation. Journals and WALs are recorded
+//! under the name of their main database, so that e.g. journal syncs and main database reads of the
+//! same database can be compared directly. Temporary files (which have random names and don't
+//! belong to a main database) are all recorded under [TEMPORARY_DB], so that they don't add new
+//! entries (and Prometheus series) for each file. Operations that are not bound to an open file
+//! (like [Vfs::delete]) are recorded without a kind. Locks of the wal-index are recorded as
+//! [Op::WalIndexLock] of the main database.
+//!
+//! ```ignore
+//! let vfs = InstrumentedVfs::new(vfs);
+//! let metrics = vfs.metrics();
+//! sqlite_vfs::register("instrumented", vfs, false)?;
+//! // ...
+//! println!("{}", metrics.snapshot().to_prometheus());
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::wip::{WalIndex, WalIndexLock};
+use crate::{DatabaseHandle, LockKind, OpenKind, OpenOptions, Vfs};
+
+/// The database name all temporary files are recorded under.
+pub const TEMPORARY_DB: &str = "(temporary)";
+
+/// The upper bounds of the latency histogram buckets (an additional bucket catches everything
+/// above).
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(10),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// A [Vfs] that records metrics about all calls to the wrapped [Vfs].
+pub struct InstrumentedVfs<V> {
+    vfs: V,
+    metrics: Metrics,
+    /// The names of the temporary files that have been opened but not yet deleted.
+    temporary: Mutex<HashSet<String>>,
+}
+
+/// A [DatabaseHandle] opened by [InstrumentedVfs].
+pub struct InstrumentedHandle<H> {
+    handle: H,
+    db: String,
+    kind: OpenKind,
+    metrics: Metrics,
+    /// The lock and the time of the first `BUSY` result while trying to acquire it.
+    busy_since: Option<(LockKind, Instant)>,
+}
+
+/// A wal-index of an [InstrumentedHandle] that records its locks.
+pub struct InstrumentedWalIndex<W> {
+    wal_index: W,
+    db: String,
+    kind: OpenKind,
+    metrics: Metrics,
+}
+
+/// The metrics of an [InstrumentedVfs]. Cheap to clone, all clones share the same metrics.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    entries: Arc<Mutex<HashMap<Key, Stats>>>,
+}
+
+/// A recorded operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Op {
+    Open,
+    Delete,
+    Exists,
+    Access,
+    Size,
+    Read,
+    Write,
+    Sync,
+    SetLen,
+    Lock,
+    Unlock,
+    Reserved,
+    /// The time from the first `BUSY` result of a lock until the lock was acquired.
+    LockWait,
+    WalIndexLock,
+}
+
+/// Reads a counter of an [Entry].
+type Counter = fn(&Entry) -> u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    db: String,
+    kind: Option<OpenKind>,
+    op: Op,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Stats {
+    calls: u64,
+    errors: u64,
+    busy: u64,
+    bytes: u64,
+    latency: Histogram,
+}
+
+/// A point in time copy of all [Metrics].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// All entries, sorted by database, kind and operation.
+    pub entries: Vec<Entry>,
+}
+
+/// The metrics of a single operation on a single database and [OpenKind].
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub db: String,
+    /// `None` for operations that aren't bound to an open file.
+    pub kind: Option<OpenKind>,
+    pub op: Op,
+    pub calls: u64,
+    /// Calls that returned an error. Short reads are not counted as errors.
+    pub errors: u64,
+    /// Lock calls (including wal-index locks) that returned `BUSY`.
+    pub busy: u64,
+    /// Bytes read or written.
+    pub bytes: u64,
+    pub latency: Histogram,
+}
+
+/// A latency histogram with the buckets [LATENCY_BUCKETS].
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// The number of observations per bucket (not cumulative). The last bucket contains all
+    /// observations above the largest bound of [LATENCY_BUCKETS].
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl<V> InstrumentedVfs<V> {
+    /// Wrap `vfs`.
+    pub fn new(vfs: V) -> Self {
+        Self {
+            vfs,
+            metrics: Metrics::default(),
+            temporary: Default::default(),
+        }
+    }
+
+    /// The metrics of this VFS. Retrieve them before registering the VFS.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+impl<V> InstrumentedVfs<V> {
+    /// The database name operations on `db` are recorded under.
+    fn label(&self, db: &str) -> String {
+        if self.temporary.lock().unwrap().contains(db) {
+            TEMPORARY_DB.to_string()
+        } else {
+            db.to_string()
+        }
+    }
+}
+
+impl<V: Vfs> Vfs for InstrumentedVfs<V> {
+    type Handle = InstrumentedHandle<V::Handle>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let kind = opts.kind;
+        let temporary = opts.database.is_none()
+            && !matches!(
+                kind,
+                OpenKind::MainDb | OpenKind::MainJournal | OpenKind::Wal
+            );
+        let main_db = match &opts.database {
+            Some(main_db) => main_db.clone(),
+            None if temporary => TEMPORARY_DB.to_string(),
+            None => db.to_string(),
+        };
+
+        let start = Instant::now();
+        let result = self.vfs.open(db, opts);
+        self.metrics
+            .record(&main_db, Some(kind), Op::Open, start, &result, 0);
+        if temporary && result.is_ok() {
+            self.temporary.lock().unwrap().insert(db.to_string());
+        }
+
+        Ok(InstrumentedHandle {
+            handle: result?,
+            db: main_db,
+            kind,
+            metrics: self.metrics.clone(),
+            busy_since: None,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        let label = if self.temporary.lock().unwrap().remove(db) {
+            TEMPORARY_DB
+        } else {
+            db
+        };
+
+        let start = Instant::now();
+        let result = self.vfs.delete(db);
+        self.metrics
+            .record(label, None, Op::Delete, start, &result, 0);
+        result
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        let label = self.label(db);
+        let start = Instant::now();
+        let result = self.vfs.exists(db);
+        self.metrics
+            .record(&label, None, Op::Exists, start, &result, 0);
+        result
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        let start = Instant::now();
+        let label = self.label(db);
+        let result = self.vfs.access(db, write);
+        self.metrics
+            .record(&label, None, Op::Access, start, &result, 0);
+        result
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl Metrics {
+    fn record<T>(
+        &self,
+        db: &str,
+        kind: Option<OpenKind>,
+        op: Op,
+        start: Instant,
+        result: &Result<T, std::io::Error>,
+        bytes: usize,
+    ) {
+        let elapsed = start.elapsed();
+        let mut entries = self.entries.lock().unwrap();
+        let key = Key {
+            db: db.to_string(),
+            kind,
+            op,
+        };
+        let stats = entries.entry(key).or_default();
+        stats.calls += 1;
+        match result {
+            Ok(_) => stats.bytes += bytes as u64,
+            Err(err) if op == Op::Read && err.kind() == ErrorKind::UnexpectedEof => {
+                stats.bytes += bytes as u64
+            }
+            Err(_) => stats.errors += 1,
+        }
+        stats.latency.observe(elapsed);
+    }
+
+    fn record_busy(&self, db: &str, kind: OpenKind, op: Op) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = Key {
+            db: db.to_string(),
+            kind: Some(kind),
+            op,
+        };
+        entries.entry(key).or_default().busy += 1;
+    }
+
+    /// Copy the current metrics.
+    pub fn snapshot(&self) -> Snapshot {
+        let entries = self.entries.lock().unwrap();
+        let mut entries: Vec<_> = entries
+            .iter()
+            .map(|(key, stats)| Entry {
+                db: key.db.clone(),
+                kind: key.kind,
+                op: key.op,
+                calls: stats.calls,
+                errors: stats.errors,
+                busy: stats.busy,
+                bytes: stats.bytes,
+                latency: stats.latency.clone(),
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            (&a.db, a.kind.map(kind_label), a.op).cmp(&(&b.db, b.kind.map(kind_label), b.op))
+        });
+        Snapshot { entries }
+    }
+
+    /// Reset all metrics.
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+}
+
+impl Snapshot {
+    /// Find the entry for the given database, kind and operation.
+    pub fn get(&self, db: &str, kind: Option<OpenKind>, op: Op) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.db == db && e.kind == kind && e.op == op)
+    }
+
+    /// Render the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters: [(&str, &str, Counter); 4] = [
+            ("sqlite_vfs_calls_total", "Number of calls.", |e| e.calls),
+            (
+                "sqlite_vfs_errors_total",
+                "Number of calls that returned an error.",
+                |e| e.errors,
+            ),
+            (
+                "sqlite_vfs_busy_total",
+                "Number of lock and wal-index lock calls that returned BUSY.",
+                |e| e.busy,
+            ),
+            (
+                "sqlite_vfs_bytes_total",
+                "Number of bytes read or written.",
+                |e| e.bytes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for entry in &self.entries {
+                writeln!(out, "{}{{{}}} {}", name, labels(entry), value(entry)).unwrap();
+            }
+        }
+
+        let name = "sqlite_vfs_latency_seconds";
+        writeln!(out, "# HELP {} Latency of calls.", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for entry in &self.entries {
+            let labels = labels(entry);
+            let mut cumulative = 0;
+            for (i, count) in entry.latency.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = match LATENCY_BUCKETS.get(i) {
+                    Some(bound) => bound.as_secs_f64().to_string(),
+                    None => "+Inf".to_string(),
+                };
+                writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, le, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_sum{{{}}} {}",
+                name,
+                labels,
+                entry.latency.sum.as_secs_f64()
+            )
+            .unwrap();
+            writeln!(out, "{}_count{{{}}} {}", name, labels, entry.latency.count).unwrap();
+        }
+
+        out
+    }
+}
+
+fn labels(entry: &Entry) -> String {
+    format!(
+        "db=\"{}\",kind=\"{}\",op=\"{}\"",
+        escape(&entry.db),
+        entry.kind.map(kind_label).unwrap_or(""),
+        op_label(entry.op)
+    )
+}
+
+/// Escape a Prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn kind_label(kind: OpenKind) -> &'static str {
+    match kind {
+        OpenKind::MainDb => "main_db",
+        OpenKind::MainJournal => "main_journal",
+        OpenKind::TempDb => "temp_db",
+        OpenKind::TempJournal => "temp_journal",
+        OpenKind::TransientDb => "transient_db",
+        OpenKind::SubJournal => "sub_journal",
+        OpenKind::SuperJournal => "super_journal",
+        OpenKind::Wal => "wal",
+    }
+}
+
+fn op_label(op: Op) -> &'static str {
+    match op {
+        Op::Open => "open",
+        Op::Delete => "delete",
+        Op::Exists => "exists",
+        Op::Access => "access",
+        Op::Size => "size",
+        Op::Read => "read",
+        Op::Write => "write",
+        Op::Sync => "sync",
+        Op::SetLen => "set_len",
+        Op::Lock => "lock",
+        Op::Unlock => "unlock",
+        Op::Reserved => "reserved",
+        Op::LockWait => "lock_wait",
+        Op::WalIndexLock => "wal_index_lock",
+    }
+}
+
+impl<H> InstrumentedHandle<H> {
+    fn record<T>(&self, op: Op, start: Instant, result: &Result<T, std::io::Error>, bytes: usize) {
+        self.metrics
+            .record(&self.db, Some(self.kind), op, start, result, bytes);
+    }
+}
+
+impl<H: DatabaseHandle> DatabaseHandle for InstrumentedHandle<H> {
+    type WalIndex = InstrumentedWalIndex<H::WalIndex>;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.size();
+        self.record(Op::Size, start, &result, 0);
+        result
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.read_exact_at(buf, offset);
+        self.record(Op::Read, start, &result, buf.len());
+        result
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.write_all_at(buf, offset);
+        self.record(Op::Write, start, &result, buf.len());
+        result
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.sync(data_only);
+        self.record(Op::Sync, start, &result, 0);
+        result
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.set_len(size);
+        self.record(Op::SetLen, start, &result, 0);
+        result
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.lock(lock);
+        self.record(Op::Lock, start, &result, 0);
+
+        match result {
+            Ok(true) => {
+                if let Some((_, since)) = self.busy_since.take() {
+                    self.record(Op::LockWait, since, &result, 0);
+                }
+            }
+            Ok(false) => {
+                self.metrics.record_busy(&self.db, self.kind, Op::Lock);
+                if !matches!(self.busy_since, Some((kind, _)) if kind == lock) {
+                    self.busy_since = Some((lock, start));
+                }
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        // SQLite gave up waiting for the lock.
+        self.busy_since = None;
+
+        let start = Instant::now();
+        let result = self.handle.unlock(lock);
+        self.record(Op::Unlock, start, &result, 0);
+        result
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        let start = Instant::now();
+        let result = self.handle.reserved();
+        self.record(Op::Reserved, start, &result, 0);
+        result
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(InstrumentedWalIndex {
+            wal_index: self.handle.wal_index(readonly)?,
+            db: self.db.clone(),
+            kind: self.kind,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+impl<W: WalIndex> WalIndex for InstrumentedWalIndex<W> {
+    fn enabled() -> bool {
+        W::enabled()
+    }
+
+    fn map(&mut self, region: u32) -> Result<[u8; 32768], std::io::Error> {
+        self.wal_index.map(region)
+    }
+
+    fn lock(&mut self, locks: Range<u8>, lock: WalIndexLock) -> Result<bool, std::io::Error> {
+        let start = Instant::now();
+        let result = self.wal_index.lock(locks, lock);
+        self.metrics.record(
+            &self.db,
+            Some(self.kind),
+            Op::WalIndexLock,
+            start,
+            &result,
+            0,
+        );
+        if let Ok(false) = result {
+            self.metrics
+                .record_busy(&self.db, self.kind, Op::WalIndexLock);
+        }
+        result
+    }
+
+    fn delete(self) -> Result<(), std::io::Error> {
+        self.wal_index.delete()
+    }
+
+    fn pull(&mut self, region: u32, data: &mut [u8; 32768]) -> Result<(), std::io::Error> {
+        self.wal_index.pull(region, data)
+    }
+
+    fn push(&mut self, region: u32, data: &[u8; 32768]) -> Result<(), std::io::Error> {
+        self.wal_index.push(region, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_rendering() {
+        let metrics = Metrics::default();
+        let start = Instant::now();
+        let result: Result<(), std::io::Error> = Ok(());
+        metrics.record(
+            "a\"b.db",
+            Some(OpenKind::Wal),
+            Op::Write,
+            start,
+            &result,
+            42,
+        );
+        metrics.record("a\"b.db", Some(OpenKind::Wal), Op::Write, start, &result, 8);
+
+        let text = metrics.snapshot().to_prometheus();
+        let labels = r#"db="a\"b.db",kind="wal",op="write""#;
+        assert!(text.contains(&format!("sqlite_vfs_calls_total{{{}}} 2\n", labels)));
+        assert!(text.contains(&format!("sqlite_vfs_bytes_total{{{}}} 50\n", labels)));
+        assert!(text.contains(&format!(
+            "sqlite_vfs_latency_seconds_bucket{{{},le=\"+Inf\"}} 2\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "sqlite_vfs_latency_seconds_count{{{}}} 2\n",
+            labels
+        )));
+    }
+}
@@ -0,0 +1,514 @@
+//! A [Vfs] that stores its files in an object store (like S3, GCS or Azure Blob Storage), with a
+//! local directory stand-in ([DirectoryStore]) to test it without a network.
+//!
+//! Each file (the main database as well as its journal or WAL) is split into fixed-size chunks,
+//! each stored as its own object under `{file}/{chunk index}`. With the chunk size matching the
+//! page size of the database, each page write of SQLite replaces exactly one object; all other
+//! writes read, patch and replace the chunks they touch. The first chunk of a file always exists
+//! (it is empty for an empty file), and chunks missing in between are read as zeros.
+//!
+//! The size of each file is cached in process memory, kept up to date by the writes of the handles
+//! of the [ObjectVfs] and refreshed whenever a handle acquires a [LockKind::Shared] lock, so that
+//! not every size lookup of SQLite lists the chunks of the file.
+//!
+//! Locks and wal indexes are managed in process memory, per [ObjectVfs] (see [LockManager] and
+//! [WalIndexManager]), so WAL mode works for all connections of a process. Additionally, a
+//! connection acquiring a [LockKind::Reserved] lock creates a `{file}.lock` object with a
+//! conditional put, so that writers in other processes are kept out. Readers in other processes are
+//! not coordinated with. The lock object stays behind if a process dies while writing, and has to
+//! be deleted manually.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::lock::{DatabaseLock, LockManager};
+use crate::wal_index::{MemWalIndex, WalIndexManager};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenOptions, Vfs};
+
+mod directory;
+
+pub use directory::DirectoryStore;
+
+/// The number of chunks written in parallel by [DatabaseHandle::write_batch].
+const PARALLEL_WRITES: usize = 16;
+
+/// The operations [ObjectVfs] needs from an object store.
+pub trait ObjectStore: Send + Sync {
+    /// Read the bytes within `range` of the object `key`. The result is shorter than the range if
+    /// the object ends before. Fails with [ErrorKind::NotFound] if the object does not exist.
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>, std::io::Error>;
+
+    /// Create or replace the object `key`. Returns the ETag of the new object.
+    fn put(&self, key: &str, data: &[u8]) -> Result<String, std::io::Error>;
+
+    /// Create or replace the object `key` if the `condition` holds. Returns the ETag of the new
+    /// object, or `None` if the condition did not hold.
+    fn put_if(
+        &self,
+        key: &str,
+        data: &[u8],
+        condition: Condition<'_>,
+    ) -> Result<Option<String>, std::io::Error>;
+
+    /// Delete the object `key`. Deleting an object that does not exist is not an error.
+    fn delete(&self, key: &str) -> Result<(), std::io::Error>;
+
+    /// List all objects whose key starts with `prefix`, sorted by their key.
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, std::io::Error>;
+}
+
+/// The condition of an [ObjectStore::put_if].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition<'a> {
+    /// The object must not exist yet.
+    NotExists,
+    /// The object must exist with the given ETag.
+    Matches(&'a str),
+}
+
+/// An object returned by [ObjectStore::list].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+}
+
+/// A [Vfs] that stores its files in an [ObjectStore].
+pub struct ObjectVfs<S> {
+    store: Arc<S>,
+    chunk_size: u64,
+    locks: LockManager,
+    wal_indexes: WalIndexManager,
+    sizes: Arc<Mutex<HashMap<String, u64>>>,
+    temp_counter: AtomicUsize,
+}
+
+/// A [DatabaseHandle] opened by [ObjectVfs].
+pub struct ObjectHandle<S: ObjectStore> {
+    store: Arc<S>,
+    db: String,
+    chunk_size: u64,
+    lock: DatabaseLock,
+    wal_indexes: WalIndexManager,
+    /// The cached sizes of the files of the [ObjectVfs].
+    sizes: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+/// The writes to a single chunk, as offsets within the chunk and the data to write there.
+type Patches<'a> = Vec<(usize, &'a [u8])>;
+
+impl<S> ObjectVfs<S> {
+    /// Store the files in `store`, split into chunks of 4096 bytes (the default page size).
+    pub fn new(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            chunk_size: 4096,
+            locks: Default::default(),
+            wal_indexes: Default::default(),
+            sizes: Default::default(),
+            temp_counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Split files into chunks of `chunk_size` bytes. Should match the page size of the database,
+    /// and must not be changed for existing files.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must not be zero");
+        self.chunk_size = chunk_size as u64;
+        self
+    }
+
+    /// The wrapped [ObjectStore].
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S: ObjectStore> ObjectVfs<S> {
+    fn handle(&self, db: &str) -> ObjectHandle<S> {
+        ObjectHandle {
+            store: self.store.clone(),
+            db: db.to_string(),
+            chunk_size: self.chunk_size,
+            lock: self.locks.lock(db),
+            wal_indexes: self.wal_indexes.clone(),
+            sizes: self.sizes.clone(),
+        }
+    }
+}
+
+impl<S: ObjectStore> ObjectHandle<S> {
+    fn chunk_key(&self, index: u64) -> String {
+        format!("{}/{:010}", self.db, index)
+    }
+
+    fn lock_key(&self) -> String {
+        format!("{}.lock", self.db)
+    }
+
+    /// The index and size of all chunks of the file, sorted by their index.
+    fn chunks(&self) -> Result<Vec<(u64, u64)>, std::io::Error> {
+        let prefix = format!("{}/", self.db);
+        Ok(self
+            .store
+            .list(&prefix)?
+            .into_iter()
+            .filter_map(|object| {
+                let index = object.key[prefix.len()..].parse().ok()?;
+                Some((index, object.size))
+            })
+            .collect())
+    }
+
+    /// List the chunks of the file to determine its size, and cache it.
+    fn refresh_size(&self) -> Result<u64, std::io::Error> {
+        let size = self
+            .chunks()?
+            .last()
+            .map(|(index, size)| index * self.chunk_size + size)
+            .unwrap_or(0);
+        self.sizes.lock().unwrap().insert(self.db.clone(), size);
+        Ok(size)
+    }
+
+    /// Extend the cached size of the file to at least `end`.
+    fn written(&self, end: u64) {
+        if let Some(size) = self.sizes.lock().unwrap().get_mut(&self.db) {
+            *size = (*size).max(end);
+        }
+    }
+
+    /// Split `len` bytes at `offset` into the chunks they span: the index of the chunk, the offset
+    /// within the chunk and the range within the `len` bytes.
+    fn split(&self, offset: u64, len: usize) -> Vec<(u64, usize, Range<usize>)> {
+        let mut parts = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let at = offset + pos as u64;
+            let within = (at % self.chunk_size) as usize;
+            let n = (self.chunk_size as usize - within).min(len - pos);
+            parts.push((at / self.chunk_size, within, pos..pos + n));
+            pos += n;
+        }
+        parts
+    }
+
+    fn get_chunk(&self, index: u64, range: Range<u64>) -> Result<Vec<u8>, std::io::Error> {
+        match self.store.get_range(&self.chunk_key(index), range) {
+            Ok(data) => Ok(data),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Apply the `patches` to the chunk `index`. The chunk is only read if it isn't replaced as a
+    /// whole.
+    fn write_chunk(&self, index: u64, patches: &[(usize, &[u8])]) -> Result<(), std::io::Error> {
+        let chunk_size = self.chunk_size as usize;
+        let replaced = patches
+            .iter()
+            .any(|(at, data)| *at == 0 && data.len() == chunk_size);
+        let mut chunk = if replaced {
+            Vec::with_capacity(chunk_size)
+        } else {
+            self.get_chunk(index, 0..self.chunk_size)?
+        };
+        for (at, data) in patches {
+            let end = at + data.len();
+            if chunk.len() < end {
+                chunk.resize(end, 0);
+            }
+            chunk[*at..end].copy_from_slice(data);
+        }
+        self.store.put(&self.chunk_key(index), &chunk)?;
+        Ok(())
+    }
+
+    /// Write the chunks, up to [PARALLEL_WRITES] at a time.
+    fn write_chunks(&self, chunks: BTreeMap<u64, Patches<'_>>) -> Result<(), std::io::Error> {
+        if chunks.len() == 1 {
+            let (index, patches) = chunks.into_iter().next().unwrap();
+            return self.write_chunk(index, &patches);
+        }
+
+        let chunks: Vec<_> = chunks.into_iter().collect();
+        for group in chunks.chunks(PARALLEL_WRITES) {
+            std::thread::scope(|scope| {
+                let writes: Vec<_> = group
+                    .iter()
+                    .map(|(index, patches)| scope.spawn(move || self.write_chunk(*index, patches)))
+                    .collect();
+                writes
+                    .into_iter()
+                    .try_for_each(|write| write.join().expect("chunk write panicked"))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn patches<'a>(&self, chunks: &mut BTreeMap<u64, Patches<'a>>, buf: &'a [u8], offset: u64) {
+        for (index, within, range) in self.split(offset, buf.len()) {
+            chunks.entry(index).or_default().push((within, &buf[range]));
+        }
+    }
+
+    /// Create the lock object, to keep out writers of other processes.
+    fn acquire_lease(&self) -> Result<bool, std::io::Error> {
+        let owner = format!("{}", std::process::id());
+        let created =
+            self.store
+                .put_if(&self.lock_key(), owner.as_bytes(), Condition::NotExists)?;
+        Ok(created.is_some())
+    }
+}
+
+impl<S: ObjectStore> Vfs for ObjectVfs<S> {
+    type Handle = ObjectHandle<S>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let handle = self.handle(db);
+        let exists = !handle.chunks()?.is_empty();
+        match (exists, opts.access) {
+            (true, OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (true, _) => {}
+            (false, OpenAccess::Create | OpenAccess::CreateNew) => {
+                self.store.put(&handle.chunk_key(0), &[])?;
+            }
+            (false, _) => return Err(ErrorKind::NotFound.into()),
+        }
+        handle.refresh_size()?;
+        Ok(handle)
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        let handle = self.handle(db);
+        let chunks = handle.chunks()?;
+        if chunks.is_empty() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        self.sizes.lock().unwrap().remove(db);
+        // delete the first chunk last, so that the file keeps existing until it is deleted entirely
+        for (index, _) in chunks.into_iter().rev() {
+            self.store.delete(&handle.chunk_key(index))?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(!self.handle(db).chunks()?.is_empty())
+    }
+
+    fn temporary_name(&self) -> String {
+        format!(
+            "etilqs_{:x}_{:x}",
+            std::process::id(),
+            self.temp_counter.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let random = RandomState::new().build_hasher().finish().to_ne_bytes();
+            for (b, r) in chunk.iter_mut().zip(random) {
+                *b = r as i8;
+            }
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        std::thread::sleep(duration);
+        duration
+    }
+}
+
+impl<S: ObjectStore> DatabaseHandle for ObjectHandle<S> {
+    type WalIndex = MemWalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        let cached = self.sizes.lock().unwrap().get(&self.db).copied();
+        match cached {
+            Some(size) => Ok(size),
+            None => self.refresh_size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let end = offset + buf.len() as u64;
+        let mut size = None;
+        for (index, within, range) in self.split(offset, buf.len()) {
+            let out = &mut buf[range];
+            let start = within as u64;
+            let data = self.get_chunk(index, start..start + out.len() as u64)?;
+            out[..data.len()].copy_from_slice(&data);
+            out[data.len()..].fill(0);
+            if data.len() < out.len() {
+                // either a gap between chunks (which reads as zeros) or the end of the file
+                let size = match size {
+                    Some(size) => size,
+                    None => *size.insert(self.size()?),
+                };
+                if size < end {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut chunks = BTreeMap::new();
+        self.patches(&mut chunks, buf, offset);
+        for (index, patches) in chunks {
+            self.write_chunk(index, &patches)?;
+        }
+        self.written(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    fn write_batch(&mut self, writes: &[(u64, &[u8])]) -> Result<(), std::io::Error> {
+        let mut chunks = BTreeMap::new();
+        for (offset, buf) in writes {
+            self.patches(&mut chunks, buf, *offset);
+        }
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        self.write_chunks(chunks)?;
+        let end = writes.iter().map(|(offset, buf)| offset + buf.len() as u64);
+        self.written(end.max().unwrap_or(0));
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        // puts are durable once they returned
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let current = self.size()?;
+        if size > current {
+            // the chunks in between are left out and read as zeros
+            return self.write_all_at(&[0], size - 1);
+        }
+        if size == current {
+            return Ok(());
+        }
+
+        for (index, len) in self.chunks()?.into_iter().rev() {
+            let start = index * self.chunk_size;
+            if start + len <= size {
+                break;
+            }
+            if start >= size && index > 0 {
+                self.store.delete(&self.chunk_key(index))?;
+            } else {
+                let data = self.get_chunk(index, 0..size - start)?;
+                self.store.put(&self.chunk_key(index), &data)?;
+            }
+        }
+        self.sizes.lock().unwrap().insert(self.db.clone(), size);
+        Ok(())
+    }
+
+    fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
+        let from = self.lock.current_lock();
+        let acquired = self.lock.lock(to);
+        let current = self.lock.current_lock();
+        if from == LockKind::None && current >= LockKind::Shared {
+            // writers of other processes might have changed the file in the meantime
+            if let Err(err) = self.refresh_size() {
+                self.lock.unlock(from);
+                return Err(err);
+            }
+        }
+        if from >= LockKind::Reserved && current < LockKind::Reserved {
+            self.store.delete(&self.lock_key())?;
+        } else if from < LockKind::Reserved && current >= LockKind::Reserved {
+            // keep writers of other processes out
+            let leased = self.acquire_lease();
+            if !matches!(leased, Ok(true)) {
+                self.lock.unlock(from);
+                return leased;
+            }
+        }
+        Ok(acquired)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        if self.lock.reserved() {
+            return Ok(true);
+        }
+        // a writer of another process
+        Ok(!self.store.list(&self.lock_key())?.is_empty())
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock.current_lock())
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(self.wal_indexes.wal_index(&self.db))
+    }
+}
+
+impl<S: ObjectStore> Drop for ObjectHandle<S> {
+    fn drop(&mut self) {
+        if let Err(err) = self.lock(LockKind::None) {
+            log::error!("failed to release lock on close: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpenKind;
+
+    #[test]
+    fn test_chunks() {
+        let dir = std::env::temp_dir().join(format!("sqlite-vfs-chunks-{}", std::process::id()));
+        let vfs = ObjectVfs::new(DirectoryStore::new(&dir).unwrap()).with_chunk_size(8);
+        let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Create);
+        let mut handle = vfs.open("file", opts).unwrap();
+        assert!(vfs.exists("file").unwrap());
+        assert_eq!(handle.size().unwrap(), 0);
+
+        // spans several chunks and leaves a gap
+        handle
+            .write_batch(&[(2, &[1; 10]), (30, &[2; 4]), (12, &[3; 2])])
+            .unwrap();
+        assert_eq!(handle.size().unwrap(), 34);
+        let mut buf = [9; 34];
+        handle.read_exact_at(&mut buf, 0).unwrap();
+        let mut expected = vec![0; 2];
+        expected.extend([1; 10]);
+        expected.extend([3; 2]);
+        expected.extend([0; 16]);
+        expected.extend([2; 4]);
+        assert_eq!(buf.as_slice(), expected);
+
+        let mut buf = [9; 8];
+        let err = handle.read_exact_at(&mut buf, 30).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(buf, [2, 2, 2, 2, 0, 0, 0, 0]);
+
+        handle.set_len(5).unwrap();
+        assert_eq!(handle.size().unwrap(), 5);
+        handle.set_len(20).unwrap();
+        let mut buf = [9; 20];
+        handle.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..5], &[0, 0, 1, 1, 1]);
+        assert_eq!(&buf[5..], &[0; 15]);
+
+        drop(handle);
+        vfs.delete("file").unwrap();
+        assert!(!vfs.exists("file").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
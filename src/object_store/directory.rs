@@ -0,0 +1,256 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::{Condition, ObjectMeta, ObjectStore};
+
+/// Numbers the temporary files of all [DirectoryStore]s of the process, so that stores on the same
+/// root don't overwrite each other's temporary files.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes the conditional puts of all [DirectoryStore]s of the process.
+static CONDITIONAL: Mutex<()> = Mutex::new(());
+
+/// An [ObjectStore] that stores each object as a file in a local directory, with the `/`
+/// separated segments of its key as sub-directories.
+///
+/// Objects are replaced atomically (written to a temporary file in `.tmp/`, which is then renamed).
+/// [Condition::NotExists] holds across processes; [Condition::Matches] only within the process.
+/// A key can't be both an object and the prefix of other objects (followed by a `/`), as it can't
+/// be both a file and a directory.
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    /// Store the objects in the directory `root`, which is created if it does not exist yet.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let root = root.into();
+        fs::create_dir_all(root.join(".tmp"))?;
+        Ok(Self { root })
+    }
+
+    /// The directory the objects are stored in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, std::io::Error> {
+        let mut path = self.root.clone();
+        for segment in key.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid object key: {}", key),
+                ));
+            }
+            path.push(segment);
+        }
+        if path == self.root {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "empty object key",
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Write `data` to a new temporary file.
+    fn temporary(&self, data: &[u8]) -> Result<PathBuf, std::io::Error> {
+        let path = self.root.join(".tmp").join(format!(
+            "{:x}_{:x}",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::options().write(true).create_new(true).open(&path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(path)
+    }
+
+    fn list_dir(
+        &self,
+        dir: &Path,
+        key: &str,
+        prefix: &str,
+        objects: &mut Vec<ObjectMeta>,
+    ) -> Result<(), std::io::Error> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if key.is_empty() && name == ".tmp" {
+                continue;
+            }
+            let key = format!("{}{}", key, name);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                let key = format!("{}/", key);
+                if key.starts_with(prefix) || prefix.starts_with(&key) {
+                    self.list_dir(&entry.path(), &key, prefix, objects)?;
+                }
+            } else if key.starts_with(prefix) {
+                objects.push(ObjectMeta {
+                    key,
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ObjectStore for DirectoryStore {
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>, std::io::Error> {
+        let mut file = File::open(self.path(key)?)?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut data = Vec::with_capacity(range.end.saturating_sub(range.start) as usize);
+        file.take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<String, std::io::Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary = self.temporary(data)?;
+        fs::rename(temporary, path)?;
+        Ok(etag(data))
+    }
+
+    fn put_if(
+        &self,
+        key: &str,
+        data: &[u8],
+        condition: Condition<'_>,
+    ) -> Result<Option<String>, std::io::Error> {
+        let path = self.path(key)?;
+        let _guard = CONDITIONAL.lock().unwrap();
+        match condition {
+            Condition::NotExists => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // linking fails if the object exists, atomically
+                let temporary = self.temporary(data)?;
+                let result = fs::hard_link(&temporary, &path);
+                fs::remove_file(temporary)?;
+                match result {
+                    Ok(()) => Ok(Some(etag(data))),
+                    Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+            Condition::Matches(expected) => {
+                let current = match fs::read(&path) {
+                    Ok(current) => current,
+                    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err),
+                };
+                if etag(&current) != expected {
+                    return Ok(None);
+                }
+                self.put(key, data).map(Some)
+            }
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), std::io::Error> {
+        match fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, std::io::Error> {
+        let mut objects = Vec::new();
+        self.list_dir(&self.root, "", prefix, &mut objects)?;
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+/// The ETag of an object: the FNV-1a hash of its content.
+fn etag(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_store() {
+        let root = std::env::temp_dir().join(format!("sqlite-vfs-store-{}", std::process::id()));
+        let store = DirectoryStore::new(&root).unwrap();
+
+        let etag = store.put("a/b/1", b"hello world").unwrap();
+        store.put("a/b/2", b"").unwrap();
+        store.put("a/c", b"!").unwrap();
+        assert_eq!(store.get_range("a/b/1", 6..100).unwrap(), b"world");
+        assert_eq!(
+            store.get_range("a/x", 0..1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let keys = |prefix| -> Vec<String> {
+            let objects = store.list(prefix).unwrap();
+            objects.into_iter().map(|object| object.key).collect()
+        };
+        assert_eq!(keys("a/b/"), ["a/b/1", "a/b/2"]);
+        assert_eq!(keys("a/"), ["a/b/1", "a/b/2", "a/c"]);
+        assert_eq!(keys("a/c"), ["a/c"]);
+        assert_eq!(store.list("a/b/1").unwrap()[0].size, 11);
+
+        assert!(store
+            .put_if("a/c", b"?", Condition::NotExists)
+            .unwrap()
+            .is_none());
+        assert!(store
+            .put_if("a/d", b"?", Condition::NotExists)
+            .unwrap()
+            .is_some());
+        assert!(store
+            .put_if("a/b/1", b"bye", Condition::Matches("0"))
+            .unwrap()
+            .is_none());
+        assert!(store
+            .put_if("a/b/1", b"bye", Condition::Matches(&etag))
+            .unwrap()
+            .is_some());
+        assert_eq!(store.get_range("a/b/1", 0..100).unwrap(), b"bye");
+
+        store.delete("a/b/1").unwrap();
+        store.delete("a/b/1").unwrap();
+        assert_eq!(keys("a/b"), ["a/b/2"]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_stores_on_the_same_root() {
+        let root = std::env::temp_dir().join(format!("sqlite-vfs-stores-{}", std::process::id()));
+        let a = DirectoryStore::new(&root).unwrap();
+        let b = DirectoryStore::new(&root).unwrap();
+
+        let first = a.temporary(b"a").unwrap();
+        let second = b.temporary(b"b").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read(&first).unwrap(), b"a");
+        assert_eq!(fs::read(&second).unwrap(), b"b");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
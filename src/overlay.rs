@@ -0,0 +1,665 @@
+//! A copy-on-write overlay over a read-only base database, e.g. to give each test or tenant a
+//! writable view of a large golden database without copying it.
+//!
+//! Pages are read from the base [Vfs] until they get modified; modified pages are stored in an
+//! upper layer, which is another [Vfs] (like the in-memory [MemoryVfs], or one that is backed by
+//! files to keep the overlay across restarts). For each database, the upper layer holds:
+//! - `{db}`: the modified pages, at their offsets in the database (i.e. a sparse file),
+//! - `{db}-overlay`: the size of the database and a bitmap of the modified pages.
+//!
+//! Journals, WALs and temporary files live in the upper layer only, as does locking (so multiple
+//! overlays over the same base don't block each other). The handles of a database opened through
+//! the same [OverlayVfs] (or its clones) share the map of modified pages in memory, so that e.g. a
+//! checkpoint of one connection in WAL mode (which doesn't release its lock) is visible to the
+//! others right away. The base must not be changed while
+//! overlays are in use. Its journals and WALs are ignored, so it must be a consistent database
+//! (e.g. checkpointed, without hot journal).
+//!
+//! An overlay can be discarded with [OverlayVfs::discard], or squashed into a new base with
+//! [OverlayVfs::squash]. Clones of an [OverlayVfs] share their layers, so keep one to do that after
+//! the VFS got registered.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+pub use crate::memory::{MemoryHandle, MemoryVfs};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+/// The size of the header of the `-overlay` file, followed by the bitmap of modified pages.
+const MAP_HEADER_SIZE: usize = 24;
+
+/// A [Vfs] that overlays the databases of a read-only base [Vfs] with the modified pages stored in
+/// an upper [Vfs].
+pub struct OverlayVfs<B, U> {
+    base: Arc<B>,
+    upper: Arc<U>,
+    page_size: usize,
+    /// The maps of all open databases, shared between their handles.
+    maps: Arc<Mutex<HashMap<String, Weak<Mutex<Map>>>>>,
+}
+
+/// A [DatabaseHandle] opened by [OverlayVfs].
+pub struct OverlayHandle<B: Vfs, U: Vfs> {
+    file: File<B::Handle, U::Handle>,
+}
+
+enum File<B, U> {
+    /// Journals, WALs and temporary files are opened from the upper layer directly.
+    Upper(U),
+    Overlay(Overlay<B, U>),
+}
+
+struct Overlay<B, U> {
+    /// `None` if the database doesn't exist in the base.
+    base: Option<B>,
+    data: U,
+    map_file: U,
+    map: Arc<Mutex<Map>>,
+}
+
+/// The content of the `-overlay` file.
+#[derive(Debug, PartialEq)]
+struct Map {
+    page_size: u64,
+    /// The size of the database.
+    size: u64,
+    /// The base is only read below this offset (it shrinks when the database is truncated).
+    base_limit: u64,
+    /// A bit for each page, set if the page is stored in the upper layer.
+    pages: Vec<u8>,
+    /// Whether the map changed since it got written to the `-overlay` file.
+    dirty: bool,
+}
+
+impl<B, U> OverlayVfs<B, U> {
+    /// Overlay the databases of `base` with the modified pages stored in `upper`, copying pages of
+    /// 4096 bytes (the default page size) on their first modification.
+    pub fn new(base: B, upper: U) -> Self {
+        Self {
+            base: Arc::new(base),
+            upper: Arc::new(upper),
+            page_size: 4096,
+            maps: Default::default(),
+        }
+    }
+
+    /// Copy pages of `page_size` bytes on their first modification. Should match the page size of
+    /// the database, and must not be changed for existing overlays.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        assert!(page_size > 0, "page size must not be zero");
+        self.page_size = page_size;
+        self
+    }
+}
+
+impl<B, U> Clone for OverlayVfs<B, U> {
+    fn clone(&self) -> Self {
+        Self {
+            base: self.base.clone(),
+            upper: self.upper.clone(),
+            page_size: self.page_size,
+            maps: self.maps.clone(),
+        }
+    }
+}
+
+impl<B: Vfs + Send, U: Vfs + Send> OverlayVfs<B, U> {
+    /// Delete the overlay of `db` (as well as its journal and WAL), so that it reads as the base
+    /// again. The database must not be open.
+    pub fn discard(&self, db: &str) -> Result<(), std::io::Error> {
+        for name in [
+            map_name(db),
+            db.to_string(),
+            format!("{}-journal", db),
+            format!("{}-wal", db),
+        ] {
+            if self.upper.exists(&name)? {
+                self.upper.delete(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the database `db` as seen through the overlay to the database `name` of `target`, e.g.
+    /// to use it as a new base. Holds a [LockKind::Shared] lock on `db` while copying it.
+    pub fn squash<V: Vfs>(&self, db: &str, target: &V, name: &str) -> Result<(), std::io::Error> {
+        let mut handle = self.open(db, open_options(OpenAccess::Read))?;
+        if !handle.lock(LockKind::Shared)? {
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                format!("{} is locked", db),
+            ));
+        }
+
+        let result = (|| {
+            let size = handle.size()?;
+            let mut out = target.open(name, open_options(OpenAccess::Create))?;
+            let mut buf = vec![0; self.page_size];
+            let mut offset = 0;
+            while offset < size {
+                let n = (size - offset).min(self.page_size as u64) as usize;
+                handle.read_exact_at(&mut buf[..n], offset)?;
+                out.write_all_at(&buf[..n], offset)?;
+                offset += n as u64;
+            }
+            out.set_len(size)?;
+            out.sync(false)
+        })();
+        handle.unlock(LockKind::None)?;
+        result
+    }
+}
+
+impl Map {
+    fn new(page_size: u64, size: u64) -> Self {
+        Self {
+            page_size,
+            size,
+            base_limit: size,
+            pages: Vec::new(),
+            dirty: true,
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, std::io::Error> {
+        if data.len() < MAP_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "overlay map is too short",
+            ));
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+        Ok(Self {
+            page_size: u64_at(0),
+            size: u64_at(8),
+            base_limit: u64_at(16),
+            pages: data[MAP_HEADER_SIZE..].to_vec(),
+            dirty: false,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MAP_HEADER_SIZE + self.pages.len());
+        data.extend_from_slice(&self.page_size.to_be_bytes());
+        data.extend_from_slice(&self.size.to_be_bytes());
+        data.extend_from_slice(&self.base_limit.to_be_bytes());
+        data.extend_from_slice(&self.pages);
+        data
+    }
+
+    fn modified(&self, page: u64) -> bool {
+        let (byte, bit) = ((page / 8) as usize, page % 8);
+        self.pages
+            .get(byte)
+            .is_some_and(|byte| byte & (1 << bit) != 0)
+    }
+
+    fn set_modified(&mut self, page: u64) {
+        let (byte, bit) = ((page / 8) as usize, page % 8);
+        if self.pages.len() <= byte {
+            self.pages.resize(byte + 1, 0);
+        }
+        self.pages[byte] |= 1 << bit;
+        self.dirty = true;
+    }
+
+    fn truncate(&mut self, size: u64) {
+        // pages (partially) below the new size stay modified
+        let pages = size.div_ceil(self.page_size);
+        self.pages.truncate(pages.div_ceil(8) as usize);
+        if let Some(last) = self.pages.last_mut() {
+            if !pages.is_multiple_of(8) {
+                *last &= (1 << (pages % 8)) - 1;
+            }
+        }
+        self.size = size;
+        self.base_limit = self.base_limit.min(size);
+        self.dirty = true;
+    }
+}
+
+impl<B: DatabaseHandle, U: DatabaseHandle> Overlay<B, U> {
+    fn map(&self) -> MutexGuard<'_, Map> {
+        self.map.lock().unwrap()
+    }
+
+    fn load_map(&mut self) -> Result<(), std::io::Error> {
+        let mut data = vec![0; self.map_file.size()? as usize];
+        self.map_file.read_exact_at(&mut data, 0)?;
+        let map = Map::decode(&data)?;
+        let mut current = self.map();
+        if map.page_size != current.page_size {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "overlay got created with a page size of {} (instead of {})",
+                    map.page_size, current.page_size
+                ),
+            ));
+        }
+        *current = map;
+        Ok(())
+    }
+
+    fn write_map(&mut self) -> Result<(), std::io::Error> {
+        let map = self.map.clone();
+        let mut map = map.lock().unwrap();
+        if !map.dirty {
+            return Ok(());
+        }
+        let data = map.encode();
+        self.map_file.write_all_at(&data, 0)?;
+        self.map_file.set_len(data.len() as u64)?;
+        map.dirty = false;
+        Ok(())
+    }
+
+    /// Read `buf` from the base at `offset`, with zeros at and beyond `base_limit`.
+    fn read_base(
+        &mut self,
+        buf: &mut [u8],
+        offset: u64,
+        base_limit: u64,
+    ) -> Result<(), std::io::Error> {
+        let n = base_limit.saturating_sub(offset).min(buf.len() as u64) as usize;
+        buf[n..].fill(0);
+        match &mut self.base {
+            Some(base) if n > 0 => base.read_exact_at(&mut buf[..n], offset),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read `buf` from the upper layer at `offset`, with zeros beyond its end.
+    fn read_upper(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        match self.data.read_exact_at(buf, offset) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                let n = self
+                    .data
+                    .size()?
+                    .saturating_sub(offset)
+                    .min(buf.len() as u64) as usize;
+                buf[n..].fill(0);
+                if n > 0 {
+                    self.data.read_exact_at(&mut buf[..n], offset)?;
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let map = self.map.clone();
+        let map = map.lock().unwrap();
+        let page_size = map.page_size;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let at = offset + pos as u64;
+            let page = at / page_size;
+            let n = ((page + 1) * page_size - at).min((buf.len() - pos) as u64) as usize;
+            let out = &mut buf[pos..pos + n];
+            if map.modified(page) {
+                self.read_upper(out, at)?;
+            } else {
+                self.read_base(out, at, map.base_limit)?;
+            }
+            pos += n;
+        }
+
+        let end = offset + buf.len() as u64;
+        if end > map.size {
+            let from = map.size.saturating_sub(offset) as usize;
+            buf[from..].fill(0);
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let map = self.map.clone();
+        let mut map = map.lock().unwrap();
+        let page_size = map.page_size;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let at = offset + pos as u64;
+            let page = at / page_size;
+            let start = page * page_size;
+            let n = (start + page_size - at).min((buf.len() - pos) as u64) as usize;
+            let data = &buf[pos..pos + n];
+            if map.modified(page) || n as u64 == page_size {
+                self.data.write_all_at(data, at)?;
+            } else {
+                // copy the page to the upper layer on its first modification
+                let mut copy = vec![0; page_size as usize];
+                self.read_base(&mut copy, start, map.base_limit)?;
+                let within = (at - start) as usize;
+                copy[within..within + n].copy_from_slice(data);
+                self.data.write_all_at(&copy, start)?;
+            }
+            map.set_modified(page);
+            pos += n;
+        }
+
+        let end = offset + buf.len() as u64;
+        if end > map.size {
+            map.size = end;
+            map.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let map = self.map.clone();
+        let mut map = map.lock().unwrap();
+        if size < map.size {
+            map.truncate(size);
+            self.data.set_len(size.min(self.data.size()?))?;
+        } else if size > map.size {
+            map.size = size;
+            map.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Vfs + Send, U: Vfs + Send> Vfs for OverlayVfs<B, U> {
+    type Handle = OverlayHandle<B, U>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(OverlayHandle {
+                file: File::Upper(self.upper.open(db, opts)?),
+            });
+        }
+
+        let map_name = map_name(db);
+        let exists = self.upper.exists(&map_name)?;
+        let base = match self.base.open(db, with_access(&opts, OpenAccess::Read)) {
+            Ok(base) => Some(base),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        match (exists || base.is_some(), opts.access) {
+            (true, OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (false, OpenAccess::Read | OpenAccess::Write) => return Err(ErrorKind::NotFound.into()),
+            _ => {}
+        }
+
+        let create = with_access(&opts, OpenAccess::Create);
+        let mut maps = self.maps.lock().unwrap();
+        let shared = maps.get(db).and_then(Weak::upgrade);
+        let mut overlay = Overlay {
+            data: self.upper.open(db, create.clone())?,
+            map_file: self.upper.open(&map_name, create)?,
+            map: shared
+                .clone()
+                .unwrap_or_else(|| Arc::new(Mutex::new(Map::new(self.page_size as u64, 0)))),
+            base,
+        };
+        if shared.is_none() {
+            if exists && overlay.map_file.size()? > 0 {
+                overlay.load_map()?;
+            } else {
+                let size = match &overlay.base {
+                    Some(base) => base.size()?,
+                    None => 0,
+                };
+                *overlay.map() = Map::new(self.page_size as u64, size);
+                overlay.write_map()?;
+                overlay.map_file.sync(false)?;
+            }
+            maps.retain(|_, map| map.strong_count() > 0);
+            maps.insert(db.to_string(), Arc::downgrade(&overlay.map));
+        }
+
+        Ok(OverlayHandle {
+            file: File::Overlay(overlay),
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.upper.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        if self.upper.exists(db)? {
+            return Ok(true);
+        }
+        // the journals and WALs of the base are ignored
+        let auxiliary = db.ends_with("-journal") || db.ends_with("-wal");
+        Ok(!auxiliary && self.base.exists(db)?)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.upper.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.upper.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.upper.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        if write {
+            self.upper.access(db, write)
+        } else {
+            self.exists(db)
+        }
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.upper.full_pathname(db)
+    }
+}
+
+impl<B: Vfs, U: Vfs> OverlayHandle<B, U> {
+    /// The handle of the upper layer that holds the locks (and the data of files that aren't
+    /// overlaid).
+    fn upper(&self) -> &U::Handle {
+        match &self.file {
+            File::Upper(upper) => upper,
+            File::Overlay(overlay) => &overlay.data,
+        }
+    }
+
+    fn upper_mut(&mut self) -> &mut U::Handle {
+        match &mut self.file {
+            File::Upper(upper) => upper,
+            File::Overlay(overlay) => &mut overlay.data,
+        }
+    }
+}
+
+impl<B: Vfs, U: Vfs> DatabaseHandle for OverlayHandle<B, U> {
+    type WalIndex = <U::Handle as DatabaseHandle>::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.file {
+            File::Upper(upper) => upper.size(),
+            File::Overlay(overlay) => Ok(overlay.map().size),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Upper(upper) => upper.read_exact_at(buf, offset),
+            File::Overlay(overlay) => overlay.read_exact_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Upper(upper) => upper.write_all_at(buf, offset),
+            File::Overlay(overlay) => overlay.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Upper(upper) => upper.sync(data_only),
+            File::Overlay(overlay) => {
+                // the pages must be durable before the map refers to them
+                overlay.data.sync(data_only)?;
+                if overlay.map().dirty {
+                    overlay.write_map()?;
+                    overlay.map_file.sync(data_only)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Upper(upper) => upper.set_len(size),
+            File::Overlay(overlay) => overlay.set_len(size),
+        }
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let from = self.upper().current_lock()?;
+        let overlay = match &mut self.file {
+            File::Upper(upper) => return upper.lock(lock),
+            File::Overlay(overlay) => overlay,
+        };
+        if !overlay.data.lock(lock)? {
+            return Ok(false);
+        }
+        if from == LockKind::None && lock == LockKind::Shared && !overlay.map().dirty {
+            // another process might have changed the database in the meantime (connections of
+            // this process share the map, and its unwritten changes are the latest)
+            overlay.load_map()?;
+        }
+        Ok(true)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        let from = self.upper().current_lock()?;
+        if let File::Overlay(overlay) = &mut self.file {
+            if lock < from && lock < LockKind::Reserved {
+                // other connections might read the database afterwards
+                overlay.write_map()?;
+            }
+        }
+        self.upper_mut().unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.upper_mut().reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.upper().current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.upper().set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.upper().moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.upper().change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.upper_mut().pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.upper_mut().checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.upper_mut().lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.upper_mut().set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.upper().immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.upper().powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.upper().sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.upper().wal_index(readonly)
+    }
+}
+
+impl<B: Vfs, U: Vfs> Drop for OverlayHandle<B, U> {
+    fn drop(&mut self) {
+        if let File::Overlay(overlay) = &mut self.file {
+            if let Err(err) = overlay.write_map() {
+                log::error!("failed to write overlay map on close: {}", err);
+            }
+        }
+    }
+}
+
+fn map_name(db: &str) -> String {
+    format!("{}-overlay", db)
+}
+
+fn with_access(opts: &OpenOptions, access: OpenAccess) -> OpenOptions {
+    let mut opts = opts.clone();
+    opts.access = access;
+    opts
+}
+
+fn open_options(access: OpenAccess) -> OpenOptions {
+    OpenOptions::new(OpenKind::MainDb, access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let mut map = Map::new(4096, 10 * 4096);
+        for page in [0, 3, 9, 17] {
+            map.set_modified(page);
+        }
+        assert!(map.modified(3));
+        assert!(!map.modified(4));
+
+        let mut decoded = Map::decode(&map.encode()).unwrap();
+        decoded.dirty = true;
+        assert_eq!(decoded, map);
+
+        // truncating within page 9 keeps it
+        map.truncate(9 * 4096 + 1);
+        assert!(map.modified(9));
+        assert!(!map.modified(17));
+        map.truncate(4096);
+        assert!(map.modified(0));
+        assert!(!map.modified(3));
+        assert_eq!(map.base_limit, 4096);
+    }
+}
@@ -0,0 +1,535 @@
+//! A [Vfs] adapter that streams the committed frames of WAL-mode databases to a [WalObserver], e.g.
+//! to continuously back them up off-site.
+//!
+//! ```ignore
+//! let sink = FileSink::new("/backups")?;
+//! register("replicated", ReplicatedVfs::new(vfs, sink), false)?;
+//! ```
+//!
+//! Once a WAL ([OpenKind::Wal]) got synced, the frames that were not passed to the observer yet
+//! are read back from it (no matter which connection wrote them), and those up to the last commit
+//! frame are passed to the observer, together with the header of the WAL (its salts tell the WAL
+//! generations apart, its checksums seed those of the frames). As SQLite only syncs the WAL on
+//! commit with `PRAGMA synchronous = FULL` (otherwise only before checkpoints), the observer lags
+//! behind accordingly. Frames that the observer fails to take are passed again after the next
+//! sync. Before a WAL is restarted (its header is rewritten), truncated or deleted (e.g. when the
+//! last connection closes, which with `PRAGMA synchronous = OFF` is the first time the frames are
+//! passed at all), the remaining committed frames are passed as well; if the observer fails to
+//! take those, the restart, truncation or deletion fails instead of losing them.
+//!
+//! [FileSink] archives the frames in files that are valid WALs, so that the database can be
+//! restored from a copy of the database file and the archive, up to any archived transaction (see
+//! [WalArchive]). A [FollowerVfs] serves a read-only
+//! replica that applies the frames streamed from a primary, e.g. through an [std::sync::mpsc]
+//! channel (whose [Sender](std::sync::mpsc::Sender) is a [WalObserver] and whose
+//! [Receiver](std::sync::mpsc::Receiver) is a [FrameSource]).
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+mod file;
+mod follower;
+mod restore;
+
+pub use file::FileSink;
+pub use follower::{FollowerHandle, FollowerVfs, FrameSource, WalBatch};
+pub use restore::{ArchivedCommit, RestorePoint, WalArchive};
+
+use restore::checksum;
+
+/// The size of the WAL header.
+pub const WAL_HEADER_SIZE: usize = 32;
+
+/// The size of the header of each WAL frame, followed by the page.
+pub const WAL_FRAME_HEADER_SIZE: usize = 24;
+
+/// The magic number of WALs with checksums computed on little-endian words; it is one higher for
+/// big-endian ones.
+const WAL_MAGIC: u32 = 0x377f0682;
+
+/// Observes the frames committed to WALs.
+pub trait WalObserver: Send + Sync {
+    /// Called after the WAL of `db` got synced (or before it gets restarted, truncated or deleted),
+    /// with its current header and the frames committed since the last call. Returning an error
+    /// passes the same frames again on the next call.
+    fn committed(
+        &self,
+        db: &str,
+        header: &WalHeader,
+        frames: &[WalFrame],
+    ) -> Result<(), std::io::Error>;
+}
+
+/// The header of a WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub page_size: u32,
+    pub checkpoint_seq: u32,
+    pub salts: [u32; 2],
+    pub checksums: [u32; 2],
+}
+
+/// A frame of a WAL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalFrame {
+    /// The position of the frame in the WAL, starting at zero.
+    pub index: u32,
+    /// The database page the frame contains (starting at one).
+    pub page: u32,
+    /// For commit frames, the size of the database in pages after the commit; zero otherwise.
+    pub commit_size: u32,
+    pub salts: [u32; 2],
+    pub checksums: [u32; 2],
+    pub data: Vec<u8>,
+}
+
+/// A [Vfs] that passes the committed WAL frames of its databases to a [WalObserver].
+pub struct ReplicatedVfs<V, O> {
+    vfs: V,
+    observer: Arc<O>,
+    positions: Arc<Mutex<HashMap<String, Position>>>,
+}
+
+/// A [DatabaseHandle] opened by [ReplicatedVfs].
+pub struct ReplicatedHandle<H, O> {
+    handle: H,
+    /// Only set for WALs.
+    wal: Option<Wal<O>>,
+}
+
+struct Wal<O> {
+    db: String,
+    observer: Arc<O>,
+    /// The frames passed to the observer so far, per database (shared by all handles).
+    positions: Arc<Mutex<HashMap<String, Position>>>,
+}
+
+/// The end of the frames of a WAL generation that got passed to the observer.
+#[derive(Clone, Copy)]
+struct Position {
+    salts: [u32; 2],
+    /// The number of frames passed.
+    frames: u32,
+    /// The checksums of the last frame passed, which seed those of the next one.
+    checksums: [u32; 2],
+}
+
+impl WalHeader {
+    pub fn decode(data: &[u8]) -> Result<Self, std::io::Error> {
+        if data.len() < WAL_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "incomplete WAL header",
+            ));
+        }
+        let header = WalHeader {
+            magic: u32_at(data, 0),
+            version: u32_at(data, 4),
+            page_size: u32_at(data, 8),
+            checkpoint_seq: u32_at(data, 12),
+            salts: [u32_at(data, 16), u32_at(data, 20)],
+            checksums: [u32_at(data, 24), u32_at(data, 28)],
+        };
+        if header.magic & !1 != WAL_MAGIC || header.page_size == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid WAL header",
+            ));
+        }
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> [u8; WAL_HEADER_SIZE] {
+        let mut data = [0; WAL_HEADER_SIZE];
+        for (i, value) in [
+            self.magic,
+            self.version,
+            self.page_size,
+            self.checkpoint_seq,
+            self.salts[0],
+            self.salts[1],
+            self.checksums[0],
+            self.checksums[1],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            data[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        data
+    }
+
+    /// The size of each frame (its header and page).
+    pub fn frame_size(&self) -> u64 {
+        WAL_FRAME_HEADER_SIZE as u64 + self.page_size as u64
+    }
+
+    /// The offset of the frame `index` in the WAL.
+    pub fn frame_offset(&self, index: u32) -> u64 {
+        WAL_HEADER_SIZE as u64 + index as u64 * self.frame_size()
+    }
+}
+
+impl WalFrame {
+    /// Decode the frame `index` from its header followed by its page.
+    pub fn decode(index: u32, data: &[u8]) -> Result<Self, std::io::Error> {
+        if data.len() <= WAL_FRAME_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "incomplete WAL frame",
+            ));
+        }
+        Ok(WalFrame {
+            index,
+            page: u32_at(data, 0),
+            commit_size: u32_at(data, 4),
+            salts: [u32_at(data, 8), u32_at(data, 12)],
+            checksums: [u32_at(data, 16), u32_at(data, 20)],
+            data: data[WAL_FRAME_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// The header of the frame, followed by its page.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(WAL_FRAME_HEADER_SIZE + self.data.len());
+        for value in [
+            self.page,
+            self.commit_size,
+            self.salts[0],
+            self.salts[1],
+            self.checksums[0],
+            self.checksums[1],
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    /// Whether the frame is the last one of a transaction.
+    pub fn is_commit(&self) -> bool {
+        self.commit_size > 0
+    }
+}
+
+impl<V, O> ReplicatedVfs<V, O> {
+    /// Pass the committed WAL frames of the databases of `vfs` to `observer`.
+    pub fn new(vfs: V, observer: O) -> Self {
+        Self {
+            vfs,
+            observer: Arc::new(observer),
+            positions: Default::default(),
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+}
+
+impl<O: WalObserver> WalObserver for Arc<O> {
+    fn committed(
+        &self,
+        db: &str,
+        header: &WalHeader,
+        frames: &[WalFrame],
+    ) -> Result<(), std::io::Error> {
+        (**self).committed(db, header, frames)
+    }
+}
+
+impl<O: WalObserver> Wal<O> {
+    /// Read the committed frames of the WAL that were not passed to the observer yet back from
+    /// `handle`, and pass them to the observer.
+    fn ship<H: DatabaseHandle>(&self, handle: &mut H) -> Result<(), std::io::Error> {
+        let mut positions = self.positions.lock().unwrap();
+        let size = handle.size()?;
+        if size < WAL_HEADER_SIZE as u64 {
+            return Ok(());
+        }
+        let mut data = [0; WAL_HEADER_SIZE];
+        handle.read_exact_at(&mut data, 0)?;
+        let header = match WalHeader::decode(&data) {
+            Ok(header) => header,
+            // not a valid WAL (yet)
+            Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let big_endian = header.magic & 1 == 1;
+        if checksum(big_endian, &data[..24], [0, 0]) != header.checksums {
+            return Ok(());
+        }
+
+        let start = match positions.get(&self.db) {
+            Some(position) if position.salts == header.salts => *position,
+            _ => Position {
+                salts: header.salts,
+                frames: 0,
+                checksums: header.checksums,
+            },
+        };
+        let mut sum = start.checksums;
+        let mut frames = Vec::new();
+        let mut committed = (0, sum);
+        let mut data = vec![0; header.frame_size() as usize];
+        for index in start.frames.. {
+            let offset = header.frame_offset(index);
+            if offset + header.frame_size() > size {
+                break;
+            }
+            handle.read_exact_at(&mut data, offset)?;
+            let frame = WalFrame::decode(index, &data)?;
+            sum = checksum(big_endian, &data[..8], sum);
+            sum = checksum(big_endian, &data[WAL_FRAME_HEADER_SIZE..], sum);
+            // frames of previous generations (or torn ones) follow those of the current one
+            if frame.salts != header.salts || frame.checksums != sum {
+                break;
+            }
+            let is_commit = frame.is_commit();
+            frames.push(frame);
+            if is_commit {
+                committed = (frames.len(), sum);
+            }
+        }
+
+        frames.truncate(committed.0);
+        if frames.is_empty() {
+            return Ok(());
+        }
+        self.observer.committed(&self.db, &header, &frames)?;
+        positions.insert(
+            self.db.clone(),
+            Position {
+                salts: header.salts,
+                frames: start.frames + frames.len() as u32,
+                checksums: committed.1,
+            },
+        );
+        Ok(())
+    }
+}
+
+impl<V: Vfs, O: WalObserver + 'static> Vfs for ReplicatedVfs<V, O> {
+    type Handle = ReplicatedHandle<V::Handle, O>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let wal = match opts.kind {
+            OpenKind::Wal => Some(Wal {
+                db: opts.database.as_deref().unwrap_or(db).to_string(),
+                observer: self.observer.clone(),
+                positions: self.positions.clone(),
+            }),
+            _ => None,
+        };
+        Ok(ReplicatedHandle {
+            handle: self.vfs.open(db, opts)?,
+            wal,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        if let Some(database) = db.strip_suffix("-wal") {
+            // pass the remaining committed frames before they are gone
+            let mut handle = match self
+                .vfs
+                .open(db, OpenOptions::new(OpenKind::Wal, OpenAccess::Read))
+            {
+                Ok(handle) => handle,
+                Err(err) if err.kind() == ErrorKind::NotFound => return self.vfs.delete(db),
+                Err(err) => return Err(err),
+            };
+            let wal = Wal {
+                db: database.to_string(),
+                observer: self.observer.clone(),
+                positions: self.positions.clone(),
+            };
+            wal.ship(&mut handle)?;
+            drop(handle);
+            self.vfs.delete(db)?;
+            self.positions.lock().unwrap().remove(database);
+            return Ok(());
+        }
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.vfs.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<std::borrow::Cow<'a, str>, std::io::Error> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+impl<H: DatabaseHandle, O: WalObserver + 'static> DatabaseHandle for ReplicatedHandle<H, O> {
+    type WalIndex = H::WalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.handle.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.handle.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        if let Some(wal) = &self.wal {
+            if offset == 0 {
+                // the WAL gets restarted, the frames of the previous generation are overwritten
+                wal.ship(&mut self.handle)?;
+            }
+        }
+        self.handle.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.handle.sync(data_only)?;
+        if let Some(wal) = &self.wal {
+            if let Err(err) = wal.ship(&mut self.handle) {
+                // try again after the next sync
+                log::error!(
+                    "failed to pass WAL frames of {} to observer: {}",
+                    wal.db,
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        if let Some(wal) = &self.wal {
+            if size < self.handle.size()? {
+                wal.ship(&mut self.handle)?;
+            }
+        }
+        self.handle.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.lock(lock)
+    }
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.handle.unlock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.handle.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.handle.current_lock()
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.handle.set_chunk_size(chunk_size)
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        self.handle.moved()
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        self.handle.change_counter()
+    }
+
+    fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.pragma(name, value)
+    }
+
+    fn checksum_verification(
+        &mut self,
+        enable: Option<bool>,
+    ) -> Option<Result<bool, std::io::Error>> {
+        self.handle.checksum_verification(enable)
+    }
+
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        self.handle.lock_proxy_file(path)
+    }
+
+    fn set_powersafe_overwrite(&mut self, enabled: bool) {
+        self.handle.set_powersafe_overwrite(enabled)
+    }
+
+    fn immutable(&self) -> bool {
+        self.handle.immutable()
+    }
+
+    fn powersafe_overwrite(&self) -> bool {
+        self.handle.powersafe_overwrite()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.handle.sector_size()
+    }
+
+    fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        self.handle.wal_index(readonly)
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_and_frame() {
+        let header = WalHeader {
+            magic: WAL_MAGIC,
+            version: 3007000,
+            page_size: 4,
+            checkpoint_seq: 1,
+            salts: [2, 3],
+            checksums: [4, 5],
+        };
+        assert_eq!(WalHeader::decode(&header.encode()).unwrap(), header);
+        assert_eq!(header.frame_offset(2), 32 + 2 * 28);
+        assert!(WalHeader::decode(&[0; 32]).is_err());
+
+        let frame = WalFrame {
+            index: 7,
+            page: 1,
+            commit_size: 2,
+            salts: [2, 3],
+            checksums: [6, 7],
+            data: vec![1, 2, 3, 4],
+        };
+        assert_eq!(WalFrame::decode(7, &frame.encode()).unwrap(), frame);
+        assert!(frame.is_commit());
+    }
+}
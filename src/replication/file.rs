@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{WalFrame, WalHeader, WalObserver, WAL_HEADER_SIZE};
+
+/// A [WalObserver] that archives the frames of each database in a directory.
+///
+/// The frames of `{db}` are archived in `{dir}/{db}/` (with the file name of `{db}` only), in a
+/// segment for each WAL generation (i.e. each time a WAL got restarted with new salts):
+/// - `{seq}.wal`: the header and the frames at the same offsets as in the WAL, so that each
+///   segment is a valid WAL,
+/// - `{seq}.commits`: for each commit frame, its index (4 bytes) and the time it got archived (8
+///   bytes, in milliseconds since the Unix epoch), big-endian.
+///
+/// Segments are numbered with ten digits, in the order they got started. After a restart of the
+/// process, frames of the same WAL generation continue its latest segment.
+pub struct FileSink {
+    dir: PathBuf,
+    segments: Mutex<HashMap<String, Segment>>,
+}
+
+struct Segment {
+    salts: [u32; 2],
+    wal: File,
+    commits: File,
+    /// The last commit frame recorded in the segment (frames can be passed again after a restart).
+    last_commit: Option<u32>,
+}
+
+impl FileSink {
+    /// Archive the frames in the directory `dir`, which is created if it does not exist yet.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            segments: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The directory the frames are archived in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Continue the latest segment of `name` if it archives the WAL generation of `header`, or
+    /// start a new one.
+    fn open_segment(&self, name: &str, header: &WalHeader) -> Result<Segment, std::io::Error> {
+        let dir = self.dir.join(name);
+        fs::create_dir_all(&dir)?;
+        let mut latest = None;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some(n) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                latest = latest.max(Some(n));
+            }
+        }
+
+        let path = |seq: u64, ext: &str| dir.join(format!("{:010}.{}", seq, ext));
+        if let Some(seq) = latest {
+            let mut wal = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path(seq, "wal"))?;
+            let mut data = [0; WAL_HEADER_SIZE];
+            let salts = match wal.read_exact(&mut data) {
+                Ok(()) => Some(WalHeader::decode(&data)?.salts),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+                Err(err) => return Err(err),
+            };
+            if salts == Some(header.salts) {
+                let mut commits = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .open(path(seq, "commits"))?;
+                let mut data = Vec::new();
+                commits.read_to_end(&mut data)?;
+                let last_commit = data
+                    .chunks_exact(12)
+                    .map(|entry| u32::from_be_bytes(entry[..4].try_into().unwrap()))
+                    .max();
+                return Ok(Segment {
+                    salts: header.salts,
+                    wal,
+                    commits,
+                    last_commit,
+                });
+            }
+        }
+
+        let seq = latest.map_or(0, |seq| seq + 1);
+        let open = |ext: &str| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path(seq, ext))
+        };
+        let mut wal = open("wal")?;
+        wal.write_all(&header.encode())?;
+        let commits = open("commits")?;
+        Ok(Segment {
+            salts: header.salts,
+            wal,
+            commits,
+            last_commit: None,
+        })
+    }
+}
+
+impl WalObserver for FileSink {
+    fn committed(
+        &self,
+        db: &str,
+        header: &WalHeader,
+        frames: &[WalFrame],
+    ) -> Result<(), std::io::Error> {
+        let name = Path::new(db)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(db);
+        let mut segments = self.segments.lock().unwrap();
+        if segments.get(name).map(|s| s.salts) != Some(header.salts) {
+            let segment = self.open_segment(name, header)?;
+            segments.insert(name.to_string(), segment);
+        }
+        let segment = segments.get_mut(name).unwrap();
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as i64)
+            .unwrap_or(0);
+        let mut commits = Vec::new();
+        for frame in frames {
+            segment
+                .wal
+                .seek(SeekFrom::Start(header.frame_offset(frame.index)))?;
+            segment.wal.write_all(&frame.encode())?;
+            if frame.is_commit() && segment.last_commit.is_none_or(|last| frame.index > last) {
+                segment.last_commit = Some(frame.index);
+                commits.extend_from_slice(&frame.index.to_be_bytes());
+                commits.extend_from_slice(&time.to_be_bytes());
+            }
+        }
+        segment.wal.sync_data()?;
+        segment.commits.write_all(&commits)?;
+        segment.commits.sync_data()
+    }
+}
@@ -0,0 +1,487 @@
+use std::io::ErrorKind;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use super::{WalFrame, WalHeader, WalObserver};
+use crate::memory::{MemoryHandle, MemoryVfs};
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs, WalDisabled};
+
+/// The committed frames passed to a [WalObserver] at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalBatch {
+    pub header: WalHeader,
+    pub frames: Vec<WalFrame>,
+}
+
+/// The stream of [WalBatch]es a follower applies.
+pub trait FrameSource: Send {
+    /// The next batch, or `None` if there is none available right now.
+    fn next_batch(&mut self) -> Result<Option<WalBatch>, std::io::Error>;
+}
+
+/// A read-only [Vfs] that serves a single database whose content follows the stream of committed
+/// WAL frames of a primary (see [WalBatch] and [FrameSource]).
+///
+/// The follower starts from a base copy of the primary's database file (taken while its WAL was
+/// empty, e.g. right after a checkpoint) and applies the frames of the stream in order. Frames are
+/// only applied between read transactions, i.e. when a connection starts a read transaction while
+/// no other connection holds a lock. The database is presented in rollback journal mode, with a file
+/// change counter that is incremented with each applied transaction, so that SQLite (as well as
+/// [DatabaseHandle::change_counter] based caches) notices the changes.
+///
+/// If the stream has a gap (i.e. frames are missing) or invalid frames (e.g. pages whose size
+/// differs from the page size of the database), the follower stops applying frames and
+/// [DatabaseHandle::moved] returns `true`; it has to be started from a new base copy. Writes fail
+/// with `SQLITE_READONLY`. Temporary files and journals are kept in memory.
+pub struct FollowerVfs<S> {
+    name: String,
+    replica: Arc<Mutex<Replica<S>>>,
+    /// Temporary files and journals.
+    memory: MemoryVfs,
+}
+
+/// A [DatabaseHandle] opened by [FollowerVfs].
+pub struct FollowerHandle<S> {
+    file: File<S>,
+}
+
+enum File<S> {
+    Replica {
+        replica: Arc<Mutex<Replica<S>>>,
+        lock: LockKind,
+    },
+    Memory(MemoryHandle),
+}
+
+struct Replica<S> {
+    source: S,
+    data: Vec<u8>,
+    /// The header of the WAL generation being followed.
+    header: Option<WalHeader>,
+    /// The index of the next frame expected from the stream.
+    next: u32,
+    /// The number of applied transactions.
+    version: u64,
+    /// The number of handles holding a lock.
+    readers: usize,
+    /// Set once a gap in the stream got detected.
+    moved: bool,
+}
+
+impl<S: FrameSource> FollowerVfs<S> {
+    /// Serve the database `name`, starting with the content `base` and following `source`.
+    pub fn new(name: &str, base: Vec<u8>, source: S) -> Self {
+        Self {
+            name: name.to_string(),
+            replica: Arc::new(Mutex::new(Replica {
+                source,
+                data: base,
+                header: None,
+                next: 0,
+                version: 0,
+                readers: 0,
+                moved: false,
+            })),
+            memory: MemoryVfs::default(),
+        }
+    }
+}
+
+impl<S: FrameSource> Replica<S> {
+    /// Apply all batches available from the stream.
+    fn pull(&mut self) -> Result<(), std::io::Error> {
+        while !self.moved {
+            match self.source.next_batch()? {
+                Some(batch) => self.apply(batch),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, batch: WalBatch) {
+        let first = match batch.frames.first() {
+            Some(frame) => frame.index,
+            None => return,
+        };
+        let next = match self.header {
+            Some(header) if header.salts == batch.header.salts => self.next,
+            // a new WAL generation, after all frames of the previous one got checkpointed
+            _ => 0,
+        };
+        let contiguous = batch
+            .frames
+            .windows(2)
+            .all(|pair| pair[1].index == pair[0].index + 1);
+        if first != next || !contiguous {
+            log::error!(
+                "gap in the replication stream: expected frame {}, got {}",
+                next,
+                first
+            );
+            self.moved = true;
+            return;
+        }
+        let page_size = batch.header.page_size as usize;
+        let valid = page_size > 0
+            && self.page_size().is_none_or(|size| size == page_size)
+            && batch
+                .frames
+                .iter()
+                .all(|f| f.page > 0 && f.salts == batch.header.salts && f.data.len() == page_size);
+        if !valid {
+            log::error!(
+                "invalid frames in the replication stream, starting at frame {}",
+                first
+            );
+            self.moved = true;
+            return;
+        }
+
+        for frame in &batch.frames {
+            let offset = (frame.page as usize - 1) * page_size;
+            if self.data.len() < offset + page_size {
+                self.data.resize(offset + page_size, 0);
+            }
+            self.data[offset..offset + page_size].copy_from_slice(&frame.data);
+            if frame.is_commit() {
+                self.data.resize(frame.commit_size as usize * page_size, 0);
+                self.version += 1;
+            }
+        }
+        self.header = Some(batch.header);
+        self.next = batch.frames.last().unwrap().index + 1;
+    }
+
+    /// The page size of the database, as far as known (from the WAL being followed, or the header
+    /// of the base copy).
+    fn page_size(&self) -> Option<usize> {
+        if let Some(header) = self.header {
+            return Some(header.page_size as usize);
+        }
+        match self.data.get(16..18)? {
+            // stored as 1 for 65536
+            [0, 1] => Some(65536),
+            size => Some(u16::from_be_bytes([size[0], size[1]]) as usize),
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let offset = (offset as usize).min(self.data.len());
+        let n = (self.data.len() - offset).min(buf.len());
+        buf[..n].copy_from_slice(&self.data[offset..offset + n]);
+        buf[n..].fill(0);
+
+        // present the database in rollback journal mode, with the applied transactions as file
+        // change counter
+        let mut patch = |at: usize, value: &[u8]| {
+            for (i, byte) in value.iter().enumerate() {
+                if (offset..offset + n).contains(&(at + i)) {
+                    buf[at + i - offset] = *byte;
+                }
+            }
+        };
+        if self.data.len() >= 100 {
+            let version = (self.version as u32).to_be_bytes();
+            patch(18, &[1, 1]);
+            patch(24, &version);
+            patch(92, &version);
+        }
+
+        if n < buf.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+impl WalObserver for mpsc::Sender<WalBatch> {
+    fn committed(
+        &self,
+        _db: &str,
+        header: &WalHeader,
+        frames: &[WalFrame],
+    ) -> Result<(), std::io::Error> {
+        self.send(WalBatch {
+            header: *header,
+            frames: frames.to_vec(),
+        })
+        .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "follower disconnected"))
+    }
+}
+
+impl FrameSource for mpsc::Receiver<WalBatch> {
+    fn next_batch(&mut self) -> Result<Option<WalBatch>, std::io::Error> {
+        match self.try_recv() {
+            Ok(batch) => Ok(Some(batch)),
+            Err(mpsc::TryRecvError::Empty | mpsc::TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+impl<S: FrameSource> Vfs for FollowerVfs<S> {
+    type Handle = FollowerHandle<S>;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        if opts.kind != OpenKind::MainDb {
+            return Ok(FollowerHandle {
+                file: File::Memory(self.memory.open(db, opts)?),
+            });
+        }
+        if db != self.name {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if opts.access != OpenAccess::Read {
+            // SQLite retries to open it read-only
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        Ok(FollowerHandle {
+            file: File::Replica {
+                replica: self.replica.clone(),
+                lock: LockKind::None,
+            },
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        if db == self.name {
+            Err(ErrorKind::PermissionDenied.into())
+        } else {
+            self.memory.delete(db)
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(db == self.name || self.memory.exists(db)?)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.memory.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        self.memory.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.memory.sleep(duration)
+    }
+
+    fn access(&self, db: &str, write: bool) -> Result<bool, std::io::Error> {
+        if db == self.name {
+            return Ok(!write);
+        }
+        self.memory.access(db, write)
+    }
+}
+
+impl<S: FrameSource> DatabaseHandle for FollowerHandle<S> {
+    type WalIndex = WalDisabled;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.file {
+            File::Replica { replica, .. } => Ok(replica.lock().unwrap().data.len() as u64),
+            File::Memory(file) => file.size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Replica { replica, .. } => replica.lock().unwrap().read_exact_at(buf, offset),
+            File::Memory(file) => file.read_exact_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Replica { .. } => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.write_all_at(buf, offset),
+        }
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Replica { .. } => Ok(()),
+            File::Memory(file) => file.sync(data_only),
+        }
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        match &mut self.file {
+            File::Replica { .. } => Err(ErrorKind::PermissionDenied.into()),
+            File::Memory(file) => file.set_len(size),
+        }
+    }
+
+    fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
+        let (replica, lock) = match &mut self.file {
+            File::Replica { replica, lock } => (replica, lock),
+            File::Memory(file) => return file.lock(to),
+        };
+        if to > LockKind::Shared {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let mut replica = replica.lock().unwrap();
+        match (*lock, to) {
+            (LockKind::None, LockKind::Shared) => {
+                if replica.readers == 0 {
+                    // no read transaction is in progress
+                    replica.pull()?;
+                }
+                replica.readers += 1;
+            }
+            (LockKind::Shared, LockKind::None) => replica.readers -= 1,
+            _ => {}
+        }
+        *lock = to;
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        match &self.file {
+            File::Replica { lock, .. } => Ok(*lock),
+            File::Memory(file) => file.current_lock(),
+        }
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        match &self.file {
+            File::Replica { replica, .. } => Ok(replica.lock().unwrap().moved),
+            File::Memory(file) => file.moved(),
+        }
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        match &self.file {
+            File::Replica { replica, .. } => Ok(Some(replica.lock().unwrap().version)),
+            File::Memory(file) => file.change_counter(),
+        }
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(WalDisabled)
+    }
+}
+
+impl<S> Drop for FollowerHandle<S> {
+    fn drop(&mut self) {
+        if let File::Replica { replica, lock } = &self.file {
+            if *lock != LockKind::None {
+                replica.lock().unwrap().readers -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(salt: u32, frames: &[(u32, u32, u32)]) -> WalBatch {
+        let header = WalHeader {
+            magic: 0x377f0682,
+            version: 3007000,
+            page_size: 4,
+            checkpoint_seq: 0,
+            salts: [salt, salt],
+            checksums: [0, 0],
+        };
+        WalBatch {
+            header,
+            frames: frames
+                .iter()
+                .map(|&(index, page, commit_size)| WalFrame {
+                    index,
+                    page,
+                    commit_size,
+                    salts: header.salts,
+                    checksums: [0, 0],
+                    data: vec![page as u8; 4],
+                })
+                .collect(),
+        }
+    }
+
+    fn open(
+        vfs: &FollowerVfs<mpsc::Receiver<WalBatch>>,
+    ) -> FollowerHandle<mpsc::Receiver<WalBatch>> {
+        let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Read);
+        vfs.open("main.db", opts).unwrap()
+    }
+
+    #[test]
+    fn test_frames_are_applied_between_read_transactions() {
+        let (tx, rx) = mpsc::channel();
+        let vfs = FollowerVfs::new("main.db", vec![0; 4], rx);
+        let mut a = open(&vfs);
+        let mut b = open(&vfs);
+
+        tx.send(batch(1, &[(0, 1, 0), (1, 2, 2)])).unwrap();
+        a.lock(LockKind::Shared).unwrap();
+        assert_eq!(a.size().unwrap(), 8);
+        assert_eq!(a.change_counter().unwrap(), Some(1));
+
+        // not while a read transaction is in progress
+        tx.send(batch(1, &[(2, 3, 3)])).unwrap();
+        b.lock(LockKind::Shared).unwrap();
+        assert_eq!(b.size().unwrap(), 8);
+        a.unlock(LockKind::None).unwrap();
+        b.unlock(LockKind::None).unwrap();
+        b.lock(LockKind::Shared).unwrap();
+        assert_eq!(b.size().unwrap(), 12);
+        let mut buf = [0; 4];
+        b.read_exact_at(&mut buf, 8).unwrap();
+        assert_eq!(buf, [3; 4]);
+        b.unlock(LockKind::None).unwrap();
+
+        // a new generation starts at frame 0
+        tx.send(batch(2, &[(0, 1, 1)])).unwrap();
+        b.lock(LockKind::Shared).unwrap();
+        assert_eq!(b.size().unwrap(), 4);
+        assert!(!b.moved().unwrap());
+        b.unlock(LockKind::None).unwrap();
+
+        // a gap
+        tx.send(batch(2, &[(2, 1, 1)])).unwrap();
+        tx.send(batch(2, &[(3, 2, 2)])).unwrap();
+        b.lock(LockKind::Shared).unwrap();
+        assert!(b.moved().unwrap());
+        assert_eq!(b.size().unwrap(), 4);
+        assert_eq!(b.change_counter().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_frames_of_a_different_page_size_are_rejected() {
+        let (tx, rx) = mpsc::channel();
+        let vfs = FollowerVfs::new("main.db", vec![0; 4], rx);
+        let mut handle = open(&vfs);
+        tx.send(batch(1, &[(0, 1, 1)])).unwrap();
+        handle.lock(LockKind::Shared).unwrap();
+        handle.unlock(LockKind::None).unwrap();
+
+        // a page that does not match the page size of the header
+        let mut short = batch(1, &[(1, 1, 1)]);
+        short.frames[0].data.pop();
+        tx.send(short).unwrap();
+        handle.lock(LockKind::Shared).unwrap();
+        assert!(handle.moved().unwrap());
+        assert_eq!(handle.change_counter().unwrap(), Some(1));
+
+        // a new generation with a different page size
+        let (tx, rx) = mpsc::channel();
+        let vfs = FollowerVfs::new("main.db", vec![0; 4], rx);
+        let mut handle = open(&vfs);
+        tx.send(batch(1, &[(0, 1, 1)])).unwrap();
+        let mut resized = batch(2, &[(0, 1, 1)]);
+        resized.header.page_size = 8;
+        resized.frames[0].data = vec![1; 8];
+        tx.send(resized).unwrap();
+        handle.lock(LockKind::Shared).unwrap();
+        assert!(handle.moved().unwrap());
+        assert_eq!(handle.size().unwrap(), 4);
+    }
+}
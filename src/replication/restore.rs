@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{WalFrame, WalHeader, WAL_FRAME_HEADER_SIZE};
+use crate::{DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+/// The WAL archive of a database, as written by a [FileSink](super::FileSink).
+pub struct WalArchive {
+    dir: PathBuf,
+}
+
+/// A transaction in a [WalArchive].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivedCommit {
+    /// The number of the transaction in the archive, starting at one.
+    pub transaction: u64,
+    pub segment: u64,
+    /// The index of the commit frame in its segment.
+    pub frame: u32,
+    /// When the transaction got archived, if recorded.
+    pub time: Option<SystemTime>,
+}
+
+/// Up to which transaction a [WalArchive] is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// All transactions in the archive.
+    Latest,
+    /// The transactions up to (and including) the given [ArchivedCommit::transaction].
+    Transaction(u64),
+    /// The transactions archived at or before the given time.
+    Time(SystemTime),
+}
+
+impl WalArchive {
+    /// The archive of the database `db` in the directory `dir` of a [FileSink](super::FileSink).
+    pub fn new(dir: impl AsRef<Path>, db: &str) -> Self {
+        let name = Path::new(db)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(db);
+        Self {
+            dir: dir.as_ref().join(name),
+        }
+    }
+
+    /// All transactions in the archive, in order.
+    ///
+    /// The transactions are those of the commit frames in the archived WALs (whose checksums are
+    /// verified like by [WalArchive::restore]); their time is `None` if it did not get recorded,
+    /// e.g. due to a crash right after the frames got archived.
+    pub fn commits(&self) -> Result<Vec<ArchivedCommit>, std::io::Error> {
+        let mut commits = Vec::new();
+        self.walk(|commit, _, _| {
+            commits.push(commit);
+            true
+        })?;
+        Ok(commits)
+    }
+
+    /// Apply the transactions of the archive up to `until` to `base` (a copy of the database file
+    /// taken while its WAL was empty, before the first archived frame), and write the result to the
+    /// database `name` of `target`. Returns the last transaction applied.
+    ///
+    /// The checksums and salts of all frames are verified, and missing segments or frames are
+    /// reported as [ErrorKind::InvalidData] errors. So are missing WAL generations: each time SQLite
+    /// restarts a WAL it increments the first salt, so unless a segment starts a new WAL (with a
+    /// checkpoint sequence of zero), its first salt has to follow the one of the previous segment.
+    /// Note that checkpoints restarting an already empty WAL (e.g. repeated `PRAGMA
+    /// wal_checkpoint(TRUNCATE)` without any writes in between) skip generations as well, which
+    /// can only be restored up to before them. The restored database keeps the journal mode of
+    /// `base`.
+    pub fn restore<V: Vfs>(
+        &self,
+        base: &[u8],
+        until: RestorePoint,
+        target: &V,
+        name: &str,
+    ) -> Result<Option<ArchivedCommit>, std::io::Error> {
+        let mut data = base.to_vec();
+        let last = self.apply(&mut data, until)?;
+        let applied = last.map_or(0, |last| last.transaction);
+        if let RestorePoint::Transaction(n) = until {
+            if applied < n {
+                return Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("the archive only has {} transactions", applied),
+                ));
+            }
+        }
+
+        let mut file = target.open(name, OpenOptions::new(OpenKind::MainDb, OpenAccess::Create))?;
+        file.write_all_at(&data, 0)?;
+        file.set_len(data.len() as u64)?;
+        file.sync(false)?;
+        Ok(last)
+    }
+
+    fn apply(
+        &self,
+        data: &mut Vec<u8>,
+        until: RestorePoint,
+    ) -> Result<Option<ArchivedCommit>, std::io::Error> {
+        let mut last = None;
+        self.walk(|commit, frames, page_size| {
+            if let RestorePoint::Time(time) = until {
+                if commit.time.is_none_or(|t| t > time) {
+                    return false;
+                }
+            }
+
+            let size = frames.last().unwrap().commit_size as usize * page_size;
+            for frame in frames {
+                let offset = (frame.page as usize - 1) * page_size;
+                if data.len() < offset + page_size {
+                    data.resize(offset + page_size, 0);
+                }
+                data[offset..offset + page_size].copy_from_slice(&frame.data);
+            }
+            data.resize(size, 0);
+            last = Some(commit);
+            until != RestorePoint::Transaction(commit.transaction)
+        })?;
+        Ok(last)
+    }
+
+    /// Verify the segments of the archive and pass each of their transactions, in order, to `f`
+    /// together with its frames (the last one being the commit frame) and the page size, until `f`
+    /// returns `false`.
+    fn walk(
+        &self,
+        mut f: impl FnMut(ArchivedCommit, Vec<WalFrame>, usize) -> bool,
+    ) -> Result<(), std::io::Error> {
+        let mut transaction = 0;
+        let mut previous: Option<WalHeader> = None;
+        for segment in self.segments()? {
+            let times = self
+                .commit_times(segment)?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            let last_commit = times.keys().max().copied();
+            let wal = fs::read(self.dir.join(format!("{:010}.wal", segment)))?;
+            let header = WalHeader::decode(&wal)?;
+            let big_endian = header.magic & 1 == 1;
+            if checksum(big_endian, &wal[..24], [0, 0]) != header.checksums {
+                return Err(invalid(format!(
+                    "segment {} has an invalid header",
+                    segment
+                )));
+            }
+            if previous.is_some_and(|previous| {
+                header.checkpoint_seq != 0 && header.salts[0] != previous.salts[0].wrapping_add(1)
+            }) {
+                return Err(invalid(format!(
+                    "the WAL generation before segment {} is missing",
+                    segment
+                )));
+            }
+            previous = Some(header);
+
+            let page_size = header.page_size as usize;
+            let mut sum = header.checksums;
+            let mut pending = Vec::new();
+            for index in 0.. {
+                let offset = header.frame_offset(index) as usize;
+                let frame = wal
+                    .get(offset..offset + header.frame_size() as usize)
+                    .map(|data| WalFrame::decode(index, data))
+                    .transpose()?;
+                let valid = frame.as_ref().is_some_and(|frame| {
+                    let data = &wal[offset..offset + WAL_FRAME_HEADER_SIZE + page_size];
+                    sum = checksum(big_endian, &data[..8], sum);
+                    sum = checksum(big_endian, &data[WAL_FRAME_HEADER_SIZE..], sum);
+                    frame.salts == header.salts && frame.checksums == sum
+                });
+                if !valid {
+                    if last_commit.is_some_and(|last_commit| index <= last_commit) {
+                        return Err(invalid(format!(
+                            "frame {} of segment {} is missing or corrupted",
+                            index, segment
+                        )));
+                    }
+                    // the end of the segment
+                    break;
+                }
+
+                let frame = frame.unwrap();
+                let is_commit = frame.is_commit();
+                pending.push(frame);
+                if !is_commit {
+                    continue;
+                }
+                transaction += 1;
+                let commit = ArchivedCommit {
+                    transaction,
+                    segment,
+                    frame: index,
+                    time: times.get(&index).copied(),
+                };
+                if !f(commit, std::mem::take(&mut pending), page_size) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The numbers of the segments, in order.
+    fn segments(&self) -> Result<Vec<u64>, std::io::Error> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("wal") {
+                continue;
+            }
+            if let Some(n) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                segments.push(n);
+            }
+        }
+        segments.sort_unstable();
+        for pair in segments.windows(2) {
+            if pair[1] != pair[0] + 1 {
+                return Err(invalid(format!("segment {} is missing", pair[0] + 1)));
+            }
+        }
+        Ok(segments)
+    }
+
+    /// The commit frames of `segment`, and when they got archived.
+    fn commit_times(&self, segment: u64) -> Result<Vec<(u32, SystemTime)>, std::io::Error> {
+        let data = match fs::read(self.dir.join(format!("{:010}.commits", segment))) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(data
+            .chunks_exact(12)
+            .map(|entry| {
+                let frame = u32::from_be_bytes(entry[..4].try_into().unwrap());
+                let millis = i64::from_be_bytes(entry[4..].try_into().unwrap());
+                (
+                    frame,
+                    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64),
+                )
+            })
+            .collect())
+    }
+}
+
+/// The WAL checksum of `data` (a multiple of 8 bytes), continuing from `seed`.
+pub(super) fn checksum(big_endian: bool, data: &[u8], seed: [u32; 2]) -> [u32; 2] {
+    let [mut s1, mut s2] = seed;
+    for words in data.chunks_exact(8) {
+        let word = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        s1 = s1.wrapping_add(word(&words[..4])).wrapping_add(s2);
+        s2 = s2.wrapping_add(word(&words[4..])).wrapping_add(s1);
+    }
+    [s1, s2]
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0];
+        // s1 = 1, s2 = 2 + 1 = 3; s1 = 1 + 3 + 3 = 7, s2 = 3 + 4 + 7 = 14
+        assert_eq!(checksum(false, &data, [0, 0]), [7, 14]);
+        assert_eq!(
+            checksum(false, &data[8..], checksum(false, &data[..8], [0, 0])),
+            [7, 14]
+        );
+        assert_eq!(checksum(true, &data[..8], [0, 0]), [1 << 24, 3 << 24]);
+    }
+}
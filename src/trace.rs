@@ -0,0 +1,277 @@
+//! Wrappers around the [crate::vfs] and [crate::io] functions that run each call inside a
+//! [tracing] span. The spans carry structured fields (`op`, `file_id`, `db`, `offset`, `len`,
+//! `lock`, ...) and record the SQLite result code as `rc` once the call returned. Calls that don't
+//! do any I/O are re-exported unchanged.
+
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::{ffi, file_state, DatabaseHandle, LockKind, Vfs};
+
+/// Record the result code `rc` on `span` (and emit an event if it isn't `SQLITE_OK`).
+fn finish(span: &Span, rc: c_int) -> c_int {
+    span.record("rc", rc);
+    if rc != ffi::SQLITE_OK {
+        span.in_scope(|| tracing::trace!(rc, "returned non-ok result code"));
+    }
+    rc
+}
+
+/// Record the id and database name of the file `p_file` on `span`.
+unsafe fn record_file<V, F: DatabaseHandle>(span: &Span, p_file: *mut ffi::sqlite3_file) {
+    if let Ok(state) = file_state::<V, F>(p_file) {
+        span.record("file_id", state.id);
+        span.record("db", state.db_name.as_str());
+    }
+}
+
+unsafe fn path<'a>(z_path: *const c_char) -> Option<std::borrow::Cow<'a, str>> {
+    z_path
+        .as_ref()
+        .map(|z_path| CStr::from_ptr(z_path).to_string_lossy())
+}
+
+pub mod vfs {
+    use super::*;
+
+    pub use crate::vfs::{
+        current_time, current_time_int64, dlclose, dlerror, dlopen, dlsym, get_last_error,
+        randomness, sleep,
+    };
+    #[cfg(feature = "syscall")]
+    pub use crate::vfs::{get_system_call, next_system_call, set_system_call};
+
+    pub unsafe extern "C" fn open<F: DatabaseHandle, V: Vfs<Handle = F>>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_name: *const c_char,
+        p_file: *mut ffi::sqlite3_file,
+        flags: c_int,
+        p_out_flags: *mut c_int,
+    ) -> c_int {
+        let span = tracing::trace_span!(
+            "open",
+            op = "open",
+            db = path(z_name).as_deref(),
+            flags,
+            file_id = Empty,
+            rc = Empty
+        );
+        let rc =
+            span.in_scope(|| crate::vfs::open::<F, V>(p_vfs, z_name, p_file, flags, p_out_flags));
+        if rc == ffi::SQLITE_OK {
+            record_file::<V, F>(&span, p_file);
+        }
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn delete<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_path: *const c_char,
+        sync_dir: c_int,
+    ) -> c_int {
+        let span = tracing::trace_span!(
+            "delete",
+            op = "delete",
+            db = path(z_path).as_deref(),
+            rc = Empty
+        );
+        let rc = span.in_scope(|| crate::vfs::delete::<V>(p_vfs, z_path, sync_dir));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn access<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_path: *const c_char,
+        flags: c_int,
+        p_res_out: *mut c_int,
+    ) -> c_int {
+        let span = tracing::trace_span!(
+            "access",
+            op = "access",
+            db = path(z_path).as_deref(),
+            flags,
+            rc = Empty
+        );
+        let rc = span.in_scope(|| crate::vfs::access::<V>(p_vfs, z_path, flags, p_res_out));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn full_pathname<V: Vfs>(
+        p_vfs: *mut ffi::sqlite3_vfs,
+        z_path: *const c_char,
+        n_out: c_int,
+        z_out: *mut c_char,
+    ) -> c_int {
+        let span = tracing::trace_span!(
+            "full_pathname",
+            op = "full_pathname",
+            db = path(z_path).as_deref(),
+            rc = Empty
+        );
+        let rc = span.in_scope(|| crate::vfs::full_pathname::<V>(p_vfs, z_path, n_out, z_out));
+        finish(&span, rc)
+    }
+}
+
+pub mod io {
+    use super::*;
+
+    pub use crate::io::{device_characteristics, sector_size, shm_barrier};
+
+    /// Create a span for the operation `$op` on the file `$p_file`.
+    macro_rules! file_span {
+        ($p_file:expr, $op:literal $(, $($fields:tt)*)?) => {{
+            let span = tracing::trace_span!(
+                $op,
+                op = $op,
+                file_id = Empty,
+                db = Empty,
+                rc = Empty
+                $(, $($fields)*)?
+            );
+            record_file::<V, F>(&span, $p_file);
+            span
+        }};
+    }
+
+    pub unsafe extern "C" fn close<V: Vfs, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+    ) -> c_int {
+        let span = file_span!(p_file, "close");
+        let rc = span.in_scope(|| crate::io::close::<V, F>(p_file));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn read<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        z_buf: *mut c_void,
+        i_amt: c_int,
+        i_ofst: ffi::sqlite3_int64,
+    ) -> c_int {
+        let span = file_span!(p_file, "read", offset = i_ofst, len = i_amt);
+        let rc = span.in_scope(|| crate::io::read::<V, F>(p_file, z_buf, i_amt, i_ofst));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn write<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        z: *const c_void,
+        i_amt: c_int,
+        i_ofst: ffi::sqlite3_int64,
+    ) -> c_int {
+        let span = file_span!(p_file, "write", offset = i_ofst, len = i_amt);
+        let rc = span.in_scope(|| crate::io::write::<V, F>(p_file, z, i_amt, i_ofst));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn truncate<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        size: ffi::sqlite3_int64,
+    ) -> c_int {
+        let span = file_span!(p_file, "truncate", size);
+        let rc = span.in_scope(|| crate::io::truncate::<V, F>(p_file, size));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn sync<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        flags: c_int,
+    ) -> c_int {
+        let span = file_span!(p_file, "sync", flags);
+        let rc = span.in_scope(|| crate::io::sync::<V, F>(p_file, flags));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn file_size<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        p_size: *mut ffi::sqlite3_int64,
+    ) -> c_int {
+        let span = file_span!(p_file, "file_size", size = Empty);
+        let rc = span.in_scope(|| crate::io::file_size::<V, F>(p_file, p_size));
+        if let Some(size) = p_size.as_ref().filter(|_| rc == ffi::SQLITE_OK) {
+            span.record("size", size);
+        }
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn lock<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        e_lock: c_int,
+    ) -> c_int {
+        let span = file_span!(
+            p_file,
+            "lock",
+            lock = LockKind::from_i32(e_lock).map(tracing::field::debug)
+        );
+        let rc = span.in_scope(|| crate::io::lock::<V, F>(p_file, e_lock));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn unlock<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        e_lock: c_int,
+    ) -> c_int {
+        let span = file_span!(
+            p_file,
+            "unlock",
+            lock = LockKind::from_i32(e_lock).map(tracing::field::debug)
+        );
+        let rc = span.in_scope(|| crate::io::unlock::<V, F>(p_file, e_lock));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn check_reserved_lock<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        p_res_out: *mut c_int,
+    ) -> c_int {
+        let span = file_span!(p_file, "check_reserved_lock");
+        let rc = span.in_scope(|| crate::io::check_reserved_lock::<V, F>(p_file, p_res_out));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn file_control<V: Vfs, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        op: c_int,
+        p_arg: *mut c_void,
+    ) -> c_int {
+        let span = file_span!(p_file, "file_control", fcntl = op);
+        let rc = span.in_scope(|| crate::io::file_control::<V, F>(p_file, op, p_arg));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn shm_map<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        region_ix: i32,
+        region_size: i32,
+        b_extend: i32,
+        pp: *mut *mut c_void,
+    ) -> i32 {
+        let span = file_span!(p_file, "shm_map", region = region_ix, len = region_size);
+        let rc = span
+            .in_scope(|| crate::io::shm_map::<V, F>(p_file, region_ix, region_size, b_extend, pp));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn shm_lock<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        offset: i32,
+        n: i32,
+        flags: i32,
+    ) -> i32 {
+        let span = file_span!(p_file, "shm_lock", offset, len = n, flags);
+        let rc = span.in_scope(|| crate::io::shm_lock::<V, F>(p_file, offset, n, flags));
+        finish(&span, rc)
+    }
+
+    pub unsafe extern "C" fn shm_unmap<V, F: DatabaseHandle>(
+        p_file: *mut ffi::sqlite3_file,
+        delete_flags: i32,
+    ) -> i32 {
+        let span = file_span!(p_file, "shm_unmap", delete_flags);
+        let rc = span.in_scope(|| crate::io::shm_unmap::<V, F>(p_file, delete_flags));
+        finish(&span, rc)
+    }
+}
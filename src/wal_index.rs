@@ -0,0 +1,251 @@
+//! A wal index in process memory, for [Vfs](crate::Vfs) implementations that cannot share a
+//! `-shm` file between the connections to a database.
+//!
+//! SQLite keeps the index of a WAL in memory shared by all connections to the database (usually
+//! the memory-mapped `{db}-shm` file). A [WalIndexManager] keeps those indexes per database
+//! (identified by a key, usually its name) in process memory instead, and hands out a
+//! [MemWalIndex] per handle, to be returned from
+//! [DatabaseHandle::wal_index](crate::DatabaseHandle::wal_index). This makes WAL mode usable
+//! without `PRAGMA locking_mode = EXCLUSIVE`, as long as all connections to a database live in the
+//! same process.
+//!
+//! The regions of an index are shared by all of its handles, and are dropped together with the
+//! last handle. Each of the eight lock slots can either be held as [WalIndexLock::Shared] by any
+//! number of handles, or as [WalIndexLock::Exclusive] by a single one.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::wip::{WalIndex, WalIndexLock};
+
+/// The number of lock slots of a wal index (`SQLITE_SHM_NLOCK`).
+const LOCK_SLOTS: usize = 8;
+
+/// The size of a region of a wal index.
+const REGION_SIZE: usize = 32768;
+
+/// Manages the wal indexes of databases in process memory. Clones share their state.
+#[derive(Default, Clone)]
+pub struct WalIndexManager {
+    databases: Arc<Mutex<HashMap<String, Weak<SharedIndex>>>>,
+}
+
+/// The wal index of a single handle on a database of a [WalIndexManager]. Its locks are released
+/// when dropped.
+pub struct MemWalIndex {
+    databases: Arc<Mutex<HashMap<String, Weak<SharedIndex>>>>,
+    db: String,
+    index: Arc<SharedIndex>,
+    /// The lock this handle holds on each slot.
+    locks: [WalIndexLock; LOCK_SLOTS],
+}
+
+#[derive(Default)]
+struct SharedIndex {
+    regions: Mutex<HashMap<u32, Box<[u8; REGION_SIZE]>>>,
+    slots: Mutex<[Slot; LOCK_SLOTS]>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Slot {
+    shared: usize,
+    exclusive: bool,
+}
+
+impl WalIndexManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle to the wal index of the database `db`, not holding any locks yet. Creates an
+    /// empty index if no other handle to it is alive.
+    pub fn wal_index(&self, db: &str) -> MemWalIndex {
+        let mut databases = self.databases.lock().unwrap();
+        let index = match databases.get(db).and_then(Weak::upgrade) {
+            Some(index) => index,
+            None => {
+                let index = Arc::new(SharedIndex::default());
+                databases.insert(db.to_string(), Arc::downgrade(&index));
+                index
+            }
+        };
+        MemWalIndex {
+            databases: self.databases.clone(),
+            db: db.to_string(),
+            index,
+            locks: [WalIndexLock::None; LOCK_SLOTS],
+        }
+    }
+}
+
+impl WalIndex for MemWalIndex {
+    fn map(&mut self, region: u32) -> Result<[u8; 32768], std::io::Error> {
+        let mut regions = self.index.regions.lock().unwrap();
+        let data = regions
+            .entry(region)
+            .or_insert_with(|| Box::new([0; REGION_SIZE]));
+        Ok(**data)
+    }
+
+    fn lock(&mut self, locks: Range<u8>, lock: WalIndexLock) -> Result<bool, std::io::Error> {
+        let slots = usize::from(locks.start)..usize::from(locks.end);
+        if slots.end > LOCK_SLOTS {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("wal index lock slots out of range: {:?}", locks),
+            ));
+        }
+
+        let mut shared = self.index.slots.lock().unwrap();
+        // check all slots before changing any of them
+        let available = slots.clone().all(|i| {
+            let slot = shared[i];
+            match (self.locks[i], lock) {
+                (_, WalIndexLock::None) | (WalIndexLock::Exclusive, _) => true,
+                (_, WalIndexLock::Shared) => !slot.exclusive,
+                (held, WalIndexLock::Exclusive) => {
+                    !slot.exclusive && slot.shared == usize::from(held == WalIndexLock::Shared)
+                }
+            }
+        });
+        if !available {
+            return Ok(false);
+        }
+
+        for i in slots {
+            let slot = &mut shared[i];
+            match self.locks[i] {
+                WalIndexLock::None => {}
+                WalIndexLock::Shared => slot.shared -= 1,
+                WalIndexLock::Exclusive => slot.exclusive = false,
+            }
+            match lock {
+                WalIndexLock::None => {}
+                WalIndexLock::Shared => slot.shared += 1,
+                WalIndexLock::Exclusive => slot.exclusive = true,
+            }
+            self.locks[i] = lock;
+        }
+        Ok(true)
+    }
+
+    fn delete(self) -> Result<(), std::io::Error> {
+        // handles created from now on start with an empty index
+        let mut databases = self.databases.lock().unwrap();
+        if databases
+            .get(&self.db)
+            .is_some_and(|index| index.as_ptr() == Arc::as_ptr(&self.index))
+        {
+            databases.remove(&self.db);
+        }
+        Ok(())
+    }
+
+    fn pull(&mut self, region: u32, data: &mut [u8; 32768]) -> Result<(), std::io::Error> {
+        let regions = self.index.regions.lock().unwrap();
+        if let Some(shared) = regions.get(&region) {
+            data.copy_from_slice(&shared[..]);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, region: u32, data: &[u8; 32768]) -> Result<(), std::io::Error> {
+        let mut regions = self.index.regions.lock().unwrap();
+        regions
+            .entry(region)
+            .or_insert_with(|| Box::new([0; REGION_SIZE]))
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl Drop for MemWalIndex {
+    fn drop(&mut self) {
+        self.lock(0..LOCK_SLOTS as u8, WalIndexLock::None).ok();
+
+        // forget the index if this is its last handle
+        let mut databases = self.databases.lock().unwrap();
+        if Arc::strong_count(&self.index) == 1
+            && databases
+                .get(&self.db)
+                .is_some_and(|index| index.as_ptr() == Arc::as_ptr(&self.index))
+        {
+            databases.remove(&self.db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions_are_shared() {
+        let manager = WalIndexManager::new();
+        let mut a = manager.wal_index("main.db");
+        let mut b = manager.wal_index("main.db");
+        let mut other = manager.wal_index("other.db");
+
+        let mut data = a.map(0).unwrap();
+        data[0] = 42;
+        a.push(0, &data).unwrap();
+        assert_eq!(b.map(0).unwrap()[0], 42);
+        assert_eq!(other.map(0).unwrap()[0], 0);
+
+        let mut data = [0; REGION_SIZE];
+        b.push(0, &[7; REGION_SIZE]).unwrap();
+        a.pull(0, &mut data).unwrap();
+        assert_eq!(data, [7; REGION_SIZE]);
+    }
+
+    #[test]
+    fn test_lock_slots() {
+        let manager = WalIndexManager::new();
+        let mut a = manager.wal_index("main.db");
+        let mut b = manager.wal_index("main.db");
+
+        assert!(a.lock(3..4, WalIndexLock::Shared).unwrap());
+        assert!(b.lock(3..4, WalIndexLock::Shared).unwrap());
+        assert!(!a.lock(3..4, WalIndexLock::Exclusive).unwrap());
+        assert!(b.lock(3..4, WalIndexLock::None).unwrap());
+        // upgrade the only shared lock
+        assert!(a.lock(3..4, WalIndexLock::Exclusive).unwrap());
+        assert!(!b.lock(3..4, WalIndexLock::Shared).unwrap());
+
+        // ranges are acquired completely or not at all
+        assert!(!b.lock(0..4, WalIndexLock::Exclusive).unwrap());
+        assert!(a.lock(0..1, WalIndexLock::Shared).unwrap());
+        assert!(a.lock(3..4, WalIndexLock::None).unwrap());
+        assert!(b.lock(1..8, WalIndexLock::Exclusive).unwrap());
+
+        // locks are released when dropped
+        drop(b);
+        let mut c = manager.wal_index("main.db");
+        assert!(c.lock(1..8, WalIndexLock::Exclusive).unwrap());
+        assert!(!c.lock(0..1, WalIndexLock::Exclusive).unwrap());
+
+        assert!(c.lock(8..9, WalIndexLock::Shared).is_err());
+    }
+
+    #[test]
+    fn test_index_is_dropped_with_last_handle() {
+        let manager = WalIndexManager::new();
+        let mut a = manager.wal_index("main.db");
+        a.push(0, &[1; REGION_SIZE]).unwrap();
+        let mut b = manager.wal_index("main.db");
+        drop(a);
+        assert_eq!(b.map(0).unwrap()[0], 1);
+        drop(b);
+        assert!(manager.databases.lock().unwrap().is_empty());
+
+        let mut c = manager.wal_index("main.db");
+        assert_eq!(c.map(0).unwrap()[0], 0);
+        c.push(0, &[1; REGION_SIZE]).unwrap();
+        let d = manager.wal_index("main.db");
+        d.delete().unwrap();
+        let mut e = manager.wal_index("main.db");
+        assert_eq!(e.map(0).unwrap()[0], 0);
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use sqlite_vfs::LockKind;
+
+/// A lock that creates a `{db}.lock` directory for any lock (like SQLite's `unix-dotfile` VFS).
+/// There is thus no difference between the lock kinds: a connection holding any lock keeps all
+/// other connections out, including readers.
+pub(crate) struct DotFileLock {
+    path: PathBuf,
+}
+
+impl DotFileLock {
+    pub(crate) fn new(db: &Path) -> Self {
+        let mut path = db.as_os_str().to_owned();
+        path.push(".lock");
+        Self { path: path.into() }
+    }
+
+    pub(crate) fn reserved(&self, current: LockKind) -> bool {
+        // a shared lock already keeps everyone else out
+        current == LockKind::None && self.path.exists()
+    }
+
+    /// Transition from the lock `from` to `to` and return the lock held afterwards.
+    pub(crate) fn transition(&mut self, from: LockKind, to: LockKind) -> LockKind {
+        if to == LockKind::None {
+            if let Err(err) = fs::remove_dir(&self.path) {
+                panic!("unlock failed: {}", err);
+            }
+            return to;
+        }
+        if from != LockKind::None {
+            return to;
+        }
+
+        match fs::create_dir(&self.path) {
+            Ok(()) => to,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => from,
+            Err(err) => panic!("lock failed: {}", err),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use sqlite_vfs::{register, RegisterError};
 
+mod dotfile_lock;
 pub mod file_lock;
 pub mod lock;
+mod posix_lock;
 pub mod range_lock;
 pub mod vfs;
 
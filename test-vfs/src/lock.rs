@@ -3,10 +3,70 @@ use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use std::os::unix::prelude::FromRawFd;
 use std::path::Path;
+use std::str::FromStr;
 use std::{env, io};
 
 pub use sqlite_vfs::LockKind;
 
+use crate::dotfile_lock::DotFileLock;
+use crate::posix_lock::PosixLock;
+
+/// The mechanism a [Lock] is built on. Network filesystems and containers tend to break some of
+/// them, which is why it can be chosen per database (see [crate::vfs::TestVfs]).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockStrategy {
+    /// BSD locks (`flock`) on the database and on `/tmp/{ino}.lck` (see [Lock]).
+    #[default]
+    Flock,
+
+    /// POSIX advisory byte-range locks (`fcntl`), on the same bytes as SQLite's unix VFS. As those
+    /// are owned by the process, the locks of all connections of this process to the same file are
+    /// coordinated in process memory.
+    Posix,
+
+    /// Open file description locks (`F_OFD_SETLK`), on the same bytes as [LockStrategy::Posix], but
+    /// owned by the open file. Only available on Linux.
+    Ofd,
+
+    /// A `{db}.lock` directory that is created for any lock, so that even readers exclude each
+    /// other. Works on any filesystem that supports an atomic `mkdir`.
+    DotFile,
+
+    /// No locking at all. Every lock is granted immediately.
+    None,
+}
+
+impl FromStr for LockStrategy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flock" => Ok(Self::Flock),
+            "posix" => Ok(Self::Posix),
+            "ofd" => Ok(Self::Ofd),
+            "dotfile" => Ok(Self::DotFile),
+            "none" => Ok(Self::None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown lock strategy: {}", s),
+            )),
+        }
+    }
+}
+
+/// The lock of a single connection on a database, using one of the [LockStrategy]s.
+pub struct Lock {
+    current: LockKind,
+    strategy: Strategy,
+}
+
+enum Strategy {
+    Flock(FlockLock),
+    Posix(PosixLock),
+    DotFile(DotFileLock),
+    None,
+}
+
 /// SQLite's default locking on UNIX systems is quite involved to work around certain limitations
 /// of POSIX locks. See https://github.com/sqlite/sqlite/blob/master/src/os_unix.c#L1026-L1114 for
 /// details.
@@ -37,44 +97,58 @@ pub use sqlite_vfs::LockKind;
 /// exclusivity first (to make sure that there is neither a pending nor exclusive lock) and then
 /// downgraded to a shared lock. Keeping the shared lock prevents any other reserved lock as there
 /// can only be one.
-pub struct Lock {
+struct FlockLock {
     fd1: RawFd,
     fd1_owned: bool,
     fd2: RawFd,
-    current: LockKind,
 }
 
 impl Lock {
+    /// A [LockStrategy::Flock] lock on the file at `path`.
     pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let path = path.as_ref();
-        let f1 = File::open(path)?;
+        Self::with_strategy(path, LockStrategy::Flock)
+    }
 
-        let f2 = OpenOptions::new()
+    /// A lock on the file at `path`, using `strategy`.
+    pub fn with_strategy(path: impl AsRef<Path>, strategy: LockStrategy) -> io::Result<Self> {
+        let path = path.as_ref();
+        // write locks require write access
+        let file = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
-            .open(env::temp_dir().join(format!("{}.lck", f1.metadata()?.ino())))?;
-
+            .write(matches!(strategy, LockStrategy::Posix | LockStrategy::Ofd))
+            .open(path)?;
+        let strategy = match strategy {
+            LockStrategy::Flock => {
+                let ino = file.metadata()?.ino();
+                Strategy::Flock(FlockLock::new(file.into_raw_fd(), true, ino)?)
+            }
+            LockStrategy::Posix => Strategy::Posix(PosixLock::new(file, false)?),
+            LockStrategy::Ofd => Strategy::Posix(PosixLock::new(file, true)?),
+            LockStrategy::DotFile => Strategy::DotFile(DotFileLock::new(path)),
+            LockStrategy::None => Strategy::None,
+        };
         Ok(Lock {
-            fd1: f1.into_raw_fd(),
-            fd1_owned: true,
-            fd2: f2.into_raw_fd(),
             current: LockKind::None,
+            strategy,
         })
     }
 
-    pub fn from_file(f1: &File) -> io::Result<Self> {
-        let f2 = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(env::temp_dir().join(format!("{}.lck", f1.metadata()?.ino())))?;
-
+    /// A lock on the already opened database `file` at `path`, using `strategy`. The lock must be
+    /// released with [Lock::close] before the file is closed.
+    pub fn from_file(path: &Path, file: &File, strategy: LockStrategy) -> io::Result<Self> {
+        let strategy = match strategy {
+            LockStrategy::Flock => {
+                let ino = file.metadata()?.ino();
+                Strategy::Flock(FlockLock::new(file.as_raw_fd(), false, ino)?)
+            }
+            LockStrategy::Posix => Strategy::Posix(PosixLock::from_file(file, false)?),
+            LockStrategy::Ofd => Strategy::Posix(PosixLock::from_file(file, true)?),
+            LockStrategy::DotFile => Strategy::DotFile(DotFileLock::new(path)),
+            LockStrategy::None => Strategy::None,
+        };
         Ok(Lock {
-            fd1: f1.as_raw_fd(),
-            fd1_owned: false,
-            fd2: f2.into_raw_fd(),
             current: LockKind::None,
+            strategy,
         })
     }
 
@@ -87,15 +161,16 @@ impl Lock {
             return true;
         }
 
-        if flock_exclusive(self.fd2) {
-            flock_unlock(self.fd2);
-            false
-        } else {
-            true
+        match &self.strategy {
+            Strategy::Flock(lock) => lock.reserved(),
+            Strategy::Posix(lock) => lock.reserved(),
+            Strategy::DotFile(lock) => lock.reserved(self.current),
+            Strategy::None => false,
         }
     }
 
-    /// Transition the lock to the given [LockKind].
+    /// Transition the lock to the given [LockKind]. A requested [LockKind::Exclusive] lock counts
+    /// as acquired if only a [LockKind::Pending] lock could be acquired so far.
     ///
     /// # Panics
     ///
@@ -113,75 +188,137 @@ impl Lock {
             )
         }
 
+        match to {
+            LockKind::Reserved if self.current != LockKind::Shared => {
+                // A shared lock is always held when a reserved lock is requested
+                panic!(
+                    "must hold a shared lock when requesting a reserved lock (current: {:?})",
+                    self.current
+                )
+            }
+            LockKind::Pending => {
+                panic!("cannot explicitly request pending lock (request explicit lock instead)")
+            }
+            _ => {}
+        }
+
+        let from = self.current;
+        self.current = match &mut self.strategy {
+            Strategy::Flock(lock) => lock.transition(from, to),
+            Strategy::Posix(lock) => lock.transition(from, to),
+            Strategy::DotFile(lock) => lock.transition(from, to),
+            Strategy::None => to,
+        };
+        self.current == to || (to == LockKind::Exclusive && self.current == LockKind::Pending)
+    }
+
+    /// Release the lock and close the database `file` it got created from. POSIX locks are released
+    /// once any file of the process to the database is closed, which is why the file is kept open
+    /// until no other connection of this process holds a lock on it anymore.
+    pub fn close(mut self, file: File) {
+        self.lock(LockKind::None);
+        match &self.strategy {
+            Strategy::Posix(lock) => lock.close(file),
+            _ => drop(file),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        self.lock(LockKind::None);
+    }
+}
+
+impl FlockLock {
+    fn new(fd1: RawFd, fd1_owned: bool, ino: u64) -> io::Result<Self> {
+        let f2 = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(env::temp_dir().join(format!("{}.lck", ino)))?;
+
+        Ok(FlockLock {
+            fd1,
+            fd1_owned,
+            fd2: f2.into_raw_fd(),
+        })
+    }
+
+    fn reserved(&self) -> bool {
+        if flock_exclusive(self.fd2) {
+            flock_unlock(self.fd2);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Transition from the lock `from` to `to` and return the lock held afterwards.
+    fn transition(&mut self, from: LockKind, to: LockKind) -> LockKind {
         match to {
             LockKind::None => {
                 flock_unlock(self.fd1);
 
-                if matches!(self.current, LockKind::Pending | LockKind::Exclusive) {
+                if from >= LockKind::Reserved {
                     flock_unlock(self.fd2);
                 }
 
-                self.current = LockKind::None;
-
-                return true;
+                LockKind::None
             }
 
             LockKind::Shared => {
-                if self.current != LockKind::Reserved {
-                    if !flock_shared(self.fd1) {
-                        return false;
-                    }
+                if from != LockKind::Reserved && !flock_shared(self.fd1) {
+                    return from;
                 }
 
                 if flock_shared(self.fd2) {
                     flock_unlock(self.fd2);
-                    self.current = LockKind::Shared;
-                    true
-                } else if matches!(self.current, LockKind::Pending | LockKind::Exclusive) {
-                    panic!("failed to transition to shared from {:?}", self.current);
-                } else if self.current == LockKind::None {
-                    flock_unlock(self.fd1);
-                    false
+                    LockKind::Shared
+                } else if matches!(from, LockKind::Pending | LockKind::Exclusive) {
+                    panic!("failed to transition to shared from {:?}", from);
                 } else {
-                    false
+                    if from == LockKind::None {
+                        flock_unlock(self.fd1);
+                    }
+                    from
                 }
             }
 
             LockKind::Reserved => {
-                // A shared lock is always held when a reserved lock is requested
-                if self.current != LockKind::Shared {
-                    panic!(
-                        "must hold a shared lock when requesting a reserved lock (current: {:?})",
-                        self.current
-                    )
-                }
-
                 if flock_exclusive(self.fd2) {
                     flock_shared(self.fd2);
-                    self.current = LockKind::Reserved;
-                    true
+                    LockKind::Reserved
                 } else {
-                    false
+                    from
                 }
             }
 
-            LockKind::Pending => {
-                panic!("cannot explicitly request pending lock (request explicit lock instead)")
-            }
-
             LockKind::Exclusive => {
-                if self.current != LockKind::Pending && !flock_exclusive(self.fd2) {
-                    return false;
+                if from != LockKind::Pending && !flock_exclusive(self.fd2) {
+                    return from;
                 }
 
                 if !flock_exclusive(self.fd1) {
-                    self.current = LockKind::Pending;
-                    return true;
+                    return LockKind::Pending;
                 }
 
-                self.current = LockKind::Exclusive;
-                true
+                LockKind::Exclusive
+            }
+
+            LockKind::Pending => unreachable!(),
+        }
+    }
+}
+
+impl Drop for FlockLock {
+    fn drop(&mut self) {
+        // Close file descriptors.
+        unsafe {
+            if self.fd1_owned {
+                File::from_raw_fd(self.fd1);
             }
+            File::from_raw_fd(self.fd2);
         }
     }
 }
@@ -223,17 +360,3 @@ pub(crate) fn flock_exclusive(fd: RawFd) -> bool {
 
     panic!("lock exclusive failed: {}", err);
 }
-
-impl Drop for Lock {
-    fn drop(&mut self) {
-        self.lock(LockKind::None);
-
-        // Close file descriptors.
-        unsafe {
-            if self.fd1_owned {
-                File::from_raw_fd(self.fd1);
-            }
-            File::from_raw_fd(self.fd2);
-        }
-    }
-}
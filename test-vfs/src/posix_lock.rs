@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+
+use sqlite_vfs::LockKind;
+
+// The bytes locked by SQLite's unix VFS.
+const PENDING_BYTE: u64 = 0x4000_0000;
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+/// The traditional POSIX locks of this process per file (device and inode).
+static INODES: Mutex<Option<HashMap<(u64, u64), Inode>>> = Mutex::new(None);
+
+/// Byte-range locks the same way SQLite's unix VFS acquires them:
+///
+/// |               pending byte     reserved byte    shared range
+///
+/// shared          read -> unlocked ¹                read
+///
+/// reserved                         write            read
+///
+/// pending         write            write            read
+///
+/// exclusive       write            write            write
+///
+///
+/// ¹ The pending byte is only read-locked while acquiring the shared lock, so that no new shared
+/// locks can be acquired once a writer holds the pending byte.
+///
+/// OFD locks belong to the open file, and are thus used as they are. Traditional POSIX locks
+/// belong to the process instead: they never conflict with other locks of the same process, and
+/// are all released once any file of the process to the same inode is closed. Their state is thus
+/// tracked per inode (as [Inode]), acquired once for all connections of this process, and files
+/// are kept open until the last lock on their inode is released.
+pub(crate) struct PosixLock {
+    fd: RawFd,
+    /// The file the lock got created with, if owned by the lock.
+    file: Option<File>,
+    ofd: bool,
+    inode: (u64, u64),
+}
+
+#[derive(Default)]
+struct Inode {
+    /// The lock this process holds.
+    lock: LockKind,
+    /// The number of connections of this process holding a shared (or higher) lock.
+    shared: usize,
+    /// Files to close once the process doesn't hold a lock anymore.
+    pending_close: Vec<File>,
+}
+
+impl PosixLock {
+    pub(crate) fn new(file: File, ofd: bool) -> io::Result<Self> {
+        let mut lock = Self::from_file(&file, ofd)?;
+        lock.file = Some(file);
+        Ok(lock)
+    }
+
+    pub(crate) fn from_file(file: &File, ofd: bool) -> io::Result<Self> {
+        if ofd && !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "OFD locks are only supported on Linux",
+            ));
+        }
+        let metadata = file.metadata()?;
+        Ok(PosixLock {
+            fd: file.as_raw_fd(),
+            file: None,
+            ofd,
+            inode: (metadata.dev(), metadata.ino()),
+        })
+    }
+
+    pub(crate) fn reserved(&self) -> bool {
+        if !self.ofd {
+            // locks of this process don't show up below
+            let inodes = INODES.lock().unwrap();
+            let inode = inodes.as_ref().and_then(|inodes| inodes.get(&self.inode));
+            if inode.is_some_and(|inode| inode.lock > LockKind::Shared) {
+                return true;
+            }
+        }
+
+        let mut lock = flock(libc::F_WRLCK, RESERVED_BYTE, 1);
+        let cmd = if self.ofd { F_OFD_GETLK } else { libc::F_GETLK };
+        if unsafe { libc::fcntl(self.fd, cmd, &mut lock) } != 0 {
+            panic!("lock check failed: {}", io::Error::last_os_error());
+        }
+        i32::from(lock.l_type) != libc::F_UNLCK
+    }
+
+    /// Transition from the lock `from` to `to` and return the lock held afterwards.
+    pub(crate) fn transition(&mut self, from: LockKind, to: LockKind) -> LockKind {
+        if self.ofd {
+            return self.transition_file(F_OFD_SETLK, from, to);
+        }
+
+        let mut inodes = INODES.lock().unwrap();
+        let inodes = inodes.get_or_insert_with(HashMap::new);
+        let inode = inodes.entry(self.inode).or_default();
+        match to {
+            LockKind::Shared if from == LockKind::None => {
+                // a writer of this process is waiting for or holds an exclusive lock
+                if inode.lock >= LockKind::Pending {
+                    return from;
+                }
+                if inode.shared == 0 {
+                    if self.transition_file(libc::F_SETLK, LockKind::None, to) != to {
+                        return from;
+                    }
+                    inode.lock = to;
+                }
+                inode.shared += 1;
+                to
+            }
+
+            LockKind::Reserved | LockKind::Exclusive => {
+                // another connection of this process is writing
+                if from == LockKind::Shared && inode.lock > LockKind::Shared {
+                    return from;
+                }
+                // other connections of this process are still reading
+                if to == LockKind::Exclusive && inode.shared > 1 {
+                    if from >= LockKind::Pending {
+                        return from;
+                    }
+                    inode.lock = self.transition_file(libc::F_SETLK, inode.lock, LockKind::Pending);
+                    return inode.lock;
+                }
+                inode.lock = self.transition_file(libc::F_SETLK, inode.lock, to);
+                inode.lock
+            }
+
+            LockKind::Shared => {
+                inode.lock = self.transition_file(libc::F_SETLK, inode.lock, to);
+                to
+            }
+
+            LockKind::None => {
+                if from > LockKind::Shared {
+                    inode.lock = self.transition_file(libc::F_SETLK, inode.lock, LockKind::Shared);
+                }
+                inode.shared -= 1;
+                if inode.shared == 0 {
+                    self.transition_file(libc::F_SETLK, inode.lock, to);
+                    // also closes the files that were kept open
+                    inodes.remove(&self.inode);
+                }
+                to
+            }
+
+            LockKind::Pending => unreachable!(),
+        }
+    }
+
+    /// Transition the locks of the file from `from` to `to` (which can also be
+    /// [LockKind::Pending]) using the fcntl `cmd`, and return the lock held afterwards.
+    fn transition_file(&self, cmd: i32, from: LockKind, to: LockKind) -> LockKind {
+        let lock = |l_type, start, len| setlk(self.fd, cmd, l_type, start, len);
+        match to {
+            LockKind::None => {
+                lock(libc::F_UNLCK, PENDING_BYTE, 2 + SHARED_SIZE);
+                to
+            }
+
+            LockKind::Shared if from == LockKind::None => {
+                if !lock(libc::F_RDLCK, PENDING_BYTE, 1) {
+                    return from;
+                }
+                let acquired = lock(libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+                lock(libc::F_UNLCK, PENDING_BYTE, 1);
+                if acquired {
+                    to
+                } else {
+                    from
+                }
+            }
+
+            LockKind::Shared => {
+                if from == LockKind::Exclusive && !lock(libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE) {
+                    panic!("failed to transition to shared from {:?}", from);
+                }
+                lock(libc::F_UNLCK, PENDING_BYTE, 2);
+                to
+            }
+
+            LockKind::Reserved => {
+                if lock(libc::F_WRLCK, RESERVED_BYTE, 1) {
+                    to
+                } else {
+                    from
+                }
+            }
+
+            LockKind::Pending | LockKind::Exclusive => {
+                if from < LockKind::Reserved && !lock(libc::F_WRLCK, RESERVED_BYTE, 1) {
+                    return from;
+                }
+                if from < LockKind::Pending && !lock(libc::F_WRLCK, PENDING_BYTE, 1) {
+                    if from < LockKind::Reserved {
+                        lock(libc::F_UNLCK, RESERVED_BYTE, 1);
+                    }
+                    return from;
+                }
+                if to == LockKind::Pending || !lock(libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE) {
+                    return LockKind::Pending;
+                }
+                to
+            }
+        }
+    }
+
+    /// Close `file` (of the same inode as the lock), unless this process still holds locks on it.
+    pub(crate) fn close(&self, file: File) {
+        if self.ofd {
+            return;
+        }
+        let mut inodes = INODES.lock().unwrap();
+        if let Some(inode) = inodes
+            .as_mut()
+            .and_then(|inodes| inodes.get_mut(&self.inode))
+        {
+            inode.pending_close.push(file);
+        }
+    }
+}
+
+impl Drop for PosixLock {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            self.close(file);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+const F_OFD_GETLK: i32 = libc::F_OFD_GETLK;
+#[cfg(target_os = "linux")]
+const F_OFD_SETLK: i32 = libc::F_OFD_SETLK;
+// never used, as OFD locks cannot be created on other platforms
+#[cfg(not(target_os = "linux"))]
+const F_OFD_GETLK: i32 = libc::F_GETLK;
+#[cfg(not(target_os = "linux"))]
+const F_OFD_SETLK: i32 = libc::F_SETLK;
+
+fn flock(l_type: i32, start: u64, len: u64) -> libc::flock {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = start as _;
+    lock.l_len = len as _;
+    lock
+}
+
+/// Set the lock `l_type` on the `len` bytes at `start` without waiting. Returns whether the lock
+/// could be acquired.
+fn setlk(fd: RawFd, cmd: i32, l_type: i32, start: u64, len: u64) -> bool {
+    let lock = flock(l_type, start, len);
+    if unsafe { libc::fcntl(fd, cmd, &lock) } == 0 {
+        return true;
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => return false,
+        // a write lock on a file opened read-only
+        Some(libc::EBADF) if l_type == libc::F_WRLCK => return false,
+        _ => {}
+    }
+
+    panic!("fcntl lock failed: {}", err);
+}
@@ -1,7 +1,8 @@
 use std::borrow::Cow;
 use std::fs::{self, File, Permissions};
 use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -9,22 +10,32 @@ use sqlite_vfs::wip::{WalIndex, WalIndexLock};
 use sqlite_vfs::{LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
 
 use crate::file_lock::FileLock;
-use crate::lock::Lock;
+use crate::lock::{Lock, LockStrategy};
 use crate::range_lock::RangeLock;
 
 /// [Vfs] test implementation based on Rust's [std::fs:File]. This implementation is not meant for
 /// any use-cases except running SQLite unit tests, as the locking is only managed in process
 /// memory.
+///
+/// Databases are locked with the [LockStrategy] set with [TestVfs::with_lock_strategy], or the one
+/// given by the `lock` URI parameter (`file:test.db?lock=posix`). The locks can be moved to a proxy
+/// file with `PRAGMA lock_proxy_file = '/path/to/proxy'` (see
+/// [DatabaseHandle::lock_proxy_file](sqlite_vfs::DatabaseHandle::lock_proxy_file)).
 #[derive(Default)]
 pub struct TestVfs {
     temp_counter: AtomicUsize,
+    lock_strategy: LockStrategy,
 }
 
 pub struct Connection {
     path: PathBuf,
-    file: File,
+    /// Closed via [Lock::close].
+    file: ManuallyDrop<File>,
     file_ino: u64,
     lock: Option<Lock>,
+    lock_strategy: LockStrategy,
+    /// The file [Connection::lock] is taken on instead of the database file, if any.
+    lock_proxy: Option<PathBuf>,
 }
 
 pub struct WalConnection {
@@ -34,6 +45,14 @@ pub struct WalConnection {
     readonly: bool,
 }
 
+impl TestVfs {
+    /// Lock databases using `strategy` (unless set differently via URI).
+    pub fn with_lock_strategy(mut self, strategy: LockStrategy) -> Self {
+        self.lock_strategy = strategy;
+        self
+    }
+}
+
 impl Vfs for TestVfs {
     type Handle = Connection;
 
@@ -56,25 +75,31 @@ impl Vfs for TestVfs {
             }
             _ => false,
         };
-        let file = o.open(&path)?;
+        if opts.nofollow {
+            o.custom_flags(libc::O_NOFOLLOW);
+        }
+        let file = o.open(&path).map_err(|err| {
+            if err.raw_os_error() == Some(libc::ELOOP) {
+                io::Error::new(ErrorKind::InvalidInput, "cannot open symbolic link")
+            } else {
+                err
+            }
+        })?;
         let metadata = file.metadata()?;
         let file_ino = metadata.ino();
 
         if is_create && matches!(opts.kind, OpenKind::Wal | OpenKind::MainJournal) {
-            if let Ok(mode) = permissions(&path) {
+            let db = normalize_path(Path::new(opts.database.as_deref().unwrap_or(db)));
+            if let Ok(mode) = permissions(&db) {
                 fs::set_permissions(&path, Permissions::from_mode(mode)).ok();
             }
         }
 
         if opts.kind == OpenKind::Wal {
             // ensure wal index access
-            let path = path.with_extension(format!(
-                "{}-shm",
-                path.extension()
-                    .and_then(|ext| ext.to_str())
-                    .map(|ext| ext.split_once('-').map(|(f, _)| f).unwrap_or(ext))
-                    .unwrap_or("db")
-            ));
+            let path = shm_path(&normalize_path(Path::new(
+                opts.database.as_deref().unwrap_or(db),
+            )));
             if path.exists()
                 && fs::metadata(&path)
                     .map(|m| m.permissions().mode())
@@ -88,17 +113,23 @@ impl Vfs for TestVfs {
             }
         }
 
+        let lock_strategy = match opts.uri_parameter("lock") {
+            Some(strategy) => strategy.parse()?,
+            None => self.lock_strategy,
+        };
         Ok(Connection {
-            path,
             // Lock needs to be created right away to ensure there is a free file descriptor for the
             // additional lock file.
             lock: if opts.kind == OpenKind::MainDb {
-                Some(Lock::from_file(&file)?)
+                Some(Lock::from_file(&path, &file, lock_strategy)?)
             } else {
                 None
             },
-            file,
+            path,
+            file: ManuallyDrop::new(file),
             file_ino,
+            lock_strategy,
+            lock_proxy: None,
         })
     }
 
@@ -162,6 +193,34 @@ impl Vfs for TestVfs {
     }
 }
 
+impl Connection {
+    /// Take the locks on the proxy file at `path` (created if missing) instead of the database
+    /// file, or on the database file again if `path` is empty.
+    fn set_lock_proxy(&mut self, path: &str) -> Result<(), std::io::Error> {
+        if self.lock.as_ref().map_or(LockKind::None, |l| l.current()) != LockKind::None {
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                "cannot change the lock proxy file while the database is locked",
+            ));
+        }
+
+        if path.is_empty() {
+            self.lock = Some(Lock::from_file(&self.path, &self.file, self.lock_strategy)?);
+            self.lock_proxy = None;
+        } else {
+            let proxy = normalize_path(Path::new(path));
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&proxy)?;
+            self.lock = Some(Lock::with_strategy(&proxy, self.lock_strategy)?);
+            self.lock_proxy = Some(proxy);
+        }
+        Ok(())
+    }
+}
+
 impl sqlite_vfs::DatabaseHandle for Connection {
     type WalIndex = WalConnection;
 
@@ -195,7 +254,11 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
         let lock = match &mut self.lock {
             Some(lock) => lock,
-            None => self.lock.get_or_insert(Lock::from_file(&self.file)?),
+            None => self.lock.get_or_insert(Lock::from_file(
+                &self.path,
+                &self.file,
+                self.lock_strategy,
+            )?),
         };
 
         // Return false if exclusive was requested and only pending was acquired.
@@ -205,7 +268,11 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     fn reserved(&mut self) -> Result<bool, std::io::Error> {
         let lock = match &mut self.lock {
             Some(lock) => lock,
-            None => self.lock.get_or_insert(Lock::from_file(&self.file)?),
+            None => self.lock.get_or_insert(Lock::from_file(
+                &self.path,
+                &self.file,
+                self.lock_strategy,
+            )?),
         };
 
         Ok(lock.reserved())
@@ -219,20 +286,26 @@ impl sqlite_vfs::DatabaseHandle for Connection {
             .unwrap_or(LockKind::None))
     }
 
+    fn lock_proxy_file(
+        &mut self,
+        path: Option<&str>,
+    ) -> Option<Result<Option<String>, std::io::Error>> {
+        if let Some(Err(err)) = path.map(|path| self.set_lock_proxy(path)) {
+            return Some(Err(err));
+        }
+        Some(Ok(self
+            .lock_proxy
+            .as_ref()
+            .map(|proxy| proxy.to_string_lossy().to_string())))
+    }
+
     fn moved(&self) -> Result<bool, std::io::Error> {
         let ino = fs::metadata(&self.path).map(|m| m.ino()).unwrap_or(0);
         Ok(ino == 0 || ino != self.file_ino)
     }
 
     fn wal_index(&self, readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
-        let path = self.path.with_extension(format!(
-            "{}-shm",
-            self.path
-                .extension()
-                .and_then(|ext| ext.to_str())
-                .map(|ext| ext.split_once('-').map(|(f, _)| f).unwrap_or(ext))
-                .unwrap_or("db")
-        ));
+        let path = shm_path(&self.path);
         let is_new = !path.exists();
 
         let mut opts = fs::OpenOptions::new();
@@ -284,6 +357,17 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let file = unsafe { ManuallyDrop::take(&mut self.file) };
+        match self.lock.take() {
+            // a proxy lock doesn't belong to the database file
+            Some(lock) if self.lock_proxy.is_none() => lock.close(file),
+            _ => drop(file),
+        }
+    }
+}
+
 impl WalIndex for WalConnection {
     fn map(&mut self, region: u32) -> Result<[u8; 32768], std::io::Error> {
         let mut data = [0u8; 32768];
@@ -337,6 +421,13 @@ impl WalIndex for WalConnection {
     }
 }
 
+/// The path of the wal index of the database at `db`.
+fn shm_path(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push("-shm");
+    PathBuf::from(path)
+}
+
 // Source: https://github.com/rust-lang/cargo/blob/7a3b56b4860c0e58dab815549a93198a1c335b64/crates/cargo-util/src/paths.rs#L81
 fn normalize_path(path: &Path) -> PathBuf {
     use std::path::Component;
@@ -367,12 +458,6 @@ fn normalize_path(path: &Path) -> PathBuf {
     ret
 }
 
-fn permissions(path: &Path) -> io::Result<u32> {
-    let path = path.with_extension(
-        path.extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.split_once('-').map(|(f, _)| f).unwrap_or(ext))
-            .unwrap_or("db"),
-    );
-    Ok(fs::metadata(&path)?.permissions().mode())
+fn permissions(db: &Path) -> io::Result<u32> {
+    Ok(fs::metadata(db)?.permissions().mode())
 }
@@ -1,7 +1,27 @@
 use std::fs;
 use std::path::PathBuf;
 
-use test_vfs::lock::{Lock, LockKind};
+use sqlite_vfs::{DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs};
+use test_vfs::lock::{Lock, LockKind, LockStrategy};
+use test_vfs::vfs::TestVfs;
+
+/// All strategies.
+const STRATEGIES: &[LockStrategy] = &[
+    LockStrategy::Flock,
+    LockStrategy::Posix,
+    #[cfg(target_os = "linux")]
+    LockStrategy::Ofd,
+    LockStrategy::DotFile,
+    LockStrategy::None,
+];
+
+/// The strategies that distinguish shared from exclusive locks.
+const SHARED_STRATEGIES: &[LockStrategy] = &[
+    LockStrategy::Flock,
+    LockStrategy::Posix,
+    #[cfg(target_os = "linux")]
+    LockStrategy::Ofd,
+];
 
 fn test_file(name: &str) -> PathBuf {
     let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
@@ -11,47 +31,62 @@ fn test_file(name: &str) -> PathBuf {
     path
 }
 
+fn test_files(name: &str, strategies: &[LockStrategy]) -> Vec<(PathBuf, LockStrategy)> {
+    strategies
+        .iter()
+        .map(|strategy| {
+            let name = format!("{}_{:?}", name, strategy).to_lowercase();
+            (test_file(&name), *strategy)
+        })
+        .collect()
+}
+
 #[test]
 fn test_none() {
-    let path = test_file(".test_none");
-    let lock = Lock::new(&path).unwrap();
-    assert_eq!(lock.current(), LockKind::None);
+    for (path, strategy) in test_files(".test_none", STRATEGIES) {
+        let lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert_eq!(lock.current(), LockKind::None);
+    }
 }
 
 #[test]
 fn test_shared() {
-    let path = test_file(".test_shared");
-    let mut lock = Lock::new(&path).unwrap();
-    assert!(lock.lock(LockKind::Shared));
-    assert_eq!(lock.current(), LockKind::Shared);
+    for (path, strategy) in test_files(".test_shared", STRATEGIES) {
+        let mut lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock.lock(LockKind::Shared));
+        assert_eq!(lock.current(), LockKind::Shared);
+    }
 }
 
 #[test]
 fn test_reserved() {
-    let path = test_file(".test_reserved");
-    let mut lock = Lock::new(&path).unwrap();
-    assert!(lock.lock(LockKind::Shared));
-    assert!(lock.lock(LockKind::Reserved));
-    assert_eq!(lock.current(), LockKind::Reserved);
+    for (path, strategy) in test_files(".test_reserved", STRATEGIES) {
+        let mut lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock.lock(LockKind::Shared));
+        assert!(lock.lock(LockKind::Reserved));
+        assert_eq!(lock.current(), LockKind::Reserved);
+    }
 }
 
 #[test]
 fn test_exclusive() {
-    let path = test_file(".test_exclusive");
-    let mut lock = Lock::new(&path).unwrap();
-    assert!(lock.lock(LockKind::Shared));
-    assert!(lock.lock(LockKind::Exclusive));
-    assert_eq!(lock.current(), LockKind::Exclusive);
+    for (path, strategy) in test_files(".test_exclusive", STRATEGIES) {
+        let mut lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock.lock(LockKind::Shared));
+        assert!(lock.lock(LockKind::Exclusive));
+        assert_eq!(lock.current(), LockKind::Exclusive);
+    }
 }
 
 #[test]
 fn test_exclusive_via_reserved() {
-    let path = test_file(".test_exclusive_via_reserved");
-    let mut lock = Lock::new(&path).unwrap();
-    assert!(lock.lock(LockKind::Shared));
-    assert!(lock.lock(LockKind::Reserved));
-    assert!(lock.lock(LockKind::Exclusive));
-    assert_eq!(lock.current(), LockKind::Exclusive);
+    for (path, strategy) in test_files(".test_exclusive_via_reserved", STRATEGIES) {
+        let mut lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock.lock(LockKind::Shared));
+        assert!(lock.lock(LockKind::Reserved));
+        assert!(lock.lock(LockKind::Exclusive));
+        assert_eq!(lock.current(), LockKind::Exclusive);
+    }
 }
 
 #[test]
@@ -95,72 +130,181 @@ fn test_reserved_to_pending_panic() {
 
 #[test]
 fn test_reserved_once() {
-    let path = test_file(".reserved_once");
-    let mut lock1 = Lock::new(&path).unwrap();
-    assert!(lock1.lock(LockKind::Shared));
+    for (path, strategy) in test_files(".reserved_once", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
 
-    let mut lock2 = Lock::new(&path).unwrap();
-    assert!(lock2.lock(LockKind::Shared));
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock2.lock(LockKind::Shared));
 
-    assert!(lock1.lock(LockKind::Reserved));
-    assert!(!lock2.lock(LockKind::Reserved));
+        assert!(lock1.lock(LockKind::Reserved));
+        assert!(!lock2.lock(LockKind::Reserved));
 
-    assert!(lock1.lock(LockKind::Shared));
-    assert!(lock2.lock(LockKind::Reserved));
+        assert!(lock1.lock(LockKind::Shared));
+        assert!(lock2.lock(LockKind::Reserved));
+    }
 }
 
 #[test]
 fn test_shared_while_reserved() {
-    let path = test_file(".shared_while_reserved");
-    let mut lock1 = Lock::new(&path).unwrap();
-    assert!(lock1.lock(LockKind::Shared));
-    assert!(lock1.lock(LockKind::Reserved));
+    for (path, strategy) in test_files(".shared_while_reserved", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
+        assert!(lock1.lock(LockKind::Reserved));
 
-    let mut lock2 = Lock::new(&path).unwrap();
-    assert!(lock2.lock(LockKind::Shared));
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock2.lock(LockKind::Shared));
+    }
 }
 
 #[test]
 fn test_pending() {
-    let path = test_file(".test_pending");
-    let mut lock1 = Lock::new(&path).unwrap();
-    assert!(lock1.lock(LockKind::Shared));
+    for (path, strategy) in test_files(".test_pending", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
 
-    let mut lock2 = Lock::new(&path).unwrap();
-    assert!(lock2.lock(LockKind::Shared));
-    assert!(lock2.lock(LockKind::Exclusive));
-    assert_eq!(lock2.current(), LockKind::Pending);
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock2.lock(LockKind::Shared));
+        assert!(lock2.lock(LockKind::Exclusive));
+        assert_eq!(lock2.current(), LockKind::Pending);
+    }
 }
 
 #[test]
 fn test_pending_once() {
-    let path = test_file(".test_pending_once");
-    let mut lock1 = Lock::new(&path).unwrap();
-    assert!(lock1.lock(LockKind::Shared));
+    for (path, strategy) in test_files(".test_pending_once", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
 
-    let mut lock2 = Lock::new(&path).unwrap();
-    assert!(lock2.lock(LockKind::Shared));
-    assert!(lock2.lock(LockKind::Exclusive));
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock2.lock(LockKind::Shared));
+        assert!(lock2.lock(LockKind::Exclusive));
 
-    assert!(!lock1.lock(LockKind::Exclusive));
+        assert!(!lock1.lock(LockKind::Exclusive));
 
-    assert_eq!(lock1.current(), LockKind::Shared);
-    assert_eq!(lock2.current(), LockKind::Pending);
+        assert_eq!(lock1.current(), LockKind::Shared);
+        assert_eq!(lock2.current(), LockKind::Pending);
+    }
 }
 
 #[test]
 fn test_pending_to_exclusive() {
-    let path = test_file(".test_pending_to_exclusive");
-    let mut lock1 = Lock::new(&path).unwrap();
+    for (path, strategy) in test_files(".test_pending_to_exclusive", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
+
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock2.lock(LockKind::Shared));
+        assert!(lock2.lock(LockKind::Exclusive));
+
+        assert!(lock1.lock(LockKind::None));
+        assert!(lock2.lock(LockKind::Exclusive));
+
+        assert_eq!(lock1.current(), LockKind::None);
+        assert_eq!(lock2.current(), LockKind::Exclusive);
+    }
+}
+
+#[test]
+fn test_reserved_check() {
+    for (path, strategy) in test_files(".test_reserved_check", SHARED_STRATEGIES) {
+        let mut lock1 = Lock::with_strategy(&path, strategy).unwrap();
+        let mut lock2 = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(lock1.lock(LockKind::Shared));
+        assert!(lock2.lock(LockKind::Shared));
+        assert!(!lock2.reserved());
+
+        assert!(lock1.lock(LockKind::Reserved));
+        assert!(lock1.reserved());
+        assert!(lock2.reserved());
+
+        assert!(lock1.lock(LockKind::None));
+        assert!(!lock2.reserved());
+    }
+}
+
+#[test]
+fn test_dotfile_excludes_readers() {
+    let path = test_file(".test_dotfile_excludes_readers");
+    let mut lock1 = Lock::with_strategy(&path, LockStrategy::DotFile).unwrap();
+    let mut lock2 = Lock::with_strategy(&path, LockStrategy::DotFile).unwrap();
     assert!(lock1.lock(LockKind::Shared));
+    assert!(!lock2.lock(LockKind::Shared));
+    assert!(lock2.reserved());
+    assert!(!lock1.reserved());
 
-    let mut lock2 = Lock::new(&path).unwrap();
+    assert!(lock1.lock(LockKind::Exclusive));
+    assert!(lock1.lock(LockKind::None));
     assert!(lock2.lock(LockKind::Shared));
-    assert!(lock2.lock(LockKind::Exclusive));
+}
 
-    assert!(lock1.lock(LockKind::None));
+#[test]
+fn test_none_never_blocks() {
+    let path = test_file(".test_none_never_blocks");
+    let mut lock1 = Lock::with_strategy(&path, LockStrategy::None).unwrap();
+    let mut lock2 = Lock::with_strategy(&path, LockStrategy::None).unwrap();
+    assert!(lock1.lock(LockKind::Shared));
+    assert!(lock1.lock(LockKind::Exclusive));
+    assert!(lock2.lock(LockKind::Shared));
     assert!(lock2.lock(LockKind::Exclusive));
+    assert_eq!(lock1.current(), LockKind::Exclusive);
+
+    let lock3 = Lock::with_strategy(&path, LockStrategy::None).unwrap();
+    assert!(!lock3.reserved());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_posix_locks_survive_close() {
+    let path = test_file(".test_posix_locks_survive_close");
+    let mut lock1 = Lock::with_strategy(&path, LockStrategy::Posix).unwrap();
+    let mut lock2 = Lock::with_strategy(&path, LockStrategy::Posix).unwrap();
+    assert!(lock1.lock(LockKind::Shared));
+    assert!(lock1.lock(LockKind::Exclusive));
+    assert!(!lock2.lock(LockKind::Shared));
+
+    // closing the file of another connection of this process doesn't release the lock (OFD locks
+    // conflict with the POSIX locks of the same process)
+    drop(lock2);
+    let mut probe = Lock::with_strategy(&path, LockStrategy::Ofd).unwrap();
+    assert!(!probe.lock(LockKind::Shared));
+
+    drop(lock1);
+    assert!(probe.lock(LockKind::Shared));
+}
+
+#[test]
+fn test_lock_proxy_file() {
+    for (path, strategy) in test_files(".test_lock_proxy_file", SHARED_STRATEGIES) {
+        let proxy = path.with_extension("proxy");
+        fs::remove_file(&proxy).ok();
+        let vfs = TestVfs::default().with_lock_strategy(strategy);
+        let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Write);
+        let mut conn = vfs.open(path.to_str().unwrap(), opts).unwrap();
+        assert_eq!(conn.lock_proxy_file(None).unwrap().unwrap(), None);
+
+        // the proxy file gets created and locked instead of the database
+        let proxy_str = proxy.to_str().unwrap();
+        let current = conn.lock_proxy_file(Some(proxy_str)).unwrap().unwrap();
+        assert_eq!(current.as_deref(), Some(proxy_str));
+        assert!(conn.lock(LockKind::Shared).unwrap());
+        assert!(conn.lock(LockKind::Exclusive).unwrap());
+        assert!(!Lock::with_strategy(&proxy, strategy)
+            .unwrap()
+            .lock(LockKind::Shared));
+        let mut db_lock = Lock::with_strategy(&path, strategy).unwrap();
+        assert!(db_lock.lock(LockKind::Shared));
+        assert!(db_lock.lock(LockKind::None));
+
+        // cannot be changed while locked
+        let err = conn.lock_proxy_file(Some("")).unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
 
-    assert_eq!(lock1.current(), LockKind::None);
-    assert_eq!(lock2.current(), LockKind::Exclusive);
+        // back to locking the database itself
+        assert!(conn.unlock(LockKind::None).unwrap());
+        assert_eq!(conn.lock_proxy_file(Some("")).unwrap().unwrap(), None);
+        assert!(conn.lock(LockKind::Shared).unwrap());
+        assert!(conn.lock(LockKind::Exclusive).unwrap());
+        assert!(!db_lock.lock(LockKind::Shared));
+    }
 }
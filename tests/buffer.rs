@@ -0,0 +1,142 @@
+#![cfg(feature = "buffer")]
+
+mod common;
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use sqlite_vfs::buffer::BufferedVfs;
+
+/// Insert a single large row and return the number of writes to the main database it took.
+fn insert_large_row(mem: &MemVfs, vfs: &str) -> usize {
+    let conn = open(vfs, "main.db").unwrap();
+    conn.execute_batch("CREATE TABLE t (x)").unwrap();
+    let writes = mem.writes("main.db");
+    conn.execute("INSERT INTO t VALUES (randomblob(200000))", [])
+        .unwrap();
+    mem.writes("main.db") - writes
+}
+
+#[test]
+fn test_writes_are_coalesced() {
+    let mem = MemVfs::default();
+    let unbuffered = insert_large_row(&mem, &register_vfs("mem", mem.clone()));
+
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem.clone(), 16 * 1024 * 1024));
+    let buffered = insert_large_row(&mem, &vfs);
+    assert!(unbuffered > 40, "{} unbuffered writes", unbuffered);
+    assert!(buffered <= 2, "{} buffered writes", buffered);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn), 1);
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_reads_of_buffered_data() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem.clone(), 16 * 1024 * 1024));
+    let conn = open(&vfs, "main.db").unwrap();
+    // a tiny page cache makes SQLite read back the pages it wrote (and spilled) before the commit
+    conn.execute_batch(
+        "PRAGMA cache_size = 2;
+         CREATE TABLE t (x);
+         BEGIN;
+         INSERT INTO t VALUES (randomblob(100000));
+         INSERT INTO t VALUES (randomblob(100000));",
+    )
+    .unwrap();
+    let len: i64 = conn
+        .query_row("SELECT sum(length(x)) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(len, 200000);
+    conn.execute_batch("COMMIT").unwrap();
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_flush_on_unlock_without_sync() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem.clone(), 16 * 1024 * 1024));
+    let conn1 = open(&vfs, "main.db").unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    conn1
+        .execute_batch(
+            "PRAGMA synchronous = OFF;
+             CREATE TABLE t (x);
+             INSERT INTO t VALUES (1);",
+        )
+        .unwrap();
+    assert_eq!(count(&conn2), 1);
+    conn2.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    assert_eq!(count(&conn1), 2);
+}
+
+#[test]
+fn test_journal_is_written_through() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem.clone(), 16 * 1024 * 1024));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT randomblob(1000) FROM n;
+         PRAGMA synchronous = OFF;
+         PRAGMA cache_size = 2;
+         BEGIN;
+         UPDATE t SET x = randomblob(1000);",
+    )
+    .unwrap();
+    // the journal already holds the original pages, before any changed page could be flushed
+    let journal = mem.file("main.db-journal").unwrap();
+    assert!(
+        journal.len() > 100 * 1000,
+        "{} bytes journaled",
+        journal.len()
+    );
+    conn.execute_batch("ROLLBACK").unwrap();
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_small_capacity() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem, 8 * 1024));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         INSERT INTO t VALUES (randomblob(100000));
+         UPDATE t SET x = randomblob(50000);",
+    )
+    .unwrap();
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_wal_checkpoint_without_sync() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("buffered", BufferedVfs::new(mem, 16 * 1024 * 1024));
+    let conn1 = open(&vfs, "main.db").unwrap();
+    conn1
+        .execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = OFF;
+             CREATE TABLE t (x);
+             INSERT INTO t VALUES (1);",
+        )
+        .unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn2), 1);
+
+    // the checkpoint neither syncs nor releases the SHARED lock of the connection, but other
+    // connections read the checkpointed pages from the database right away
+    conn1
+        .execute_batch(
+            "INSERT INTO t VALUES (2);
+             PRAGMA wal_checkpoint(TRUNCATE);",
+        )
+        .unwrap();
+    let conn3 = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn3), 2);
+    assert_eq!(count(&conn2), 2);
+    assert_eq!(integrity_check(&conn2), "ok");
+}
@@ -0,0 +1,94 @@
+#![cfg(feature = "cache")]
+
+mod common;
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use sqlite_vfs::cache::CachedVfs;
+
+fn setup(conn: &rusqlite::Connection) {
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         INSERT INTO t VALUES (randomblob(10000));
+         INSERT INTO t VALUES (randomblob(10000));",
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_repeated_reads_are_cached() {
+    // Without a change counter of the handle, the one of the database header is read instead.
+    for (mem, expected) in [
+        (MemVfs::default(), 0),
+        (MemVfs::default().without_change_counter(), 3),
+    ] {
+        let vfs = register_vfs("cached", CachedVfs::new(mem.clone(), 1024 * 1024));
+        let conn = open(&vfs, "main.db").unwrap();
+        setup(&conn);
+
+        let sum = "SELECT sum(length(x)) FROM t";
+        let first: i64 = conn.query_row(sum, [], |row| row.get(0)).unwrap();
+        let reads = mem.reads("main.db");
+        for _ in 0..3 {
+            let again: i64 = conn.query_row(sum, [], |row| row.get(0)).unwrap();
+            assert_eq!(again, first);
+        }
+        assert_eq!(mem.reads("main.db") - reads, expected);
+    }
+}
+
+#[test]
+fn test_changes_of_other_connections_invalidate() {
+    for mem in [
+        MemVfs::default(),
+        MemVfs::default().without_change_counter(),
+    ] {
+        let vfs1 = register_vfs("cached", CachedVfs::new(mem.clone(), 1024 * 1024));
+        let vfs2 = register_vfs("cached", CachedVfs::new(mem.clone(), 1024 * 1024));
+        let conn1 = open(&vfs1, "main.db").unwrap();
+        let conn2 = open(&vfs2, "main.db").unwrap();
+        setup(&conn1);
+        assert_eq!(count(&conn1), 2);
+        assert_eq!(count(&conn2), 2);
+
+        conn2.execute("DELETE FROM t", []).unwrap();
+        conn2.execute("INSERT INTO t VALUES (42)", []).unwrap();
+        assert_eq!(count(&conn1), 1);
+        let x: i64 = conn1
+            .query_row("SELECT x FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(x, 42);
+
+        conn1.execute("INSERT INTO t VALUES (43)", []).unwrap();
+        assert_eq!(count(&conn2), 2);
+    }
+}
+
+#[test]
+fn test_small_capacity() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("cached", CachedVfs::new(mem, 2 * 4096));
+    let conn = open(&vfs, "main.db").unwrap();
+    setup(&conn);
+    for _ in 0..3 {
+        conn.execute("UPDATE t SET x = randomblob(10000)", [])
+            .unwrap();
+        assert_eq!(integrity_check(&conn), "ok");
+    }
+}
+
+#[test]
+fn test_wal_mode_is_not_cached() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("cached", CachedVfs::new(mem.clone(), 1024 * 1024));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; PRAGMA journal_mode = WAL;")
+        .unwrap();
+    setup(&conn);
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+
+    let reads = mem.reads("main.db");
+    conn.execute_batch("PRAGMA cache_size = 0").unwrap();
+    assert_eq!(count(&conn), 2);
+    assert!(mem.reads("main.db") > reads);
+}
@@ -0,0 +1,179 @@
+#![cfg(feature = "checksum")]
+
+mod common;
+
+use common::{open, register_vfs, MemVfs};
+use rusqlite::config::DbConfig;
+use sqlite_vfs::checksum::{ChecksumVfs, RESERVED_BYTES};
+
+const IOERR_DATA: i32 = rusqlite::ffi::SQLITE_IOERR | (32 << 8);
+
+fn file_control(conn: &rusqlite::Connection, op: i32, arg: *mut std::ffi::c_void) -> i32 {
+    let db = b"main\0";
+    unsafe { rusqlite::ffi::sqlite3_file_control(conn.handle(), db.as_ptr() as _, op, arg) }
+}
+
+fn reserve_bytes(conn: &rusqlite::Connection) {
+    let mut n = RESERVED_BYTES as i32;
+    let rc = file_control(
+        conn,
+        rusqlite::ffi::SQLITE_FCNTL_RESERVE_BYTES,
+        &mut n as *mut i32 as _,
+    );
+    assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+}
+
+fn checksum_verification(conn: &rusqlite::Connection) -> String {
+    conn.query_row("PRAGMA checksum_verification", [], |row| row.get(0))
+        .unwrap()
+}
+
+fn create(vfs: &str) {
+    let conn = open(vfs, "main.db").unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "CREATE TABLE t (text TEXT);
+         INSERT INTO t VALUES ('original value');",
+    )
+    .unwrap();
+}
+
+/// Flip a byte of the text stored on the second page.
+fn corrupt(mem: &MemVfs) {
+    let mut data = mem.file("main.db").unwrap();
+    let pos = data
+        .windows(14)
+        .position(|w| w == b"original value")
+        .unwrap();
+    data[pos] = b'O';
+    mem.set_file("main.db", data);
+}
+
+#[test]
+fn test_checksums_are_verified() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("checksum", ChecksumVfs::new(mem.clone()));
+    create(&vfs);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    let text: String = conn
+        .query_row("SELECT text FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(text, "original value");
+    assert_eq!(checksum_verification(&conn), "1");
+    drop(conn);
+
+    corrupt(&mem);
+    let conn = open(&vfs, "main.db").unwrap();
+    match conn.query_row("SELECT text FROM t", [], |row| row.get::<_, String>(0)) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.extended_code, IOERR_DATA),
+        result => panic!("expected checksum fault, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_disable_verification() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("checksum", ChecksumVfs::new(mem.clone()));
+    create(&vfs);
+    corrupt(&mem);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA checksum_verification = OFF")
+        .unwrap();
+    assert_eq!(checksum_verification(&conn), "0");
+    let text: String = conn
+        .query_row("SELECT text FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(text, "Original value");
+
+    conn.execute_batch("PRAGMA checksum_verification = ON")
+        .unwrap();
+    assert_eq!(checksum_verification(&conn), "1");
+}
+
+#[test]
+fn test_without_reserved_bytes() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("checksum", ChecksumVfs::new(mem.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (text TEXT);
+         INSERT INTO t VALUES ('original value');",
+    )
+    .unwrap();
+    assert_eq!(checksum_verification(&conn), "0");
+
+    // cannot be enabled without checksums
+    conn.execute_batch("PRAGMA checksum_verification = ON")
+        .unwrap();
+    assert_eq!(checksum_verification(&conn), "0");
+    drop(conn);
+
+    corrupt(&mem);
+    let conn = open(&vfs, "main.db").unwrap();
+    let text: String = conn
+        .query_row("SELECT text FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(text, "Original value");
+}
+
+#[test]
+fn test_cksm_file_control() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("checksum", ChecksumVfs::new(mem));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    let mut file: *mut std::ffi::c_void = std::ptr::null_mut();
+    let rc = file_control(
+        &conn,
+        rusqlite::ffi::SQLITE_FCNTL_CKSM_FILE,
+        &mut file as *mut _ as _,
+    );
+    assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+    assert!(!file.is_null());
+}
+
+#[test]
+fn test_wal_mode() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("checksum", ChecksumVfs::new(mem.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.set_db_config(DbConfig::SQLITE_DBCONFIG_NO_CKPT_ON_CLOSE, true)
+        .unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE;
+         PRAGMA journal_mode = WAL;
+         CREATE TABLE t (text TEXT);
+         INSERT INTO t VALUES ('original value');",
+    )
+    .unwrap();
+    drop(conn);
+    assert!(!mem.file("main.db-wal").unwrap().is_empty());
+
+    // the pages are read from the WAL as it wasn't checkpointed
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+        .unwrap();
+    let text: String = conn
+        .query_row("SELECT text FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(text, "original value");
+    assert_eq!(checksum_verification(&conn), "1");
+
+    // checkpointed pages get their checksum
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    drop(conn);
+    corrupt(&mem);
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+        .unwrap();
+    match conn.query_row("SELECT text FROM t", [], |row| row.get::<_, String>(0)) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.extended_code, IOERR_DATA),
+        result => panic!("expected checksum fault, got {:?}", result),
+    }
+}
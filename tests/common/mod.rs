@@ -0,0 +1,288 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::lock::{DatabaseLock, LockManager};
+use sqlite_vfs::wal_index::{MemWalIndex, WalIndexManager};
+use sqlite_vfs::{register, DatabaseHandle, LockKind, OpenAccess, OpenOptions, Vfs};
+
+/// Register `vfs` under a unique name derived from `prefix` and return the name.
+pub fn register_vfs<V: Vfs>(prefix: &str, vfs: V) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("{}-{}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed));
+    register(&name, vfs, false).unwrap();
+    name
+}
+
+/// Open `path` (which can be a `file:` URI) using the VFS `vfs`.
+pub fn open(vfs: &str, path: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        vfs,
+    )
+}
+
+/// The number of rows in table `t`.
+pub fn count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// The sum of column `n` of table `t`.
+pub fn sum(conn: &Connection) -> i64 {
+    conn.query_row("SELECT sum(n) FROM t", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// The result of `PRAGMA integrity_check` (`"ok"` for a healthy database).
+pub fn integrity_check(conn: &Connection) -> String {
+    conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .unwrap()
+}
+
+/// An in-memory [Vfs] for tests. Files are shared between all handles (and clones of the [MemVfs]),
+/// locks and wal indexes are managed in process memory.
+#[derive(Default, Clone)]
+pub struct MemVfs {
+    files: Arc<Mutex<HashMap<String, Arc<MemFile>>>>,
+    locks: LockManager,
+    wal_indexes: WalIndexManager,
+    temp_counter: Arc<AtomicUsize>,
+    no_change_counter: bool,
+}
+
+#[derive(Default)]
+pub struct MemFile {
+    pub data: Mutex<Vec<u8>>,
+    /// Incremented on each change.
+    version: AtomicU64,
+    /// The number of reads so far.
+    reads: AtomicUsize,
+    /// The number of writes so far.
+    writes: AtomicUsize,
+}
+
+pub struct MemHandle {
+    db: String,
+    file: Arc<MemFile>,
+    lock: DatabaseLock,
+    wal_indexes: WalIndexManager,
+    no_change_counter: bool,
+}
+
+impl MemVfs {
+    /// Don't provide a [DatabaseHandle::change_counter].
+    pub fn without_change_counter(mut self) -> Self {
+        self.no_change_counter = true;
+        self
+    }
+
+    /// The number of reads from the file `db` so far.
+    pub fn reads(&self, db: &str) -> usize {
+        let files = self.files.lock().unwrap();
+        files.get(db).map_or(0, |f| f.reads.load(Ordering::Relaxed))
+    }
+
+    /// The number of writes to the file `db` so far.
+    pub fn writes(&self, db: &str) -> usize {
+        let files = self.files.lock().unwrap();
+        files
+            .get(db)
+            .map_or(0, |f| f.writes.load(Ordering::Relaxed))
+    }
+
+    /// The raw content of the file `db`.
+    pub fn file(&self, db: &str) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files.get(db).map(|f| f.data.lock().unwrap().clone())
+    }
+
+    /// Replace the raw content of the file `db`.
+    pub fn set_file(&self, db: &str, data: Vec<u8>) {
+        let mut files = self.files.lock().unwrap();
+        let file = files.entry(db.to_string()).or_default();
+        *file.data.lock().unwrap() = data;
+    }
+
+    /// The names of all existing files.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.files.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+impl Vfs for MemVfs {
+    type Handle = MemHandle;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let file = match (files.get(db), opts.access) {
+            (Some(_), OpenAccess::CreateNew) => return Err(ErrorKind::AlreadyExists.into()),
+            (Some(file), _) => file.clone(),
+            (None, OpenAccess::Create | OpenAccess::CreateNew) => {
+                files.entry(db.to_string()).or_default().clone()
+            }
+            (None, _) => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(MemHandle {
+            db: db.to_string(),
+            file,
+            lock: self.locks.lock(db),
+            wal_indexes: self.wal_indexes.clone(),
+            no_change_counter: self.no_change_counter,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        match self.files.lock().unwrap().remove(db) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.files.lock().unwrap().contains_key(db))
+    }
+
+    fn temporary_name(&self) -> String {
+        format!("temp-{}", self.temp_counter.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = (i * 31 % 256) as i8;
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        std::thread::sleep(duration);
+        duration
+    }
+}
+
+impl DatabaseHandle for MemHandle {
+    type WalIndex = MemWalIndex;
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.data.lock().unwrap().len() as u64)
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.reads.fetch_add(1, Ordering::Relaxed);
+        let data = self.file.data.lock().unwrap();
+        let offset = offset as usize;
+        if offset + buf.len() > data.len() {
+            let n = data.len().saturating_sub(offset);
+            buf[..n].copy_from_slice(&data[offset.min(data.len())..]);
+            buf[n..].fill(0);
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.version.fetch_add(1, Ordering::Relaxed);
+        self.file.writes.fetch_add(1, Ordering::Relaxed);
+        let mut data = self.file.data.lock().unwrap();
+        let offset = offset as usize;
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.version.fetch_add(1, Ordering::Relaxed);
+        self.file.data.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lock(&mut self, to: LockKind) -> Result<bool, std::io::Error> {
+        Ok(self.lock.lock(to))
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(self.lock.reserved())
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock.current_lock())
+    }
+
+    fn change_counter(&self) -> Result<Option<u64>, std::io::Error> {
+        if self.no_change_counter {
+            return Ok(None);
+        }
+        Ok(Some(self.file.version.load(Ordering::Relaxed)))
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, std::io::Error> {
+        Ok(self.wal_indexes.wal_index(&self.db))
+    }
+}
+
+// The workspace enables the `sqlite_test` feature (via `test-vfs`), which expects the hooks of
+// SQLite's TCL test harness to be linked in. The bundled SQLite doesn't provide them.
+#[cfg(feature = "sqlite_test")]
+mod test_hooks {
+    #[no_mangle]
+    extern "C" fn sqlite3_inc_sync_count() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_inc_fullsync_count() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_set_current_time(_current_time: i32) {}
+    #[no_mangle]
+    extern "C" fn sqlite3_get_current_time() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_dec_diskfull_pending() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_get_diskfull_pending() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_set_diskfull() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_inc_open_file_count() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_dec_open_file_count() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_dec_io_error_pending() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_get_io_error_persist() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_get_io_error_hit() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_inc_io_error_hit() {}
+    #[no_mangle]
+    extern "C" fn sqlite3_set_io_error_hit(_hit: i32) {}
+    #[no_mangle]
+    extern "C" fn sqlite3_get_io_error_benign() -> i32 {
+        0
+    }
+    #[no_mangle]
+    extern "C" fn sqlite3_inc_io_error_hardhit() {}
+}
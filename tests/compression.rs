@@ -0,0 +1,161 @@
+#![cfg(feature = "compression")]
+
+mod common;
+
+use common::{count, open, register_vfs, MemVfs};
+use sqlite_vfs::compression::{CompressedVfs, Compression};
+use sqlite_vfs::OpenKind;
+
+const MAGIC: &[u8] = b"SQLVFSZ\0";
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn populate(conn: &rusqlite::Connection) {
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, text TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+         INSERT INTO t SELECT i, 'a rather repetitive text ' || (i % 10) FROM n;",
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_compressed_roundtrip() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("compressed", CompressedVfs::new(mem.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    populate(&conn);
+    let logical: i64 = conn
+        .query_row(
+            "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    drop(conn);
+
+    let physical = mem.file("main.db").unwrap().len() as i64;
+    assert!(
+        physical * 3 < logical,
+        "expected compression (physical={} logical={})",
+        physical,
+        logical
+    );
+
+    let conn = open(&vfs, "main.db").unwrap();
+    let (count, integrity): (i64, String) = conn
+        .query_row(
+            "SELECT (SELECT count(*) FROM t), (SELECT integrity_check FROM pragma_integrity_check)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(count, 2000);
+    assert_eq!(integrity, "ok");
+}
+
+#[test]
+fn test_journal_stays_uncompressed() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("compressed", CompressedVfs::new(mem.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA journal_mode = PERSIST").unwrap();
+    populate(&conn);
+    conn.execute("DELETE FROM t WHERE id % 2 = 0", []).unwrap();
+
+    // a persisted journal only has its header zeroed, the page images are kept as is
+    let journal = mem.file("main.db-journal").unwrap();
+    assert!(!journal.starts_with(MAGIC));
+    assert!(contains(&journal, b"a rather repetitive text"));
+}
+
+#[test]
+fn test_compressed_journal() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs(
+        "compressed",
+        CompressedVfs::new(mem.clone()).with_compression(
+            OpenKind::MainJournal,
+            Compression {
+                block_size: 1024,
+                level: 1,
+            },
+        ),
+    );
+
+    let conn = open(&vfs, "main.db").unwrap();
+    populate(&conn);
+    conn.execute_batch(
+        "PRAGMA journal_mode = PERSIST;
+         BEGIN;
+         DELETE FROM t WHERE id % 2 = 0;
+         ROLLBACK;
+         DELETE FROM t WHERE id > 1000;",
+    )
+    .unwrap();
+
+    let journal = mem.file("main.db-journal").unwrap();
+    assert!(journal.starts_with(MAGIC));
+
+    assert_eq!(count(&conn), 1000);
+}
+
+#[test]
+fn test_changes_are_visible_to_other_connections() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("compressed", CompressedVfs::new(mem));
+
+    let conn1 = open(&vfs, "main.db").unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    populate(&conn1);
+
+    assert_eq!(count(&conn2), 2000);
+
+    conn2.execute("DELETE FROM t WHERE id > 1000", []).unwrap();
+    assert_eq!(count(&conn1), 1000);
+}
+
+#[cfg(feature = "crash")]
+#[test]
+fn test_power_loss_at_any_point() {
+    use sqlite_vfs::crash::{CrashVfs, PowerLoss};
+
+    let workload = |vfs: &str| -> rusqlite::Result<()> {
+        let conn = open(vfs, "main.db")?;
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, text TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+             INSERT INTO t SELECT i, 'a rather repetitive text ' || (i % 10) FROM n;",
+        )?;
+        // changes the size of the compressed blocks, so that they get moved
+        for _ in 0..3 {
+            conn.execute("UPDATE t SET text = text || hex(randomblob(8))", [])?;
+        }
+        Ok(())
+    };
+
+    for point in 0.. {
+        let mem = MemVfs::default();
+        let vfs = CrashVfs::new(mem.clone()).crash_at(point, PowerLoss::Random(point as u64));
+        let crash = vfs.crash();
+        workload(&register_vfs("crash", CompressedVfs::new(vfs))).ok();
+        if !crash.crashed() {
+            // the workload completed before reaching the crash point
+            break;
+        }
+
+        let conn = open(
+            &register_vfs("recovered", CompressedVfs::new(mem)),
+            "main.db",
+        )
+        .unwrap_or_else(|err| panic!("crash point {}: {}", point, err));
+        let check = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get::<_, String>(0))
+            .unwrap_or_else(|err| panic!("crash point {}: {}", point, err));
+        assert_eq!(check, "ok", "crash point {}", point);
+    }
+}
@@ -0,0 +1,146 @@
+#![cfg(feature = "crash")]
+
+mod common;
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use sqlite_vfs::crash::{CrashTest, CrashVfs, PowerLoss};
+
+#[test]
+fn test_unsynced_writes_are_lost() {
+    for (loss, expected) in [(PowerLoss::DropAll, 1), (PowerLoss::KeepAll, 2)] {
+        let mem = MemVfs::default();
+        let vfs = CrashVfs::new(mem.clone());
+        let crash = vfs.crash();
+        let vfs = register_vfs("crash", vfs);
+
+        let conn = open(&vfs, "main.db").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (x);
+             INSERT INTO t VALUES (1);
+             PRAGMA synchronous = OFF;
+             INSERT INTO t VALUES (2);",
+        )
+        .unwrap();
+        assert!(crash.unsynced() > 0);
+
+        crash.power_loss(loss).unwrap();
+        assert!(crash.crashed());
+        assert!(conn.execute("INSERT INTO t VALUES (3)", []).is_err());
+        drop(conn);
+
+        let conn = open(&register_vfs("recovered", CrashVfs::new(mem)), "main.db").unwrap();
+        assert_eq!(count(&conn), expected);
+        assert_eq!(integrity_check(&conn), "ok");
+    }
+}
+
+#[test]
+fn test_random_power_loss_during_transaction() {
+    for seed in 0..32 {
+        for psow in [true, false] {
+            let mem = MemVfs::default();
+            let vfs = CrashVfs::new(mem.clone());
+            let crash = vfs.crash();
+            let vfs = register_vfs("crash", vfs);
+
+            let path = format!("file:main.db?psow={}", psow as u8);
+            let conn = open(&vfs, &path).unwrap();
+            conn.execute_batch(
+                "PRAGMA cache_size = 2;
+                 CREATE TABLE t (x);
+                 CREATE VIEW rows AS WITH RECURSIVE n(i) AS (
+                   SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100
+                 ) SELECT i FROM n;
+                 INSERT INTO t SELECT randomblob(500) FROM rows;
+                 BEGIN;
+                 UPDATE t SET x = randomblob(600);
+                 INSERT INTO t SELECT randomblob(500) FROM rows;",
+            )
+            .unwrap();
+            // the cache spilled: the journal got synced, the database pages were written after
+            assert!(crash.unsynced() > 0);
+
+            crash.power_loss(PowerLoss::Random(seed)).unwrap();
+            drop(conn);
+
+            let conn = open(&register_vfs("recovered", CrashVfs::new(mem)), &path).unwrap();
+            assert_eq!(integrity_check(&conn), "ok", "seed {}", seed);
+            assert_eq!(count(&conn), 100, "seed {}", seed);
+        }
+    }
+}
+
+/// Update all rows of `t` in a single transaction, three times.
+fn update_all(vfs: &str, path: &str, setup: &str) -> rusqlite::Result<()> {
+    let conn = open(vfs, path)?;
+    conn.execute_batch(setup)?;
+    conn.execute_batch(
+        "CREATE TABLE t (n, x);
+         CREATE VIEW rows AS WITH RECURSIVE n(i) AS (
+           SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50
+         ) SELECT i FROM n;
+         INSERT INTO t SELECT 0, randomblob(500) FROM rows;",
+    )?;
+    for _ in 0..3 {
+        conn.execute("UPDATE t SET n = n + 1, x = randomblob(500)", [])?;
+    }
+    Ok(())
+}
+
+/// All rows of `t` (if it exists) got updated the same number of times.
+fn updated_atomically(vfs: &str, path: &str) -> Result<(), String> {
+    let conn = open(vfs, path).map_err(|err| err.to_string())?;
+    let exists: bool = conn
+        .query_row(
+            "SELECT count(*) > 0 FROM sqlite_schema WHERE name = 't'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|err| err.to_string())?;
+    if !exists {
+        return Ok(());
+    }
+    let distinct: i64 = conn
+        .query_row("SELECT count(DISTINCT n) FROM t", [], |row| row.get(0))
+        .map_err(|err| err.to_string())?;
+    match distinct {
+        0 | 1 => Ok(()),
+        n => Err(format!("{} distinct update counts", n)),
+    }
+}
+
+#[test]
+fn test_crash_test_passes() {
+    for loss in [PowerLoss::DropAll, PowerLoss::Random(0)] {
+        let points = CrashTest::new(MemVfs::default)
+            .loss(loss)
+            .run(
+                |vfs| update_all(vfs, "main.db", "PRAGMA cache_size = 2"),
+                |vfs| updated_atomically(vfs, "main.db"),
+            )
+            .unwrap();
+        assert!(points > 20, "only {} crash points", points);
+    }
+}
+
+#[test]
+fn test_crash_test_reports_failure() {
+    let path = "file:main.db?psow=0";
+    let test = || CrashTest::new(MemVfs::default).database(path);
+    let workload = |vfs: &str| update_all(vfs, path, "PRAGMA journal_mode = OFF");
+    let failure = test()
+        .loss(PowerLoss::KeepAll)
+        .run(workload, |vfs| updated_atomically(vfs, path))
+        .unwrap_err();
+    assert!(failure.boundary.as_ref().unwrap().starts_with("write of"));
+    assert!(failure.to_string().contains(".point("), "{}", failure);
+
+    // the failure can be reproduced
+    let reproduced = test()
+        .loss(failure.loss)
+        .point(failure.point)
+        .run(workload, |vfs| updated_atomically(vfs, path))
+        .unwrap_err();
+    assert_eq!(reproduced.point, failure.point);
+    assert_eq!(reproduced.error, failure.error);
+}
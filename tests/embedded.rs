@@ -0,0 +1,83 @@
+#![cfg(feature = "embedded")]
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use common::{integrity_check, open, register_vfs, sum};
+use rusqlite::ErrorCode;
+use sqlite_vfs::embedded::StaticVfs;
+
+/// A database with 100 rows, created with the default VFS.
+fn database() -> Vec<u8> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "sqlite-vfs-embedded-{}-{}.db",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::remove_file(&path).ok();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (n, x);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT i, randomblob(500) FROM n;",
+    )
+    .unwrap();
+    drop(conn);
+    let data = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    data
+}
+
+#[test]
+fn test_static_and_shared_bytes() {
+    let data: &'static [u8] = Box::leak(database().into_boxed_slice());
+    let shared: Arc<[u8]> = database().into();
+    let vfs = StaticVfs::new()
+        .with_database("static.db", data)
+        .with_database("shared.db", shared);
+    let vfs = register_vfs("embedded", vfs);
+
+    for name in ["static.db", "shared.db"] {
+        let conn = open(&vfs, name).unwrap();
+        assert_eq!(sum(&conn), 5050);
+        assert_eq!(integrity_check(&conn), "ok");
+    }
+}
+
+#[test]
+fn test_writes_are_read_only() {
+    let vfs = register_vfs(
+        "embedded",
+        StaticVfs::new().with_database("main.db", database()),
+    );
+    let conn = open(&vfs, "main.db").unwrap();
+    let err = conn.execute("DELETE FROM t", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+    assert_eq!(sum(&conn), 5050);
+
+    // temporary files are kept in memory
+    conn.execute_batch(
+        "PRAGMA temp_store = FILE;
+         CREATE TEMP TABLE sorted AS SELECT * FROM t ORDER BY x;
+         UPDATE sorted SET n = 0;",
+    )
+    .unwrap();
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM sorted WHERE n = 0", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(n, 100);
+}
+
+#[test]
+fn test_unknown_database() {
+    let vfs = register_vfs("embedded", StaticVfs::new());
+    let err = open(&vfs, "main.db")
+        .and_then(|conn| conn.execute("CREATE TABLE t (x)", []))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+}
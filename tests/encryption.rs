@@ -0,0 +1,253 @@
+#![cfg(feature = "encryption")]
+
+mod common;
+
+use common::{open, register_vfs, MemVfs};
+use rusqlite::ErrorCode;
+use sqlite_vfs::encryption::{EncryptedVfs, RESERVED_BYTES};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn reserve_bytes(conn: &rusqlite::Connection) {
+    let mut n = RESERVED_BYTES as i32;
+    let db = b"main\0";
+    let rc = unsafe {
+        rusqlite::ffi::sqlite3_file_control(
+            conn.handle(),
+            db.as_ptr() as _,
+            rusqlite::ffi::SQLITE_FCNTL_RESERVE_BYTES,
+            &mut n as *mut i32 as _,
+        )
+    };
+    assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn test_uri_key_roundtrip() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let uri = format!("file:main.db?key={}", KEY);
+    let conn = open(&vfs, &uri).unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "CREATE TABLE t (secret TEXT);
+         BEGIN;
+         INSERT INTO t VALUES ('top secret value');
+         UPDATE t SET secret = 'top secret value!';
+         COMMIT;",
+    )
+    .unwrap();
+    drop(conn);
+
+    let raw = mem.file("main.db").unwrap();
+    assert!(!raw.is_empty());
+    assert!(!contains(&raw, b"SQLite format 3"));
+    assert!(!contains(&raw, b"top secret"));
+
+    let conn = open(&vfs, &uri).unwrap();
+    let secret: String = conn
+        .query_row("SELECT secret FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(secret, "top secret value!");
+}
+
+#[test]
+fn test_pragma_key() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(&format!("PRAGMA key = '{}'", KEY))
+        .unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+        .unwrap();
+    drop(conn);
+
+    // without the key, the database cannot be read
+    let conn = open(&vfs, "main.db").unwrap();
+    assert!(conn.query_row("SELECT x FROM t", [], |_| Ok(())).is_err());
+    drop(conn);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(&format!("PRAGMA key = '{}'", KEY))
+        .unwrap();
+    let x: i64 = conn
+        .query_row("SELECT x FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(x, 42);
+}
+
+#[test]
+fn test_journal_is_encrypted() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let uri = format!("file:main.db?key={}", KEY);
+    let conn = open(&vfs, &uri).unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "PRAGMA journal_mode = PERSIST;
+         CREATE TABLE t (secret TEXT);
+         INSERT INTO t VALUES ('first secret');
+         UPDATE t SET secret = 'second secret';",
+    )
+    .unwrap();
+
+    let journal = mem.file("main.db-journal").unwrap();
+    assert!(!journal.is_empty());
+    assert!(!contains(&journal, b"first secret"));
+}
+
+#[test]
+fn test_missing_reserved_bytes() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem));
+
+    let conn = open(&vfs, &format!("file:main.db?key={}", KEY)).unwrap();
+    assert!(conn.execute_batch("CREATE TABLE t (x)").is_err());
+}
+
+#[test]
+fn test_wal_is_encrypted() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let uri = format!("file:main.db?key={}", KEY);
+    let conn = open(&vfs, &uri).unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE;
+         PRAGMA journal_mode = WAL;
+         CREATE TABLE t (secret TEXT);
+         INSERT INTO t VALUES ('wal secret');",
+    )
+    .unwrap();
+
+    let wal = mem.file("main.db-wal").unwrap();
+    assert!(!wal.is_empty());
+    assert!(!contains(&wal, b"wal secret"));
+
+    let secret: String = conn
+        .query_row("SELECT secret FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(secret, "wal secret");
+}
+
+#[test]
+fn test_zeroed_page_is_rejected() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let uri = format!("file:main.db?key={}", KEY);
+    let conn = open(&vfs, &uri).unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+        .unwrap();
+    drop(conn);
+
+    let mut raw = mem.file("main.db").unwrap();
+    raw[4096..8192].fill(0);
+    mem.set_file("main.db", raw);
+    let conn = open(&vfs, &uri).unwrap();
+    let err = conn
+        .query_row("SELECT x FROM t", [], |_| Ok(()))
+        .unwrap_err();
+    // fails to decrypt instead of reading a (corrupt) page of zeros
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::SystemIoFailure));
+}
+
+#[test]
+fn test_wal_commits_are_padded_to_blocks() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let uri = format!("file:main.db?key={}", KEY);
+    let conn = open(&vfs, &uri).unwrap();
+    reserve_bytes(&conn);
+    conn.execute_batch(
+        "PRAGMA locking_mode = EXCLUSIVE;
+         PRAGMA journal_mode = WAL;
+         PRAGMA synchronous = FULL;
+         CREATE TABLE t (x);",
+    )
+    .unwrap();
+
+    // without "powersafe-overwrite", SQLite pads each commit to the end of a sector (block) by
+    // repeating its last frame, so that the next transaction doesn't re-encrypt synced frames
+    let frames = || {
+        let len = mem.file("main.db-wal").unwrap().len();
+        let blocks = len.div_ceil(4096 + RESERVED_BYTES);
+        (len - blocks * RESERVED_BYTES - 32) / (4096 + 24)
+    };
+    let mut before = frames();
+    for i in 0..3 {
+        conn.execute("INSERT INTO t VALUES (?)", [i]).unwrap();
+        assert_eq!(frames(), before + 2);
+        before = frames();
+    }
+}
+
+#[test]
+fn test_key_is_per_connection() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem));
+
+    let conn1 = open(&vfs, &format!("file:main.db?key={}", KEY)).unwrap();
+    reserve_bytes(&conn1);
+    conn1
+        .execute_batch("CREATE TABLE t (secret TEXT); INSERT INTO t VALUES ('top secret');")
+        .unwrap();
+
+    // a connection without the key cannot read the database, even while another one has it open
+    let conn2 = open(&vfs, "file:main.db").unwrap();
+    assert!(conn2
+        .query_row("SELECT secret FROM t", [], |_| Ok(()))
+        .is_err());
+
+    // setting a (wrong) key on one connection doesn't affect the others
+    conn2
+        .execute_batch(&format!("PRAGMA key = '{}'", "ff".repeat(32)))
+        .unwrap();
+    assert!(conn2
+        .query_row("SELECT secret FROM t", [], |_| Ok(()))
+        .is_err());
+    let secret: String = conn1
+        .query_row("SELECT secret FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(secret, "top secret");
+}
+
+#[test]
+fn test_pragma_key_is_shared_with_wal() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("encrypted", EncryptedVfs::new(mem.clone()));
+
+    let conn1 = open(&vfs, "main.db").unwrap();
+    conn1
+        .execute_batch(&format!("PRAGMA key = '{}'", KEY))
+        .unwrap();
+    reserve_bytes(&conn1);
+    conn1
+        .execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE t (secret TEXT);
+             INSERT INTO t VALUES ('wal secret');",
+        )
+        .unwrap();
+    assert!(!contains(&mem.file("main.db-wal").unwrap(), b"wal secret"));
+
+    let conn2 = open(&vfs, "main.db").unwrap();
+    conn2
+        .execute_batch(&format!("PRAGMA key = '{}'", KEY))
+        .unwrap();
+    let secret: String = conn2
+        .query_row("SELECT secret FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(secret, "wal secret");
+}
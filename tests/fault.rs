@@ -0,0 +1,96 @@
+#![cfg(feature = "fault")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{count, open, register_vfs, MemVfs};
+use rusqlite::ErrorCode;
+use sqlite_vfs::fault::{Fault, FaultVfs, Op, Rule};
+use sqlite_vfs::OpenKind;
+
+const IOERR_WRITE: i32 = rusqlite::ffi::SQLITE_IOERR | (3 << 8);
+
+#[test]
+fn test_fail_nth_journal_write() {
+    let vfs = FaultVfs::new(MemVfs::default());
+    let faults = vfs.faults();
+    let vfs = register_vfs("fault", vfs);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         INSERT INTO t VALUES (randomblob(10000));",
+    )
+    .unwrap();
+
+    faults.add(
+        Rule::on(Op::Write)
+            .kind(OpenKind::MainJournal)
+            .nth(3)
+            .fail(Fault::Io),
+    );
+    match conn.execute("UPDATE t SET x = randomblob(10000)", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.extended_code, IOERR_WRITE),
+        result => panic!("expected I/O error, got {:?}", result),
+    }
+    assert_eq!(faults.injected(), 1);
+
+    // the rule only fires once
+    conn.execute("INSERT INTO t VALUES (1)", []).unwrap();
+    assert_eq!(count(&conn), 2);
+}
+
+#[test]
+fn test_disk_full() {
+    let vfs = FaultVfs::new(MemVfs::default())
+        .with_rule(Rule::disk_full_after(64 * 1024).kind(OpenKind::MainDb));
+    let faults = vfs.faults();
+    let vfs = register_vfs("fault", vfs);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("CREATE TABLE t (x)").unwrap();
+    match conn.execute("INSERT INTO t VALUES (randomblob(128 * 1024))", []) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => assert_eq!(err.code, ErrorCode::DiskFull),
+        result => panic!("expected disk full, got {:?}", result),
+    }
+
+    // the disk stays full (even for the rollback) until the rule is removed
+    assert!(conn
+        .execute("INSERT INTO t VALUES (randomblob(128 * 1024))", [])
+        .is_err());
+    faults.clear();
+    assert_eq!(count(&conn), 0);
+    conn.execute("INSERT INTO t VALUES (randomblob(128 * 1024))", [])
+        .unwrap();
+    assert_eq!(count(&conn), 1);
+}
+
+#[test]
+fn test_lock_busy() {
+    let vfs = FaultVfs::new(MemVfs::default());
+    let faults = vfs.faults();
+    let vfs = register_vfs("fault", vfs);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.busy_timeout(Duration::ZERO).unwrap();
+    conn.execute_batch("CREATE TABLE t (x)").unwrap();
+
+    faults.add(
+        Rule::on(Op::Lock)
+            .kind(OpenKind::MainDb)
+            .times(2)
+            .fail(Fault::Busy),
+    );
+    match conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0)) {
+        Err(rusqlite::Error::SqliteFailure(err, _)) => {
+            assert_eq!(err.code, ErrorCode::DatabaseBusy)
+        }
+        result => panic!("expected busy, got {:?}", result),
+    }
+
+    // the busy handler retries past the second busy lock
+    conn.busy_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(count(&conn), 0);
+    assert_eq!(faults.injected(), 2);
+}
@@ -0,0 +1,192 @@
+#![cfg(feature = "history")]
+
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use rusqlite::ErrorCode;
+use sqlite_vfs::history::HistoryVfs;
+
+/// The current time as `asof` parameter, after waiting for the clock to move on.
+fn now() -> String {
+    std::thread::sleep(Duration::from_millis(5));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    format!("{:.3}", now.as_secs_f64())
+}
+
+fn insert(conn: &rusqlite::Connection, rows: usize) {
+    conn.execute(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+         INSERT INTO t SELECT i, randomblob(500) FROM n",
+        [rows],
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_open_as_of_past_time() {
+    let vfs = register_vfs("history", HistoryVfs::new(MemVfs::default()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    let created = now();
+    insert(&conn, 100);
+    let inserted = now();
+    conn.execute("DELETE FROM t WHERE n > 50", []).unwrap();
+    let deleted = now();
+    conn.execute("UPDATE t SET x = NULL", []).unwrap();
+
+    for (asof, rows) in [(&created, 0), (&inserted, 100), (&deleted, 50)] {
+        let snapshot = open(&vfs, &format!("file:main.db?asof={}", asof)).unwrap();
+        assert_eq!(count(&snapshot), rows);
+        assert_eq!(integrity_check(&snapshot), "ok");
+    }
+    let snapshot = open(&vfs, &format!("file:main.db?asof={}", deleted)).unwrap();
+    let blobs: i64 = snapshot
+        .query_row("SELECT count(x) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(blobs, 50);
+
+    // the database itself is unaffected
+    assert_eq!(count(&conn), 50);
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_commits_without_sync() {
+    let vfs = register_vfs("history", HistoryVfs::new(MemVfs::default()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA synchronous = OFF; CREATE TABLE t (n, x)")
+        .unwrap();
+    insert(&conn, 100);
+    let inserted = now();
+    conn.execute("DELETE FROM t WHERE n > 50", []).unwrap();
+
+    let snapshot = open(&vfs, &format!("file:main.db?asof={}", inserted)).unwrap();
+    assert_eq!(count(&snapshot), 100);
+    assert_eq!(
+        count(&open(&vfs, &format!("file:main.db?asof={}", now())).unwrap()),
+        50
+    );
+}
+
+#[test]
+fn test_snapshot_is_read_only_and_stable() {
+    let vfs = register_vfs("history", HistoryVfs::new(MemVfs::default()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    insert(&conn, 100);
+    let asof = now();
+
+    let snapshot = open(&vfs, &format!("file:main.db?asof={}", asof)).unwrap();
+    let err = snapshot.execute("DELETE FROM t", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+
+    // later transactions don't show up in an open snapshot
+    conn.execute("DELETE FROM t WHERE n % 2 = 0", []).unwrap();
+    insert(&conn, 10);
+    assert_eq!(count(&snapshot), 100);
+    assert_eq!(count(&conn), 60);
+}
+
+#[test]
+fn test_snapshot_of_truncated_database() {
+    let vfs = register_vfs("history", HistoryVfs::new(MemVfs::default()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    insert(&conn, 200);
+    let asof = now();
+    conn.execute_batch("DELETE FROM t; VACUUM;").unwrap();
+    insert(&conn, 20);
+
+    let snapshot = open(&vfs, &format!("file:main.db?asof={}", asof)).unwrap();
+    assert_eq!(count(&snapshot), 200);
+    assert_eq!(integrity_check(&snapshot), "ok");
+}
+
+#[test]
+fn test_retention() {
+    let files = MemVfs::default();
+    let vfs = HistoryVfs::new(files.clone())
+        .with_retention(Duration::from_millis(100))
+        .with_segment_size(1);
+    let vfs = register_vfs("history", vfs);
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    insert(&conn, 10);
+    let asof = now();
+    insert(&conn, 10);
+    let segments = |files: &MemVfs| {
+        files
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("main.db-history-"))
+            .count()
+    };
+    // a segment per transaction, and the empty current one
+    assert_eq!(segments(&files), 4);
+    assert_eq!(
+        count(&open(&vfs, &format!("file:main.db?asof={}", asof)).unwrap()),
+        10
+    );
+
+    // the next commit deletes the segments older than the retention
+    std::thread::sleep(Duration::from_millis(150));
+    insert(&conn, 10);
+    assert_eq!(segments(&files), 2);
+    let err = open(&vfs, &format!("file:main.db?asof={}", asof))
+        .and_then(|conn| conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0)))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+}
+
+#[test]
+fn test_before_history() {
+    let files = MemVfs::default();
+    let conn = open(&register_vfs("plain", files.clone()), "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    let asof = now();
+
+    let vfs = register_vfs("history", HistoryVfs::new(files));
+    let conn = open(&vfs, "main.db").unwrap();
+    insert(&conn, 10);
+    let err = open(&vfs, &format!("file:main.db?asof={}", asof))
+        .and_then(|conn| conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0)))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+}
+
+#[test]
+fn test_wal_mode_is_refused() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("history", HistoryVfs::new(mem.clone()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("CREATE TABLE t (n, x)", []).unwrap();
+    insert(&conn, 10);
+    let asof = now();
+
+    // switching to WAL mode doesn't take effect (the header update is refused)
+    let _ = conn.execute_batch("PRAGMA journal_mode = WAL");
+    insert(&conn, 10);
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "delete");
+    assert_eq!(count(&conn), 20);
+    assert!(!mem.names().contains(&"main.db-wal".to_string()));
+    drop(conn);
+
+    // a database switched to WAL mode elsewhere can neither be recorded nor snapshotted
+    let conn = open(&register_vfs("mem", mem), "main.db").unwrap();
+    conn.execute_batch("PRAGMA journal_mode = WAL").unwrap();
+    insert(&conn, 10);
+    for name in [format!("file:main.db?asof={}", asof), "main.db".to_string()] {
+        let err = open(&vfs, &name)
+            .and_then(|conn| {
+                conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+            })
+            .unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+    }
+}
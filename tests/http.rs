@@ -0,0 +1,275 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{integrity_check, open, register_vfs, sum};
+use rusqlite::ErrorCode;
+use sqlite_vfs::http::HttpVfs;
+
+/// A loopback HTTP server that serves `files` and answers `Range` requests.
+struct Server {
+    addr: SocketAddr,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Whether `Range` headers are ignored.
+    ignore_ranges: Arc<AtomicBool>,
+    /// The method, path and range header of each request received.
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    fn start(files: HashMap<String, Vec<u8>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server {
+            addr,
+            files: Arc::new(Mutex::new(files)),
+            ignore_ranges: Arc::new(AtomicBool::new(false)),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        };
+        let (files, ignore_ranges, log) = (
+            server.files.clone(),
+            server.ignore_ranges.clone(),
+            server.requests.clone(),
+        );
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let ignore_ranges = ignore_ranges.load(Ordering::Relaxed);
+                serve(stream.unwrap(), &files, ignore_ranges, &log);
+            }
+        });
+        server
+    }
+
+    fn base(&self) -> String {
+        format!("http://{}/dbs", self.addr)
+    }
+
+    fn requests(&self, method: &str) -> usize {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().filter(|r| r.starts_with(method)).count()
+    }
+}
+
+/// A strong entity tag of `data`.
+fn etag(data: &[u8]) -> String {
+    let hash = data.iter().fold(0u64, |hash, b| {
+        hash.wrapping_mul(31).wrapping_add(u64::from(*b))
+    });
+    format!("\"{:x}\"", hash)
+}
+
+fn serve(
+    stream: TcpStream,
+    files: &Mutex<HashMap<String, Vec<u8>>>,
+    ignore_ranges: bool,
+    log: &Mutex<Vec<String>>,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap().to_string();
+    let path = parts.next().unwrap().to_string();
+    let mut range = None;
+    let mut if_match = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Range: bytes=") {
+            let (start, end) = value.trim().split_once('-').unwrap();
+            range = Some((
+                start.parse::<usize>().unwrap(),
+                end.parse::<usize>().unwrap(),
+            ));
+        } else if let Some(value) = line.strip_prefix("If-Match: ") {
+            if_match = Some(value.trim().to_string());
+        }
+    }
+    log.lock()
+        .unwrap()
+        .push(format!("{} {} {:?}", method, path, range));
+
+    let mut stream = reader.into_inner();
+    let data = match files.lock().unwrap().get(&path) {
+        Some(data) => data.clone(),
+        None => {
+            write!(
+                stream,
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+            )
+            .unwrap();
+            return;
+        }
+    };
+    let etag = etag(&data);
+    if if_match.is_some_and(|if_match| if_match != etag) {
+        write!(
+            stream,
+            "HTTP/1.1 412 Precondition Failed\r\nContent-Length: 0\r\n\r\n"
+        )
+        .unwrap();
+        return;
+    }
+    let (status, body, content_range) = match range.filter(|_| !ignore_ranges) {
+        Some((start, _)) if start >= data.len() => {
+            ("416 Range Not Satisfiable", &[][..], String::new())
+        }
+        Some((start, end)) => {
+            let end = end.min(data.len() - 1);
+            (
+                "206 Partial Content",
+                &data[start..=end],
+                format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len()),
+            )
+        }
+        None => ("200 OK", &data[..], String::new()),
+    };
+    let len = if method == "HEAD" {
+        data.len()
+    } else {
+        body.len()
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: {}\r\n{}\r\n",
+        status, len, etag, content_range
+    )
+    .unwrap();
+    if method != "HEAD" {
+        stream.write_all(body).unwrap();
+    }
+}
+
+/// A database with 100 rows of 500 bytes each.
+fn database() -> Vec<u8> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "sqlite-vfs-http-{}-{}.db",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::remove_file(&path).ok();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (n, x);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT i, randomblob(500) FROM n;",
+    )
+    .unwrap();
+    drop(conn);
+    let data = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    data
+}
+
+fn server() -> Server {
+    Server::start(HashMap::from([("/dbs/ref.db".to_string(), database())]))
+}
+
+#[test]
+fn test_query_with_readahead() {
+    let server = server();
+    let vfs = HttpVfs::new(server.base()).with_readahead(1024 * 1024);
+    let conn = open(&register_vfs("http", vfs), "ref.db").unwrap();
+    assert_eq!(sum(&conn), 5050);
+    assert_eq!(integrity_check(&conn), "ok");
+
+    // the whole database got fetched at once
+    assert_eq!(server.requests("HEAD"), 1);
+    assert_eq!(server.requests("GET"), 1);
+}
+
+#[test]
+fn test_query_without_readahead() {
+    let server = server();
+    let vfs = HttpVfs::new(server.base()).with_readahead(0);
+    let conn = open(&register_vfs("http", vfs), "ref.db").unwrap();
+    assert_eq!(sum(&conn), 5050);
+
+    // a request per page
+    let pages: i64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+    assert!(server.requests("GET") as i64 >= pages);
+}
+
+#[test]
+fn test_database_is_read_only() {
+    let server = server();
+    let vfs = HttpVfs::new(server.base());
+    let conn = open(&register_vfs("http", vfs), "ref.db").unwrap();
+
+    let err = conn.execute("INSERT INTO t VALUES (0, 0)", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+
+    // temporary files are kept in memory
+    conn.execute_batch(
+        "PRAGMA temp_store = FILE;
+         CREATE TEMP TABLE sorted AS SELECT * FROM t ORDER BY x;",
+    )
+    .unwrap();
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM sorted", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 100);
+    assert!(server
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|r| r.contains("/dbs/ref.db ")));
+}
+
+#[test]
+fn test_missing_database() {
+    let server = server();
+    let vfs = HttpVfs::new(server.base());
+    let vfs = register_vfs("http", vfs);
+    let err = open(&vfs, "missing.db")
+        .and_then(|conn| conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+}
+
+#[test]
+fn test_server_ignoring_ranges() {
+    let server = server();
+    server.ignore_ranges.store(true, Ordering::Relaxed);
+    let vfs = HttpVfs::new(server.base()).with_readahead(0);
+    let conn = open(&register_vfs("http", vfs), "ref.db").unwrap();
+    assert_eq!(sum(&conn), 5050);
+
+    // the whole database got downloaded once, and is read from memory since
+    assert_eq!(server.requests("GET"), 1);
+}
+
+#[test]
+fn test_replaced_database() {
+    let server = server();
+    let vfs = HttpVfs::new(server.base()).with_readahead(0);
+    let conn = open(&register_vfs("http", vfs), "ref.db").unwrap();
+    let n: i64 = conn
+        .query_row("SELECT n FROM t WHERE rowid = 1", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 1);
+
+    // reading pages of the new version fails instead of mixing them with the cached ones
+    server
+        .files
+        .lock()
+        .unwrap()
+        .insert("/dbs/ref.db".to_string(), database());
+    let err = conn
+        .query_row("SELECT sum(n) FROM t", [], |row| row.get::<_, i64>(0))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::SystemIoFailure));
+}
@@ -0,0 +1,140 @@
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{open, register_vfs, MemVfs};
+use sqlite_vfs::metrics::{InstrumentedVfs, Op, TEMPORARY_DB};
+use sqlite_vfs::OpenKind;
+
+#[test]
+fn test_counts_per_kind() {
+    let vfs = InstrumentedVfs::new(MemVfs::default());
+    let metrics = vfs.metrics();
+    let vfs = register_vfs("instrumented", vfs);
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         INSERT INTO t VALUES (1);
+         INSERT INTO t VALUES (2);",
+    )
+    .unwrap();
+    drop(conn);
+
+    let snapshot = metrics.snapshot();
+    let db_writes = snapshot
+        .get("main.db", Some(OpenKind::MainDb), Op::Write)
+        .unwrap();
+    assert!(db_writes.calls > 0);
+    assert!(db_writes.bytes >= 4096);
+    assert_eq!(db_writes.errors, 0);
+    assert_eq!(db_writes.latency.count, db_writes.calls);
+
+    let journal_syncs = snapshot
+        .get("main.db", Some(OpenKind::MainJournal), Op::Sync)
+        .unwrap();
+    assert!(journal_syncs.calls > 0);
+    assert!(snapshot.get("main.db-journal", None, Op::Delete).is_some());
+
+    let text = snapshot.to_prometheus();
+    assert!(text.contains("# TYPE sqlite_vfs_latency_seconds histogram"));
+    assert!(text.contains(&format!(
+        "sqlite_vfs_calls_total{{db=\"main.db\",kind=\"main_journal\",op=\"sync\"}} {}",
+        journal_syncs.calls
+    )));
+
+    metrics.reset();
+    assert!(metrics.snapshot().entries.is_empty());
+}
+
+#[test]
+fn test_busy_and_lock_wait() {
+    let vfs = InstrumentedVfs::new(MemVfs::default());
+    let metrics = vfs.metrics();
+    let vfs = register_vfs("instrumented", vfs);
+
+    let conn1 = open(&vfs, "main.db").unwrap();
+    conn1.execute_batch("CREATE TABLE t (x)").unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    conn2.busy_timeout(Duration::from_secs(5)).unwrap();
+
+    conn1
+        .execute_batch("BEGIN EXCLUSIVE; INSERT INTO t VALUES (1);")
+        .unwrap();
+    let writer = thread::spawn(move || {
+        conn2.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    conn1.execute_batch("COMMIT").unwrap();
+    writer.join().unwrap();
+
+    let snapshot = metrics.snapshot();
+    let lock = snapshot
+        .get("main.db", Some(OpenKind::MainDb), Op::Lock)
+        .unwrap();
+    assert!(lock.busy > 0);
+
+    let wait = snapshot
+        .get("main.db", Some(OpenKind::MainDb), Op::LockWait)
+        .unwrap();
+    assert_eq!(wait.calls, 1);
+    assert!(wait.latency.sum >= Duration::from_millis(50));
+}
+
+#[test]
+fn test_temporary_files_share_a_label() {
+    let vfs = InstrumentedVfs::new(MemVfs::default());
+    let metrics = vfs.metrics();
+    let vfs = register_vfs("instrumented", vfs);
+
+    for _ in 0..3 {
+        let conn = open(&vfs, "main.db").unwrap();
+        conn.execute_batch(
+            "PRAGMA temp_store = FILE;
+             CREATE TEMP TABLE tmp (x);
+             PRAGMA temp.cache_size = 2;
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO tmp SELECT randomblob(4096) FROM n;",
+        )
+        .unwrap();
+    }
+
+    let snapshot = metrics.snapshot();
+    let opens = snapshot
+        .get(TEMPORARY_DB, Some(OpenKind::TempDb), Op::Open)
+        .unwrap();
+    assert_eq!(opens.calls, 3);
+    assert!(snapshot.get(TEMPORARY_DB, None, Op::Delete).is_some());
+    assert!(snapshot.entries.iter().all(|e| !e.db.starts_with("temp-")));
+}
+
+#[test]
+fn test_wal_index_busy() {
+    let vfs = InstrumentedVfs::new(MemVfs::default());
+    let metrics = vfs.metrics();
+    let vfs = register_vfs("instrumented", vfs);
+
+    let conn1 = open(&vfs, "main.db").unwrap();
+    conn1
+        .execute_batch("PRAGMA journal_mode = WAL; CREATE TABLE t (x);")
+        .unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    conn2.busy_timeout(Duration::ZERO).unwrap();
+
+    // the WAL write lock is a wal-index lock
+    conn1
+        .execute_batch("BEGIN IMMEDIATE; INSERT INTO t VALUES (1);")
+        .unwrap();
+    assert!(conn2.execute("INSERT INTO t VALUES (2)", []).is_err());
+    conn1.execute_batch("COMMIT").unwrap();
+
+    let snapshot = metrics.snapshot();
+    let lock = snapshot
+        .get("main.db", Some(OpenKind::MainDb), Op::WalIndexLock)
+        .unwrap();
+    assert!(lock.calls > 0);
+    assert!(lock.busy > 0);
+}
@@ -0,0 +1,191 @@
+#![cfg(feature = "object_store")]
+
+mod common;
+
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{count, integrity_check, open, register_vfs};
+use sqlite_vfs::object_store::{Condition, DirectoryStore, ObjectMeta, ObjectStore, ObjectVfs};
+
+/// A fresh directory for the objects of a test.
+fn directory(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "sqlite-vfs-object-store-{}-{}",
+        name,
+        std::process::id()
+    ));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+/// Counts the [ObjectStore::list] requests to the wrapped store.
+struct CountingStore {
+    store: DirectoryStore,
+    lists: Arc<AtomicUsize>,
+}
+
+impl ObjectStore for CountingStore {
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>, std::io::Error> {
+        self.store.get_range(key, range)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<String, std::io::Error> {
+        self.store.put(key, data)
+    }
+
+    fn put_if(
+        &self,
+        key: &str,
+        data: &[u8],
+        condition: Condition<'_>,
+    ) -> Result<Option<String>, std::io::Error> {
+        self.store.put_if(key, data, condition)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), std::io::Error> {
+        self.store.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, std::io::Error> {
+        self.lists.fetch_add(1, Ordering::Relaxed);
+        self.store.list(prefix)
+    }
+}
+
+#[test]
+fn test_database_is_stored_as_objects() {
+    let dir = directory("objects");
+    let vfs = ObjectVfs::new(DirectoryStore::new(&dir).unwrap());
+    let conn = open(&register_vfs("objects", vfs), "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (x);
+         INSERT INTO t VALUES (randomblob(10000));
+         BEGIN;
+         INSERT INTO t VALUES (randomblob(10000));
+         ROLLBACK;
+         INSERT INTO t VALUES (randomblob(10000));",
+    )
+    .unwrap();
+    drop(conn);
+
+    // one object per page, the journal got deleted
+    let store = DirectoryStore::new(&dir).unwrap();
+    let pages = store.list("main.db/").unwrap();
+    assert!(pages.len() > 4);
+    assert!(pages.iter().all(|page| page.size == 4096));
+    assert!(store.list("main.db-journal").unwrap().is_empty());
+
+    // reopen with a new VFS over the same directory
+    let vfs = ObjectVfs::new(store);
+    let conn = open(&register_vfs("objects", vfs), "main.db").unwrap();
+    assert_eq!(count(&conn), 2);
+    assert_eq!(integrity_check(&conn), "ok");
+    drop(conn);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_chunk_size_differs_from_page_size() {
+    for chunk_size in [1000, 16 * 1024] {
+        let dir = directory(&format!("chunks-{}", chunk_size));
+        let store = DirectoryStore::new(&dir).unwrap();
+        let vfs = ObjectVfs::new(store).with_chunk_size(chunk_size);
+        let conn = open(&register_vfs("chunks", vfs), "main.db").unwrap();
+        conn.execute_batch(
+            "PRAGMA cache_size = 2;
+             CREATE TABLE t (x);
+             INSERT INTO t VALUES (randomblob(10000));
+             INSERT INTO t VALUES (randomblob(10000));
+             UPDATE t SET x = randomblob(100);
+             VACUUM;",
+        )
+        .unwrap();
+        assert_eq!(count(&conn), 2);
+        assert_eq!(integrity_check(&conn), "ok");
+        drop(conn);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn test_writers_of_other_processes_are_kept_out() {
+    let dir = directory("lease");
+    let vfs1 = ObjectVfs::new(DirectoryStore::new(&dir).unwrap());
+    // a separate VFS doesn't share the in-process locks, just like another process
+    let vfs2 = ObjectVfs::new(DirectoryStore::new(&dir).unwrap());
+    let conn1 = open(&register_vfs("lease", vfs1), "main.db").unwrap();
+    let conn2 = open(&register_vfs("lease", vfs2), "main.db").unwrap();
+    conn2.busy_timeout(Duration::ZERO).unwrap();
+    conn1.execute("CREATE TABLE t (x)", []).unwrap();
+
+    conn1
+        .execute_batch("BEGIN; INSERT INTO t VALUES (1);")
+        .unwrap();
+    assert!(conn2.execute("INSERT INTO t VALUES (2)", []).is_err());
+    conn1.execute_batch("COMMIT").unwrap();
+
+    conn2.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    assert_eq!(count(&conn1), 2);
+    drop((conn1, conn2));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_wal_without_exclusive_locking_mode() {
+    let dir = directory("wal");
+    let vfs = register_vfs("wal", ObjectVfs::new(DirectoryStore::new(&dir).unwrap()));
+    let writer = open(&vfs, "main.db").unwrap();
+    let mode: String = writer
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    writer.execute("CREATE TABLE t (x)", []).unwrap();
+
+    let reader = open(&vfs, "main.db").unwrap();
+    writer.execute("INSERT INTO t VALUES (1)", []).unwrap();
+    assert_eq!(count(&reader), 1);
+    writer
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    writer.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    assert_eq!(count(&reader), 2);
+    assert_eq!(integrity_check(&reader), "ok");
+    drop((writer, reader));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_size_is_cached() {
+    let dir = directory("size");
+    let lists = Arc::new(AtomicUsize::new(0));
+    let store = CountingStore {
+        store: DirectoryStore::new(&dir).unwrap(),
+        lists: lists.clone(),
+    };
+    let conn = open(&register_vfs("size", ObjectVfs::new(store)), "main.db").unwrap();
+    conn.execute_batch(
+        "PRAGMA journal_mode = PERSIST;
+         PRAGMA locking_mode = EXCLUSIVE;
+         CREATE TABLE t (x);",
+    )
+    .unwrap();
+
+    let before = lists.load(Ordering::Relaxed);
+    for _ in 0..10 {
+        conn.execute("INSERT INTO t VALUES (randomblob(5000))", [])
+            .unwrap();
+    }
+    assert_eq!(count(&conn), 10);
+    assert_eq!(lists.load(Ordering::Relaxed), before);
+    drop(conn);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
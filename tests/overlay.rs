@@ -0,0 +1,179 @@
+#![cfg(feature = "overlay")]
+
+mod common;
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use sqlite_vfs::overlay::{MemoryVfs, OverlayVfs};
+
+/// A base with a golden database of 100 rows.
+fn golden() -> MemVfs {
+    let base = MemVfs::default();
+    let conn = open(&register_vfs("golden", base.clone()), "main.db").unwrap();
+    conn.execute_batch(
+        "CREATE TABLE t (n, x);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT i, randomblob(500) FROM n;",
+    )
+    .unwrap();
+    base
+}
+
+#[test]
+fn test_writes_go_to_the_upper_layer() {
+    let base = golden();
+    let pristine = base.file("main.db").unwrap();
+    let writes = base.writes("main.db");
+    let upper = MemVfs::default();
+    let vfs = register_vfs("overlay", OverlayVfs::new(base.clone(), upper.clone()));
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch(
+        "DELETE FROM t WHERE n > 50;
+         UPDATE t SET x = NULL WHERE n = 1;",
+    )
+    .unwrap();
+    assert_eq!(count(&conn), 50);
+    assert_eq!(integrity_check(&conn), "ok");
+
+    // the base is untouched
+    assert_eq!(base.file("main.db").unwrap(), pristine);
+    assert_eq!(base.writes("main.db"), writes);
+    assert_eq!(base.names(), ["main.db"]);
+    assert_eq!(upper.names(), ["main.db", "main.db-overlay"]);
+
+    // a second connection sees the changes
+    let conn2 = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn2), 50);
+    conn2.execute("INSERT INTO t VALUES (0, 0)", []).unwrap();
+    assert_eq!(count(&conn), 51);
+}
+
+#[test]
+fn test_overlays_are_independent() {
+    let base = golden();
+    let vfs1 = register_vfs(
+        "overlay",
+        OverlayVfs::new(base.clone(), MemoryVfs::default()),
+    );
+    let vfs2 = register_vfs(
+        "overlay",
+        OverlayVfs::new(base.clone(), MemoryVfs::default()),
+    );
+    let conn1 = open(&vfs1, "main.db").unwrap();
+    let conn2 = open(&vfs2, "main.db").unwrap();
+
+    // locks are held in the upper layers
+    conn1.execute_batch("BEGIN; DELETE FROM t;").unwrap();
+    conn2.execute("INSERT INTO t VALUES (0, 0)", []).unwrap();
+    conn1.execute_batch("COMMIT").unwrap();
+
+    assert_eq!(count(&conn1), 0);
+    assert_eq!(count(&conn2), 101);
+    let conn = open(&register_vfs("golden", base), "main.db").unwrap();
+    assert_eq!(count(&conn), 100);
+}
+
+#[test]
+fn test_truncate_and_grow() {
+    let base = golden();
+    let vfs = register_vfs("overlay", OverlayVfs::new(base, MemoryVfs::default()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("DELETE FROM t; VACUUM;").unwrap();
+    let pages: i64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+    assert!(pages < 5);
+
+    // the pages of the base beyond the truncation must not reappear
+    conn.execute_batch(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+    )
+    .unwrap();
+    assert_eq!(count(&conn), 100);
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_overlay_is_kept_in_upper_layer() {
+    let base = golden();
+    let upper = MemVfs::default();
+    let vfs = register_vfs("overlay", OverlayVfs::new(base.clone(), upper.clone()));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("DELETE FROM t WHERE n % 2 = 0", []).unwrap();
+    drop(conn);
+
+    // e.g. after a restart
+    let vfs = register_vfs("overlay", OverlayVfs::new(base, upper));
+    let conn = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn), 50);
+    assert_eq!(integrity_check(&conn), "ok");
+}
+
+#[test]
+fn test_discard_and_squash() {
+    let base = golden();
+    let overlay = OverlayVfs::new(base, MemoryVfs::default());
+    let vfs = register_vfs("overlay", overlay.clone());
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute("DELETE FROM t WHERE n > 10", []).unwrap();
+    drop(conn);
+
+    let squashed = MemVfs::default();
+    overlay.squash("main.db", &squashed, "main.db").unwrap();
+    let conn = open(&register_vfs("squashed", squashed), "main.db").unwrap();
+    assert_eq!(count(&conn), 10);
+    assert_eq!(integrity_check(&conn), "ok");
+
+    overlay.discard("main.db").unwrap();
+    let conn = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn), 100);
+}
+
+#[test]
+fn test_new_database_without_base() {
+    let vfs = OverlayVfs::new(MemVfs::default(), MemoryVfs::default());
+    let conn = open(&register_vfs("overlay", vfs), "new.db").unwrap();
+    conn.execute_batch("CREATE TABLE t (n); INSERT INTO t VALUES (1);")
+        .unwrap();
+    assert_eq!(count(&conn), 1);
+}
+
+#[cfg(feature = "checksum")]
+#[test]
+fn test_pragmas_reach_the_upper_layer() {
+    use sqlite_vfs::checksum::ChecksumVfs;
+
+    let upper = ChecksumVfs::new(MemVfs::default());
+    let vfs = register_vfs("overlay", OverlayVfs::new(golden(), upper));
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA checksum_verification = OFF")
+        .unwrap();
+    let enabled: String = conn
+        .query_row("PRAGMA checksum_verification", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(enabled, "0");
+}
+
+#[test]
+fn test_wal_checkpoint_is_visible_to_other_connections() {
+    let base = golden();
+    let vfs = register_vfs("overlay", OverlayVfs::new(base, MemVfs::default()));
+
+    let conn1 = open(&vfs, "main.db").unwrap();
+    conn1.execute_batch("PRAGMA journal_mode = WAL").unwrap();
+    let conn2 = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn2), 100);
+
+    // both connections keep their SHARED lock in WAL mode, so the checkpoint doesn't cause a lock
+    // change of the other connection
+    conn1
+        .execute_batch(
+            "DELETE FROM t WHERE n > 20;
+             PRAGMA wal_checkpoint(TRUNCATE);",
+        )
+        .unwrap();
+    assert_eq!(count(&conn2), 20);
+    assert_eq!(integrity_check(&conn2), "ok");
+}
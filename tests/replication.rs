@@ -0,0 +1,414 @@
+#![cfg(feature = "replication")]
+
+mod common;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{count, integrity_check, open, register_vfs, MemVfs};
+use rusqlite::ErrorCode;
+use sqlite_vfs::replication::{
+    FileSink, FollowerVfs, ReplicatedVfs, RestorePoint, WalArchive, WalFrame, WalHeader,
+    WalObserver,
+};
+
+/// Collects all committed frames.
+#[derive(Default)]
+struct Collector {
+    calls: Mutex<Vec<(String, WalHeader, Vec<WalFrame>)>>,
+    /// Fail to take any frames while set.
+    fail: AtomicBool,
+}
+
+impl WalObserver for Collector {
+    fn committed(
+        &self,
+        db: &str,
+        header: &WalHeader,
+        frames: &[WalFrame],
+    ) -> Result<(), std::io::Error> {
+        if self.fail.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("observer unavailable"));
+        }
+        self.calls
+            .lock()
+            .unwrap()
+            .push((db.to_string(), *header, frames.to_vec()));
+        Ok(())
+    }
+}
+
+impl Collector {
+    fn frames(&self) -> Vec<WalFrame> {
+        let calls = self.calls.lock().unwrap();
+        calls.iter().flat_map(|(_, _, f)| f.clone()).collect()
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "sqlite-vfs-replication-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+/// Create a table and switch to WAL mode (without shared memory and automatic checkpoints).
+fn setup(conn: &rusqlite::Connection, synchronous: &str) {
+    conn.execute_batch(&format!(
+        "CREATE TABLE t (n, x);
+         PRAGMA locking_mode = EXCLUSIVE;
+         PRAGMA journal_mode = WAL;
+         PRAGMA wal_autocheckpoint = 0;
+         PRAGMA synchronous = {};",
+        synchronous
+    ))
+    .unwrap();
+}
+
+fn insert(conn: &rusqlite::Connection, rows: usize) {
+    conn.execute(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+         INSERT INTO t SELECT i, randomblob(500) FROM n",
+        [rows],
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_observer_is_notified_after_sync() {
+    let collector = Arc::new(Collector::default());
+    let vfs = ReplicatedVfs::new(MemVfs::default(), collector.clone());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "NORMAL");
+
+    // the WAL is only synced before checkpoints
+    insert(&conn, 10);
+    insert(&conn, 10);
+    assert!(collector.frames().is_empty());
+    conn.execute_batch("PRAGMA wal_checkpoint").unwrap();
+    let frames = collector.frames();
+    assert_eq!(frames.iter().filter(|f| f.is_commit()).count(), 2);
+    assert!(frames.last().unwrap().is_commit());
+
+    // and on each commit with synchronous = FULL
+    conn.execute_batch("PRAGMA synchronous = FULL").unwrap();
+    insert(&conn, 10);
+    let calls = collector.calls.lock().unwrap();
+    assert_eq!(calls.len(), 2);
+    for (db, header, frames) in calls.iter() {
+        assert_eq!(db, "main.db");
+        assert!(frames.iter().all(|f| f.salts == header.salts));
+        assert!(frames
+            .iter()
+            .all(|f| f.data.len() == header.page_size as usize));
+    }
+    for (_, _, frames) in calls.iter() {
+        let indices = frames.iter().map(|f| f.index).collect::<Vec<_>>();
+        assert_eq!(indices, (0..frames.len() as u32).collect::<Vec<_>>());
+    }
+    // the checkpointed WAL got restarted
+    assert_ne!(calls[0].1.salts, calls[1].1.salts);
+}
+
+#[test]
+fn test_frames_of_other_connections_are_passed() {
+    let collector = Arc::new(Collector::default());
+    let vfs = register_vfs(
+        "replicated",
+        ReplicatedVfs::new(MemVfs::default(), collector.clone()),
+    );
+    let writer = open(&vfs, "main.db").unwrap();
+    writer
+        .execute_batch(
+            "CREATE TABLE t (n, x);
+             PRAGMA journal_mode = WAL;
+             PRAGMA wal_autocheckpoint = 0;
+             PRAGMA synchronous = NORMAL;",
+        )
+        .unwrap();
+    let other = open(&vfs, "main.db").unwrap();
+    insert(&writer, 10);
+    insert(&writer, 10);
+    assert!(collector.frames().is_empty());
+
+    // the WAL synced and restarted by another connection includes the frames of the writer
+    other
+        .execute_batch("PRAGMA wal_checkpoint(RESTART)")
+        .unwrap();
+    insert(&other, 1);
+    let calls = collector.calls.lock().unwrap();
+    assert_eq!(calls.len(), 2);
+    let frames = &calls[0].2;
+    assert_eq!(frames.iter().filter(|f| f.is_commit()).count(), 2);
+    assert!(frames.iter().all(|f| f.salts == calls[0].1.salts));
+    // the other connection syncs on commit (synchronous is per connection)
+    assert_ne!(calls[0].1.salts, calls[1].1.salts);
+    assert_eq!(calls[1].2.len(), 1);
+}
+
+#[test]
+fn test_frames_are_not_lost_when_observer_fails() {
+    let collector = Arc::new(Collector::default());
+    let vfs = ReplicatedVfs::new(MemVfs::default(), collector.clone());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "FULL");
+    collector.fail.store(true, Ordering::Relaxed);
+    insert(&conn, 10);
+    insert(&conn, 10);
+
+    // the WAL cannot be truncated before its frames got passed
+    assert!(conn
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .is_err());
+
+    collector.fail.store(false, Ordering::Relaxed);
+    insert(&conn, 10);
+    let frames = collector.frames();
+    assert_eq!(frames.iter().filter(|f| f.is_commit()).count(), 3);
+    assert_eq!(count(&conn), 30);
+}
+
+#[test]
+fn test_frames_are_passed_before_wal_is_deleted() {
+    let collector = Arc::new(Collector::default());
+    let mem = MemVfs::default();
+    let vfs = register_vfs(
+        "replicated",
+        ReplicatedVfs::new(mem.clone(), collector.clone()),
+    );
+    let conn = open(&vfs, "main.db").unwrap();
+    setup(&conn, "OFF");
+    insert(&conn, 10);
+    insert(&conn, 10);
+
+    // the WAL is never synced, but is kept while its frames cannot be passed
+    collector.fail.store(true, Ordering::Relaxed);
+    drop(conn);
+    assert!(collector.frames().is_empty());
+    assert!(mem.file("main.db-wal").is_some());
+
+    let conn = open(&vfs, "main.db").unwrap();
+    conn.execute_batch("PRAGMA synchronous = OFF").unwrap();
+    insert(&conn, 10);
+    collector.fail.store(false, Ordering::Relaxed);
+    drop(conn);
+    assert!(mem.file("main.db-wal").is_none());
+    let frames = collector.frames();
+    assert_eq!(frames.iter().filter(|f| f.is_commit()).count(), 3);
+}
+
+#[test]
+fn test_file_sink_archives_valid_wals() {
+    let dir = temp_dir();
+    let mem = MemVfs::default();
+    let vfs = ReplicatedVfs::new(mem.clone(), FileSink::new(&dir).unwrap());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "FULL");
+    let base = mem.file("main.db").unwrap();
+    for _ in 0..3 {
+        insert(&conn, 20);
+    }
+    let segment = dir.join("main.db").join("0000000000.wal");
+    let commits = std::fs::read(dir.join("main.db").join("0000000000.commits")).unwrap();
+    assert_eq!(commits.len(), 3 * 12);
+
+    // the base and the archived WAL restore the database
+    let restored = MemVfs::default();
+    restored.set_file("main.db", base);
+    restored.set_file("main.db-wal", std::fs::read(&segment).unwrap());
+    let conn2 = open(&register_vfs("restored", restored), "main.db").unwrap();
+    conn2
+        .execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+        .unwrap();
+    assert_eq!(count(&conn2), 60);
+
+    // a restarted WAL is archived in a new segment
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    insert(&conn, 1);
+    assert!(dir.join("main.db").join("0000000001.wal").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_follower_applies_stream() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mem = MemVfs::default();
+    let primary = open(
+        &register_vfs("replicated", ReplicatedVfs::new(mem.clone(), tx)),
+        "main.db",
+    )
+    .unwrap();
+    setup(&primary, "FULL");
+
+    let follower = FollowerVfs::new("replica.db", mem.file("main.db").unwrap(), rx);
+    let follower = open(&register_vfs("follower", follower), "replica.db").unwrap();
+    assert_eq!(count(&follower), 0);
+
+    insert(&primary, 10);
+    assert_eq!(count(&follower), 10);
+    let err = follower.execute("DELETE FROM t", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+
+    // across checkpoints and restarted WALs
+    primary
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    insert(&primary, 10);
+    primary.execute("DELETE FROM t WHERE n > 5", []).unwrap();
+    assert_eq!(count(&follower), 10);
+    assert_eq!(integrity_check(&follower), "ok");
+}
+
+#[test]
+fn test_restore_from_archive() {
+    let dir = temp_dir();
+    let mem = MemVfs::default();
+    let vfs = ReplicatedVfs::new(mem.clone(), FileSink::new(&dir).unwrap());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "FULL");
+    let base = mem.file("main.db").unwrap();
+    for i in 0..4 {
+        if i == 2 {
+            conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+                .unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        insert(&conn, 10);
+    }
+
+    let archive = WalArchive::new(&dir, "main.db");
+    let commits = archive.commits().unwrap();
+    assert_eq!(commits.len(), 4);
+    assert_eq!(commits[2].segment, 1);
+
+    let restore = |until| {
+        let target = MemVfs::default();
+        let last = archive.restore(&base, until, &target, "main.db")?;
+        let conn = open(&register_vfs("restored", target), "main.db").unwrap();
+        conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+            .unwrap();
+        Ok::<_, std::io::Error>((count(&conn), last))
+    };
+    assert_eq!(
+        restore(RestorePoint::Latest).unwrap(),
+        (40, Some(commits[3]))
+    );
+    assert_eq!(
+        restore(RestorePoint::Transaction(3)).unwrap(),
+        (30, Some(commits[2]))
+    );
+    let time = commits[1].time.unwrap();
+    assert_eq!(restore(RestorePoint::Time(time)).unwrap().0, 20);
+    let err = restore(RestorePoint::Transaction(5)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+    // transactions whose time did not get recorded (crash after archiving their frames)
+    let times = dir.join("main.db").join("0000000001.commits");
+    let recorded = std::fs::read(&times).unwrap();
+    std::fs::write(&times, &recorded[..12]).unwrap();
+    let unrecorded = archive.commits().unwrap();
+    assert_eq!(unrecorded[..3], commits[..3]);
+    assert_eq!(unrecorded[3].time, None);
+    assert_eq!(
+        restore(RestorePoint::Transaction(4)).unwrap(),
+        (40, Some(unrecorded[3]))
+    );
+    assert_eq!(restore(RestorePoint::Time(time)).unwrap().0, 20);
+    std::fs::write(&times, &recorded).unwrap();
+
+    // corrupted frames are detected
+    let segment = dir.join("main.db").join("0000000001.wal");
+    let mut wal = std::fs::read(&segment).unwrap();
+    wal[100] ^= 1;
+    std::fs::write(&segment, &wal).unwrap();
+    let err = restore(RestorePoint::Latest).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    // but restoring up to before them succeeds
+    assert_eq!(restore(RestorePoint::Transaction(2)).unwrap().0, 20);
+
+    // as are missing segments
+    std::fs::write(dir.join("main.db").join("0000000002.wal"), &wal).unwrap();
+    std::fs::remove_file(&segment).unwrap();
+    let err = restore(RestorePoint::Latest).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_file_sink_continues_segment_after_restart() {
+    let dir = temp_dir();
+    let mem = MemVfs::default();
+    let vfs = ReplicatedVfs::new(mem.clone(), FileSink::new(&dir).unwrap());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "FULL");
+    let base = mem.file("main.db").unwrap();
+    insert(&conn, 10);
+
+    // continue with the files left behind by a crashed process
+    let recovered = MemVfs::default();
+    recovered.set_file("main.db", mem.file("main.db").unwrap());
+    recovered.set_file("main.db-wal", mem.file("main.db-wal").unwrap());
+    let vfs = ReplicatedVfs::new(recovered, FileSink::new(&dir).unwrap());
+    let conn = open(&register_vfs("recovered", vfs), "main.db").unwrap();
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE")
+        .unwrap();
+    insert(&conn, 10);
+
+    let archive = WalArchive::new(&dir, "main.db");
+    let commits = archive.commits().unwrap();
+    assert_eq!(commits.len(), 2);
+    assert!(commits.iter().all(|commit| commit.segment == 0));
+
+    let target = MemVfs::default();
+    archive
+        .restore(&base, RestorePoint::Latest, &target, "main.db")
+        .unwrap();
+    let conn = open(&register_vfs("restored", target), "main.db").unwrap();
+    assert_eq!(count(&conn), 20);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restore_detects_missing_generations() {
+    let dir = temp_dir();
+    let mem = MemVfs::default();
+    let vfs = ReplicatedVfs::new(mem.clone(), FileSink::new(&dir).unwrap());
+    let conn = open(&register_vfs("replicated", vfs), "main.db").unwrap();
+    setup(&conn, "FULL");
+    let base = mem.file("main.db").unwrap();
+    for i in 0..3 {
+        if i > 0 {
+            conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+                .unwrap();
+        }
+        insert(&conn, 10);
+    }
+
+    // lose the second generation, with the segments still numbered consecutively
+    let path = |seq: u64, ext: &str| dir.join("main.db").join(format!("{:010}.{}", seq, ext));
+    for ext in ["wal", "commits"] {
+        std::fs::rename(path(2, ext), path(1, ext)).unwrap();
+    }
+
+    let archive = WalArchive::new(&dir, "main.db");
+    let target = MemVfs::default();
+    let err = archive
+        .restore(&base, RestorePoint::Latest, &target, "main.db")
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let last = archive
+        .restore(&base, RestorePoint::Transaction(1), &target, "main.db")
+        .unwrap();
+    assert_eq!(last.unwrap().transaction, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,151 @@
+#![cfg(feature = "tracing")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::{open, register_vfs, MemVfs};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+#[derive(Debug, Default, Clone)]
+struct Span {
+    name: &'static str,
+    fields: HashMap<&'static str, String>,
+}
+
+/// Collects all spans (with their fields) and events.
+#[derive(Default, Clone)]
+struct Collector {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<HashMap<u64, Span>>>,
+    events: Arc<Mutex<Vec<HashMap<&'static str, String>>>>,
+}
+
+struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut span = Span {
+            name: attrs.metadata().name(),
+            ..Default::default()
+        };
+        attrs.record(&mut Fields(&mut span.fields));
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, id: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            values.record(&mut Fields(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut Fields(&mut fields));
+        self.events.lock().unwrap().push(fields);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn test_spans_per_call() {
+    let vfs = register_vfs("traced", MemVfs::default());
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(&vfs, "main.db").unwrap();
+        conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (1);")
+            .unwrap();
+    });
+
+    let spans = collector.spans.lock().unwrap();
+    let open = spans
+        .values()
+        .find(|s| s.name == "open" && s.fields.get("db").map(String::as_str) == Some("main.db"))
+        .expect("open span");
+    assert_eq!(open.fields["rc"], "0");
+    let file_id = &open.fields["file_id"];
+
+    let write = spans
+        .values()
+        .find(|s| s.name == "write" && s.fields.get("db").map(String::as_str) == Some("main.db"))
+        .expect("write span");
+    assert_eq!(&write.fields["file_id"], file_id);
+    assert_eq!(write.fields["op"], "write");
+    assert_eq!(write.fields["offset"].parse::<u64>().unwrap() % 4096, 0);
+    assert_eq!(write.fields["len"], "4096");
+    assert_eq!(write.fields["rc"], "0");
+
+    assert!(spans
+        .values()
+        .any(|s| s.name == "lock" && s.fields["lock"] == "Exclusive" && s.fields["rc"] == "0"));
+
+    // unhandled file controls report SQLITE_NOTFOUND
+    assert!(spans
+        .values()
+        .any(|s| s.name == "file_control" && s.fields["rc"] != "0"));
+    assert!(collector
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.contains_key("rc")));
+}
+
+#[test]
+fn test_fcntl_trace_event() {
+    let vfs = register_vfs("traced-fcntl", MemVfs::default());
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let conn = open(&vfs, "main.db").unwrap();
+        conn.execute_batch("CREATE TABLE t (x);").unwrap();
+
+        // SQLite only sends SQLITE_FCNTL_TRACE when built with SQLITE_USE_FCNTL_TRACE, so send
+        // it directly
+        let msg = b"hello from sqlite\0";
+        let db = b"main\0";
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                db.as_ptr() as _,
+                rusqlite::ffi::SQLITE_FCNTL_TRACE,
+                msg.as_ptr() as *mut _,
+            )
+        };
+        assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+    });
+
+    let events = collector.events.lock().unwrap();
+    let event = events
+        .iter()
+        .find(|e| e.get("trace").map(String::as_str) == Some("hello from sqlite"))
+        .expect("trace event");
+    assert_eq!(event["message"], "sqlite trace");
+}
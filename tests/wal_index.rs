@@ -0,0 +1,53 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{count, open, register_vfs, MemVfs};
+use rusqlite::ErrorCode;
+
+#[test]
+fn test_wal_without_exclusive_locking_mode() {
+    let mem = MemVfs::default();
+    let vfs = register_vfs("wal", mem.clone());
+    let writer = open(&vfs, "main.db").unwrap();
+    let mode: String = writer
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    writer
+        .execute_batch("PRAGMA wal_autocheckpoint = 0; CREATE TABLE t (n)")
+        .unwrap();
+
+    // a second connection sees the committed changes through the shared index
+    let reader = open(&vfs, "main.db").unwrap();
+    reader.busy_timeout(Duration::ZERO).unwrap();
+    writer.execute("INSERT INTO t VALUES (1)", []).unwrap();
+    assert_eq!(count(&reader), 1);
+
+    // readers keep their snapshot while the writer commits
+    reader.execute_batch("BEGIN").unwrap();
+    assert_eq!(count(&reader), 1);
+    writer.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    assert_eq!(count(&reader), 1);
+    reader.execute_batch("COMMIT").unwrap();
+    assert_eq!(count(&reader), 2);
+
+    // there can only be one writer
+    writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let err = reader.execute_batch("BEGIN IMMEDIATE").unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::DatabaseBusy));
+    writer.execute_batch("COMMIT").unwrap();
+
+    writer
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+        .unwrap();
+    assert_eq!(count(&reader), 2);
+    assert!(mem.names().iter().all(|name| !name.ends_with("-shm")));
+
+    // the database is checkpointed once the last connection is closed
+    drop(reader);
+    drop(writer);
+    assert_eq!(mem.names(), vec!["main.db".to_string()]);
+    let conn = open(&vfs, "main.db").unwrap();
+    assert_eq!(count(&conn), 2);
+}